@@ -0,0 +1,1064 @@
+//! REST API for managing screenplays and inspecting rooms without a
+//! WebSocket connection.
+//!
+//! Content is read through the room owning the document, so it includes
+//! edits that have not been compacted to storage yet.
+//!
+//! When authentication is on, callers present the same token as on the
+//! WebSocket in an `Authorization: Bearer` header and are held to their
+//! role in each screenplay's ACL. Screenplays they create are owned by
+//! them. Without authentication the API trusts every caller.
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use axum::{
+    extract::{Path, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use tokio::sync::oneshot;
+use yrs::{updates::decoder::Decode, Doc, Transact, Update};
+
+use crate::{
+    auth::{AuthError, User},
+    peers::new_session,
+    socket::request_snapshot,
+    state::{AppState, Grant, RoomCmd},
+    store::StoreError,
+};
+
+use shared::{
+    api::{
+        Acl, Health, NewScreenplay, NewShareLink, RenameScreenplay, Role, RoomStats, Screenplay,
+        ScreenplayMeta, ShareLink,
+    },
+    screenplay::{fountain, ydoc},
+};
+
+/// Lifetime of a share link when none is given.
+const DEFAULT_SHARE_SECS: u64 = 7 * 24 * 60 * 60;
+/// Longest lifetime a share link can be given.
+const MAX_SHARE_SECS: u64 = 365 * 24 * 60 * 60;
+
+/// Longest title accepted, in characters.
+const MAX_TITLE_LEN: usize = 200;
+
+pub(crate) fn router() -> Router<AppState> {
+    Router::new()
+        .route("/api/health", get(health))
+        .route("/api/rooms", get(list_rooms))
+        .route("/api/rooms/{doc_id}", get(room_stats))
+        .route("/api/screenplays", get(list).post(create))
+        .route("/api/screenplays/import", post(import))
+        .route(
+            "/api/screenplays/{id}",
+            get(fetch).patch(rename).delete(delete),
+        )
+        .route("/api/screenplays/{id}/snapshot", get(snapshot))
+        .route("/api/screenplays/{id}/fountain", get(export))
+        .route("/api/screenplays/{id}/duplicate", post(duplicate))
+        .route("/api/screenplays/{id}/acl", get(get_acl).put(set_acl))
+        .route(
+            "/api/screenplays/{id}/shares",
+            get(list_shares).post(create_share),
+        )
+        .route(
+            "/api/screenplays/{id}/shares/{token}",
+            axum::routing::delete(revoke_share),
+        )
+        .route("/api/shares/{token}", get(resolve_share))
+}
+
+#[derive(serde::Deserialize)]
+struct ListParams {
+    /// Include deleted screenplays.
+    #[serde(default)]
+    deleted: bool,
+}
+
+async fn health(State(state): State<AppState>) -> Response {
+    let shutting_down = state
+        .shutting_down
+        .load(std::sync::atomic::Ordering::SeqCst);
+    let health = Health {
+        status: if shutting_down { "shutting_down" } else { "ok" }.to_string(),
+        rooms: state.rooms.len(),
+    };
+
+    let status = if shutting_down {
+        StatusCode::SERVICE_UNAVAILABLE
+    } else {
+        StatusCode::OK
+    };
+    (status, Json(health)).into_response()
+}
+
+/// Stats of every loaded room the caller owns, or of every loaded room
+/// when authentication is off.
+async fn list_rooms(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<RoomStats>>, ApiError> {
+    let user = caller(&state, &headers)?;
+    let doc_ids: Vec<String> = state.rooms.iter().map(|r| r.key().clone()).collect();
+
+    let mut rooms = Vec::with_capacity(doc_ids.len());
+    for doc_id in doc_ids {
+        if let Some(user) = &user {
+            let role = load_acl(&state, &doc_id).await?.role(Some(&user.id));
+            if role != Some(Role::Owner) {
+                continue;
+            }
+        }
+        if let Some(stats) = query_room(&state, &doc_id).await {
+            rooms.push(stats);
+        }
+    }
+
+    Ok(Json(rooms))
+}
+
+async fn room_stats(
+    State(state): State<AppState>,
+    Path(doc_id): Path<String>,
+    headers: HeaderMap,
+) -> Result<Json<RoomStats>, ApiError> {
+    authorize(&state, &headers, &doc_id, Role::Owner).await?;
+    query_room(&state, &doc_id)
+        .await
+        .map(Json)
+        .ok_or(ApiError::NotFound)
+}
+
+async fn list(
+    State(state): State<AppState>,
+    Query(params): Query<ListParams>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<ScreenplayMeta>>, ApiError> {
+    let user = caller(&state, &headers)?;
+    let store = state.store.clone();
+    let mut metas = blocking(move || {
+        let mut metas = store.list_meta()?;
+        metas.retain(|m| params.deleted || m.deleted_at.is_none());
+        if let Some(user) = user {
+            let mut visible = Vec::with_capacity(metas.len());
+            for meta in metas {
+                let acl = store.load_acl(&meta.id)?.unwrap_or_default();
+                if acl.role(Some(&user.id)).is_some() {
+                    visible.push(meta);
+                }
+            }
+            metas = visible;
+        }
+        Ok(metas)
+    })
+    .await?;
+    metas.sort_by_key(|m| std::cmp::Reverse(m.updated_at));
+
+    Ok(Json(metas))
+}
+
+async fn create(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(body): Json<NewScreenplay>,
+) -> Result<(StatusCode, Json<ScreenplayMeta>), ApiError> {
+    let user = caller(&state, &headers)?;
+    let title = validate_title(body.title.as_deref().unwrap_or("Untitled"))?;
+    let meta = new_meta(title);
+
+    let store = state.store.clone();
+    let saved = meta.clone();
+    blocking(move || {
+        if let Some(user) = user {
+            store.save_acl(&saved.id, &Acl::owned_by(&user.id))?;
+        }
+        store.save_meta(&saved)
+    })
+    .await?;
+    tracing::info!(id = %meta.id, title = %meta.title, "created screenplay");
+
+    Ok((StatusCode::CREATED, Json(meta)))
+}
+
+#[derive(serde::Deserialize)]
+struct ImportParams {
+    /// Title of the new screenplay; taken from the title page if absent.
+    title: Option<String>,
+}
+
+/// Creates a screenplay from a Fountain script sent as the request body.
+async fn import(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(params): Query<ImportParams>,
+    body: String,
+) -> Result<(StatusCode, Json<ScreenplayMeta>), ApiError> {
+    let user = caller(&state, &headers)?;
+    let content = fountain::parse(&body);
+    let title = match params.title {
+        Some(title) => title,
+        None => script_title(&content).unwrap_or("Untitled").to_string(),
+    };
+    let meta = new_meta(validate_title(&title)?);
+
+    let update = {
+        let doc = Doc::new();
+        let mut txn = doc.transact_mut();
+        ydoc::write(&mut txn, &content);
+        txn.encode_update_v1()
+    };
+    if update.len() > state.config.max_document_bytes {
+        return Err(ApiError::BadRequest(format!(
+            "script exceeds the document size limit of {} bytes",
+            state.config.max_document_bytes
+        )));
+    }
+
+    let store = state.store.clone();
+    let saved = meta.clone();
+    blocking(move || {
+        store.compact(&saved.id, &update, 0)?;
+        if let Some(user) = user {
+            store.save_acl(&saved.id, &Acl::owned_by(&user.id))?;
+        }
+        store.save_meta(&saved)
+    })
+    .await?;
+    tracing::info!(id = %meta.id, elements = content.elements.len(), "imported screenplay");
+
+    Ok((StatusCode::CREATED, Json(meta)))
+}
+
+/// The first line of the title page's title, without emphasis markers.
+fn script_title(content: &shared::screenplay::Screenplay) -> Option<&str> {
+    content
+        .title_page
+        .get("title")?
+        .lines()
+        .map(|line| line.trim().trim_matches(['*', '_']).trim())
+        .find(|line| !line.is_empty())
+}
+
+async fn fetch(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+) -> Result<Json<Screenplay>, ApiError> {
+    authorize(&state, &headers, &id, Role::Viewer).await?;
+    let meta = load_meta(&state, &id).await?;
+    let content = load_content(&state, &id).await?;
+
+    Ok(Json(Screenplay { meta, content }))
+}
+
+/// The screenplay as a Fountain script.
+async fn export(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    authorize(&state, &headers, &id, Role::Viewer).await?;
+    load_meta(&state, &id).await?;
+    let content = load_content(&state, &id).await?;
+
+    Ok((
+        [(header::CONTENT_TYPE, "text/plain; charset=utf-8")],
+        fountain::write(&content),
+    )
+        .into_response())
+}
+
+async fn snapshot(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    authorize(&state, &headers, &id, Role::Viewer).await?;
+    load_meta(&state, &id).await?;
+    let update = load_snapshot(&state, &id).await?;
+
+    Ok(([(header::CONTENT_TYPE, "application/octet-stream")], update).into_response())
+}
+
+async fn rename(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+    Json(body): Json<RenameScreenplay>,
+) -> Result<Json<ScreenplayMeta>, ApiError> {
+    authorize(&state, &headers, &id, Role::Editor).await?;
+    let mut meta = load_meta(&state, &id).await?;
+    meta.title = validate_title(&body.title)?;
+    meta.updated_at = now();
+
+    let store = state.store.clone();
+    let saved = meta.clone();
+    blocking(move || store.save_meta(&saved)).await?;
+
+    Ok(Json(meta))
+}
+
+async fn duplicate(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+    body: Option<Json<NewScreenplay>>,
+) -> Result<(StatusCode, Json<ScreenplayMeta>), ApiError> {
+    let user = authorize(&state, &headers, &id, Role::Viewer).await?;
+    let source = load_meta(&state, &id).await?;
+    let title = match body.and_then(|Json(body)| body.title) {
+        Some(title) => validate_title(&title)?,
+        None => validate_title(&format!("Copy of {}", source.title))?,
+    };
+    let update = load_snapshot(&state, &id).await?;
+    let meta = new_meta(title);
+
+    let store = state.store.clone();
+    let saved = meta.clone();
+    blocking(move || {
+        store.compact(&saved.id, &update, 0)?;
+        if let Some(user) = user {
+            store.save_acl(&saved.id, &Acl::owned_by(&user.id))?;
+        }
+        store.save_meta(&saved)
+    })
+    .await?;
+    tracing::info!(from = %id, id = %meta.id, "duplicated screenplay");
+
+    Ok((StatusCode::CREATED, Json(meta)))
+}
+
+async fn delete(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+) -> Result<StatusCode, ApiError> {
+    authorize(&state, &headers, &id, Role::Owner).await?;
+    let mut meta = load_meta(&state, &id).await?;
+    meta.deleted_at = Some(now());
+    meta.updated_at = now();
+
+    let store = state.store.clone();
+    blocking(move || store.save_meta(&meta)).await?;
+    state.close_room(&id, "screenplay deleted").await;
+    tracing::info!(%id, "deleted screenplay");
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn get_acl(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+) -> Result<Json<Acl>, ApiError> {
+    authorize(&state, &headers, &id, Role::Owner).await?;
+    load_meta(&state, &id).await?;
+
+    Ok(Json(load_acl(&state, &id).await?))
+}
+
+async fn set_acl(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+    Json(acl): Json<Acl>,
+) -> Result<Json<Acl>, ApiError> {
+    authorize(&state, &headers, &id, Role::Owner).await?;
+    load_meta(&state, &id).await?;
+
+    if acl.default_role == Some(Role::Owner) {
+        return Err(ApiError::BadRequest(
+            "default_role cannot be owner".to_string(),
+        ));
+    }
+    if state.auth.is_some() && !acl.members.values().any(|r| *r == Role::Owner) {
+        return Err(ApiError::BadRequest(
+            "the acl must keep at least one owner".to_string(),
+        ));
+    }
+
+    let store = state.store.clone();
+    let saved = acl.clone();
+    let doc_id = id.clone();
+    blocking(move || store.save_acl(&doc_id, &saved)).await?;
+    tracing::info!(%id, "updated access list");
+
+    let room = state.rooms.get(&id).map(|r| r.cmd_tx.clone());
+    if let Some(cmd_tx) = room {
+        let _ = cmd_tx.send(RoomCmd::SetAcl { acl: acl.clone() }).await;
+    }
+
+    Ok(Json(acl))
+}
+
+async fn list_shares(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<ShareLink>>, ApiError> {
+    authorize(&state, &headers, &id, Role::Owner).await?;
+    load_meta(&state, &id).await?;
+
+    let store = state.store.clone();
+    let mut links = blocking(move || store.list_shares(&id)).await?;
+    links.sort_by_key(|l| std::cmp::Reverse(l.created_at));
+
+    Ok(Json(links))
+}
+
+async fn create_share(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+    Json(body): Json<NewShareLink>,
+) -> Result<(StatusCode, Json<ShareLink>), ApiError> {
+    authorize(&state, &headers, &id, Role::Owner).await?;
+    load_meta(&state, &id).await?;
+
+    if body.role == Role::Owner {
+        return Err(ApiError::BadRequest(
+            "share links cannot grant ownership".to_string(),
+        ));
+    }
+    let lifetime = body.expires_in_secs.unwrap_or(DEFAULT_SHARE_SECS);
+    if lifetime == 0 || lifetime > MAX_SHARE_SECS {
+        return Err(ApiError::BadRequest(format!(
+            "expires_in_secs must be between 1 and {MAX_SHARE_SECS}"
+        )));
+    }
+    if body.max_uses == Some(0) {
+        return Err(ApiError::BadRequest(
+            "max_uses must be at least 1".to_string(),
+        ));
+    }
+
+    let now = now();
+    let link = ShareLink {
+        token: new_session(),
+        doc_id: id,
+        role: body.role,
+        created_at: now,
+        expires_at: now + lifetime,
+        max_uses: body.max_uses,
+        uses: 0,
+    };
+
+    let store = state.store.clone();
+    let saved = link.clone();
+    blocking(move || store.save_share(&saved)).await?;
+    tracing::info!(id = %link.doc_id, role = %link.role, "created share link");
+
+    Ok((StatusCode::CREATED, Json(link)))
+}
+
+async fn revoke_share(
+    State(state): State<AppState>,
+    Path((id, token)): Path<(String, String)>,
+    headers: HeaderMap,
+) -> Result<StatusCode, ApiError> {
+    authorize(&state, &headers, &id, Role::Owner).await?;
+
+    let store = state.store.clone();
+    let lookup = token.clone();
+    let link = blocking(move || store.load_share(&lookup)).await?;
+    if link.is_none_or(|l| l.doc_id != id) {
+        return Err(ApiError::NotFound);
+    }
+
+    let store = state.store.clone();
+    let deleted = token.clone();
+    blocking(move || store.delete_share(&deleted)).await?;
+    tracing::info!(%id, "revoked share link");
+
+    let room = state.rooms.get(&id).map(|r| r.cmd_tx.clone());
+    if let Some(cmd_tx) = room {
+        let _ = cmd_tx.send(RoomCmd::RevokeShare { token }).await;
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Looks up a share link for whoever holds it, without using it.
+async fn resolve_share(
+    State(state): State<AppState>,
+    Path(token): Path<String>,
+) -> Result<Json<ShareLink>, ApiError> {
+    let store = state.store.clone();
+    match blocking(move || store.load_share(&token)).await? {
+        Some(link) if link.is_valid(now()) => Ok(Json(link)),
+        Some(_) => Err(ApiError::Gone),
+        None => Err(ApiError::NotFound),
+    }
+}
+
+/// Looks up the share link `token` for `doc_id` without using it,
+/// returning the role it grants. Links that have used up their
+/// connections still count, so their holders can resume a session.
+pub(crate) async fn share_role(
+    state: &AppState,
+    doc_id: &str,
+    token: &str,
+) -> Result<Role, StatusCode> {
+    let store = state.store.clone();
+    let token = token.to_string();
+    match blocking(move || store.load_share(&token)).await {
+        Ok(Some(link)) if link.doc_id == doc_id && now() < link.expires_at => Ok(link.role),
+        Ok(_) => {
+            tracing::warn!(%doc_id, "refused connection with invalid share link");
+            Err(StatusCode::FORBIDDEN)
+        }
+        Err(e) => {
+            tracing::error!(%doc_id, error = ?e, "failed to load share link");
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+/// Uses the share link `token` to open `doc_id`, returning the role it
+/// grants.
+pub(crate) async fn redeem_share(
+    state: &AppState,
+    doc_id: &str,
+    token: &str,
+) -> Result<Role, StatusCode> {
+    let store = state.store.clone();
+    let (id, token) = (doc_id.to_string(), token.to_string());
+    let redeemed = blocking(move || match store.load_share(&token)? {
+        Some(link) if link.doc_id == id => store.redeem_share(&token, now()),
+        _ => Ok(None),
+    })
+    .await;
+
+    match redeemed {
+        Ok(Some(link)) => Ok(link.role),
+        Ok(None) => {
+            tracing::warn!(%doc_id, "refused connection with invalid share link");
+            Err(StatusCode::FORBIDDEN)
+        }
+        Err(e) => {
+            tracing::error!(%doc_id, error = ?e, "failed to redeem share link");
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+/// Whether a peer with `grant` may open `doc_id` over a WebSocket: the
+/// ACL has to let them in, and deleted screenplays cannot be opened at
+/// all.
+pub(crate) async fn can_open(
+    state: &AppState,
+    doc_id: &str,
+    grant: &Grant,
+) -> Result<(), StatusCode> {
+    let store = state.store.clone();
+    let id = doc_id.to_string();
+    let loaded = blocking(move || {
+        Ok((
+            store.load_meta(&id)?,
+            store.load_acl(&id)?.unwrap_or_default(),
+        ))
+    })
+    .await;
+    match loaded {
+        Ok((Some(meta), _)) if meta.deleted_at.is_some() => Err(StatusCode::GONE),
+        Ok((_, acl)) if grant.role(&acl).is_none() => {
+            tracing::warn!(%doc_id, ?grant, "refused connection without access");
+            Err(StatusCode::FORBIDDEN)
+        }
+        Ok(_) => Ok(()),
+        Err(e) => {
+            tracing::error!(%doc_id, error = ?e, "failed to load metadata");
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+/// The user making a request, or `None` when authentication is off.
+fn caller(state: &AppState, headers: &HeaderMap) -> Result<Option<User>, ApiError> {
+    let Some(auth) = &state.auth else {
+        return Ok(None);
+    };
+
+    auth.verify(headers, None)
+        .map(Some)
+        .map_err(ApiError::Unauthorized)
+}
+
+/// Checks that the caller has at least role `min` in screenplay `id`,
+/// returning who they are.
+async fn authorize(
+    state: &AppState,
+    headers: &HeaderMap,
+    id: &str,
+    min: Role,
+) -> Result<Option<User>, ApiError> {
+    let Some(user) = caller(state, headers)? else {
+        return Ok(None);
+    };
+
+    match load_acl(state, id).await?.role(Some(&user.id)) {
+        Some(role) if role >= min => Ok(Some(user)),
+        _ => Err(ApiError::Forbidden),
+    }
+}
+
+async fn load_acl(state: &AppState, id: &str) -> Result<Acl, ApiError> {
+    let store = state.store.clone();
+    let doc_id = id.to_string();
+    Ok(blocking(move || store.load_acl(&doc_id))
+        .await?
+        .unwrap_or_default())
+}
+
+/// Loads the metadata of a screenplay that exists and is not deleted.
+async fn load_meta(state: &AppState, id: &str) -> Result<ScreenplayMeta, ApiError> {
+    let store = state.store.clone();
+    let doc_id = id.to_string();
+    match blocking(move || store.load_meta(&doc_id)).await? {
+        Some(meta) if meta.deleted_at.is_some() => Err(ApiError::Gone),
+        Some(meta) => Ok(meta),
+        None => Err(ApiError::NotFound),
+    }
+}
+
+/// Encodes the current state of `id`, loading its room if needed.
+async fn load_snapshot(state: &AppState, id: &str) -> Result<Vec<u8>, ApiError> {
+    let handle = state.room(id);
+    request_snapshot(&handle, None)
+        .await
+        .ok_or_else(|| ApiError::Internal("room closed before answering".to_string()))
+}
+
+/// Reads the current screenplay of `id`, loading its room if needed.
+async fn load_content(
+    state: &AppState,
+    id: &str,
+) -> Result<shared::screenplay::Screenplay, ApiError> {
+    let update = load_snapshot(state, id).await?;
+
+    let doc = Doc::new();
+    let update = Update::decode_v1(&update).map_err(|e| ApiError::Internal(e.to_string()))?;
+    doc.transact_mut()
+        .apply_update(update)
+        .map_err(|e| ApiError::Internal(e.to_string()))?;
+    let content = ydoc::read(&doc.transact()).map_err(|e| ApiError::Internal(e.to_string()))?;
+
+    Ok(content)
+}
+
+/// Asks a running room for its peers. Returns `None` if it is not running.
+async fn query_room(state: &AppState, doc_id: &str) -> Option<RoomStats> {
+    let cmd_tx = state.rooms.get(doc_id)?.cmd_tx.clone();
+    let (tx, rx) = oneshot::channel();
+    cmd_tx.send(RoomCmd::Stats { tx }).await.ok()?;
+
+    Some(RoomStats {
+        doc_id: doc_id.to_string(),
+        clients: rx.await.ok()?,
+    })
+}
+
+fn new_meta(title: String) -> ScreenplayMeta {
+    let now = now();
+    ScreenplayMeta {
+        id: uuid::Uuid::new_v4().to_string(),
+        title,
+        created_at: now,
+        updated_at: now,
+        deleted_at: None,
+    }
+}
+
+fn validate_title(title: &str) -> Result<String, ApiError> {
+    let title = title.trim();
+    if title.is_empty() {
+        return Err(ApiError::BadRequest("title must not be empty".to_string()));
+    }
+    if title.chars().count() > MAX_TITLE_LEN {
+        return Err(ApiError::BadRequest(format!(
+            "title must be at most {MAX_TITLE_LEN} characters"
+        )));
+    }
+
+    Ok(title.to_string())
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Runs a blocking store call off the async runtime.
+async fn blocking<T: Send + 'static>(
+    f: impl FnOnce() -> Result<T, StoreError> + Send + 'static,
+) -> Result<T, ApiError> {
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(|e| ApiError::Internal(e.to_string()))?
+        .map_err(ApiError::Store)
+}
+
+#[derive(Debug)]
+enum ApiError {
+    Unauthorized(AuthError),
+    Forbidden,
+    NotFound,
+    Gone,
+    BadRequest(String),
+    Store(StoreError),
+    Internal(String),
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let (status, message) = match self {
+            ApiError::Unauthorized(e) => return e.into_response(),
+            ApiError::Forbidden => (
+                StatusCode::FORBIDDEN,
+                "not allowed for your role".to_string(),
+            ),
+            ApiError::NotFound => (StatusCode::NOT_FOUND, "not found".to_string()),
+            ApiError::Gone => (StatusCode::GONE, "screenplay was deleted".to_string()),
+            ApiError::BadRequest(e) => (StatusCode::BAD_REQUEST, e),
+            ApiError::Store(e) => {
+                tracing::error!("store error: {}", e);
+                (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+            }
+            ApiError::Internal(e) => {
+                tracing::error!("internal error: {}", e);
+                (StatusCode::INTERNAL_SERVER_ERROR, e)
+            }
+        };
+
+        (status, Json(serde_json::json!({ "error": message }))).into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, http::Request};
+    use http_body_util::BodyExt;
+    use serde_json::{json, Value};
+    use tokio::sync::mpsc;
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::{
+        auth::{test_token, TEST_SECRET},
+        config::Config,
+    };
+
+    use shared::server::ServerReply;
+
+    fn test_state() -> AppState {
+        AppState::for_tests(Config::for_tests(&[]))
+    }
+
+    fn auth_state() -> AppState {
+        AppState::for_tests(Config::for_tests(&["--auth-secret", TEST_SECRET]))
+    }
+
+    /// Sends `request` through the API router, returning the status and
+    /// the response body.
+    async fn call(state: &AppState, request: Request<Body>) -> (StatusCode, Vec<u8>) {
+        let response = router()
+            .with_state(state.clone())
+            .oneshot(request)
+            .await
+            .unwrap();
+
+        let status = response.status();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        (status, body.to_vec())
+    }
+
+    fn request(method: &str, uri: &str) -> Request<Body> {
+        Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    fn json_request(method: &str, uri: &str, body: Value) -> Request<Body> {
+        Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    }
+
+    /// Signs `request` as `user`.
+    fn authed(mut request: Request<Body>, user: &str) -> Request<Body> {
+        let token = test_token(TEST_SECRET, user, user, 600);
+        let bearer = format!("Bearer {token}").parse().unwrap();
+        request.headers_mut().insert(header::AUTHORIZATION, bearer);
+        request
+    }
+
+    fn parse<T: serde::de::DeserializeOwned>(body: &[u8]) -> T {
+        serde_json::from_slice(body).unwrap()
+    }
+
+    async fn create(state: &AppState, title: &str) -> ScreenplayMeta {
+        let body = json!({ "title": title });
+        let (status, body) = call(state, json_request("POST", "/api/screenplays", body)).await;
+        assert_eq!(status, StatusCode::CREATED);
+        parse(&body)
+    }
+
+    #[tokio::test]
+    async fn manages_screenplays() {
+        let state = test_state();
+        let (status, _) = call(&state, request("GET", "/api/health")).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let meta = create(&state, "  Brick & Steel ").await;
+        assert_eq!(meta.title, "Brick & Steel");
+        let uri = format!("/api/screenplays/{}", meta.id);
+
+        let body = json!({ "title": "Full Retired" });
+        let (status, body) = call(&state, json_request("PATCH", &uri, body)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(parse::<ScreenplayMeta>(&body).title, "Full Retired");
+
+        let (_, body) = call(&state, request("GET", &uri)).await;
+        let fetched: Screenplay = parse(&body);
+        assert_eq!(fetched.meta.title, "Full Retired");
+        assert!(fetched.content.elements.is_empty());
+
+        let (_, body) = call(&state, request("GET", "/api/screenplays")).await;
+        let listed: Vec<ScreenplayMeta> = parse(&body);
+        assert_eq!(listed.iter().map(|m| &m.id).collect::<Vec<_>>(), [&meta.id]);
+
+        let body = json!({ "title": "" });
+        let (status, _) = call(&state, json_request("PATCH", &uri, body)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, _) = call(&state, request("GET", "/api/screenplays/nope")).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn imports_exports_and_duplicates_scripts() {
+        let state = test_state();
+        let script = "Title: Brick & Steel\n\nINT. GARAGE - NIGHT\n\nThe door opens.\n";
+        let import = Request::builder()
+            .method("POST")
+            .uri("/api/screenplays/import")
+            .body(Body::from(script))
+            .unwrap();
+        let (status, body) = call(&state, import).await;
+        assert_eq!(status, StatusCode::CREATED);
+        let meta: ScreenplayMeta = parse(&body);
+        assert_eq!(meta.title, "Brick & Steel");
+
+        let uri = format!("/api/screenplays/{}/fountain", meta.id);
+        let (status, body) = call(&state, request("GET", &uri)).await;
+        assert_eq!(status, StatusCode::OK);
+        let exported = String::from_utf8(body).unwrap();
+        assert_eq!(fountain::parse(&exported), fountain::parse(script));
+
+        let uri = format!("/api/screenplays/{}/duplicate", meta.id);
+        let (status, body) = call(&state, request("POST", &uri)).await;
+        assert_eq!(status, StatusCode::CREATED);
+        let copy: ScreenplayMeta = parse(&body);
+        assert_eq!(copy.title, "Copy of Brick & Steel");
+
+        let mut contents = Vec::new();
+        for id in [&meta.id, &copy.id] {
+            let uri = format!("/api/screenplays/{id}");
+            let (_, body) = call(&state, request("GET", &uri)).await;
+            contents.push(parse::<Screenplay>(&body).content);
+        }
+        assert_eq!(contents[0], contents[1]);
+        assert_eq!(contents[1].elements.len(), 2);
+
+        let uri = format!("/api/screenplays/{}/snapshot", copy.id);
+        let (status, body) = call(&state, request("GET", &uri)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(Update::decode_v1(&body).is_ok());
+    }
+
+    #[tokio::test]
+    async fn deleting_hides_a_screenplay_and_closes_its_room() {
+        let state = test_state();
+        let meta = create(&state, "Doomed").await;
+        let uri = format!("/api/screenplays/{}", meta.id);
+
+        let room = state.room(&meta.id);
+        let (tx, mut rx) = mpsc::channel(16);
+        let join = RoomCmd::Join {
+            peer_id: 1,
+            grant: Grant::Acl(None),
+            name: None,
+            session: None,
+            tx,
+        };
+        room.cmd_tx.send(join).await.unwrap();
+        assert!(matches!(rx.recv().await, Some(ServerReply::Join { .. })));
+
+        let (_, body) = call(&state, request("GET", "/api/rooms")).await;
+        let rooms: Vec<RoomStats> = parse(&body);
+        assert_eq!(rooms[0].clients.len(), 1);
+
+        let (status, _) = call(&state, request("DELETE", &uri)).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let mut closing = None;
+        while let Some(reply) = rx.recv().await {
+            closing = Some(reply);
+        }
+        assert!(matches!(
+            closing,
+            Some(ServerReply::Closing { reason }) if reason == "screenplay deleted"
+        ));
+        assert!(!state.rooms.contains_key(&meta.id));
+
+        let (status, _) = call(&state, request("GET", &uri)).await;
+        assert_eq!(status, StatusCode::GONE);
+        let (_, body) = call(&state, request("GET", "/api/screenplays")).await;
+        assert!(parse::<Vec<ScreenplayMeta>>(&body).is_empty());
+        let (_, body) = call(&state, request("GET", "/api/screenplays?deleted=true")).await;
+        assert_eq!(parse::<Vec<ScreenplayMeta>>(&body).len(), 1);
+    }
+
+    #[tokio::test]
+    async fn holds_callers_to_their_role_in_the_acl() {
+        let state = auth_state();
+        let (status, _) = call(&state, request("GET", "/api/screenplays")).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let body = json!({ "title": "Ada's" });
+        let new = authed(json_request("POST", "/api/screenplays", body), "ada");
+        let (_, body) = call(&state, new).await;
+        let meta: ScreenplayMeta = parse(&body);
+        let uri = format!("/api/screenplays/{}", meta.id);
+        let acl_uri = format!("{uri}/acl");
+
+        let (status, _) = call(&state, authed(request("GET", &uri), "bob")).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (_, body) = call(&state, authed(request("GET", &acl_uri), "ada")).await;
+        assert_eq!(parse::<Acl>(&body), Acl::owned_by("ada"));
+
+        for bad in [
+            json!({ "members": { "bob": "editor" }, "default_role": null }),
+            json!({ "members": { "ada": "owner" }, "default_role": "owner" }),
+        ] {
+            let put = authed(json_request("PUT", &acl_uri, bad), "ada");
+            assert_eq!(call(&state, put).await.0, StatusCode::BAD_REQUEST);
+        }
+        let acl = json!({ "members": { "ada": "owner", "bob": "viewer" }, "default_role": null });
+        let put = authed(json_request("PUT", &acl_uri, acl.clone()), "bob");
+        assert_eq!(call(&state, put).await.0, StatusCode::FORBIDDEN);
+        let put = authed(json_request("PUT", &acl_uri, acl), "ada");
+        assert_eq!(call(&state, put).await.0, StatusCode::OK);
+
+        // A viewer can read, but not rename, delete or see the ACL.
+        let (status, _) = call(&state, authed(request("GET", &uri), "bob")).await;
+        assert_eq!(status, StatusCode::OK);
+        let rename = json_request("PATCH", &uri, json!({ "title": "Bob's" }));
+        assert_eq!(
+            call(&state, authed(rename, "bob")).await.0,
+            StatusCode::FORBIDDEN
+        );
+        for (method, uri) in [("DELETE", &uri), ("GET", &acl_uri)] {
+            let (status, _) = call(&state, authed(request(method, uri), "bob")).await;
+            assert_eq!(status, StatusCode::FORBIDDEN);
+        }
+
+        for (user, visible) in [("bob", 1), ("carol", 0)] {
+            let list = authed(request("GET", "/api/screenplays"), user);
+            let (_, body) = call(&state, list).await;
+            assert_eq!(parse::<Vec<ScreenplayMeta>>(&body).len(), visible, "{user}");
+        }
+    }
+
+    #[tokio::test]
+    async fn creates_resolves_and_revokes_share_links() {
+        let state = auth_state();
+        let body = json!({ "title": "Shared" });
+        let new = authed(json_request("POST", "/api/screenplays", body), "ada");
+        let meta: ScreenplayMeta = parse(&call(&state, new).await.1);
+        let shares = format!("/api/screenplays/{}/shares", meta.id);
+
+        for bad in [
+            json!({ "role": "owner" }),
+            json!({ "role": "viewer", "expires_in_secs": 0 }),
+            json!({ "role": "viewer", "max_uses": 0 }),
+        ] {
+            let post = authed(json_request("POST", &shares, bad), "ada");
+            assert_eq!(call(&state, post).await.0, StatusCode::BAD_REQUEST);
+        }
+        let body = json!({ "role": "editor", "expires_in_secs": 60 });
+        let post = authed(json_request("POST", &shares, body.clone()), "bob");
+        assert_eq!(call(&state, post).await.0, StatusCode::FORBIDDEN);
+        let post = authed(json_request("POST", &shares, body), "ada");
+        let (status, body) = call(&state, post).await;
+        assert_eq!(status, StatusCode::CREATED);
+        let link: ShareLink = parse(&body);
+        assert_eq!(
+            (link.role, link.expires_at - link.created_at),
+            (Role::Editor, 60)
+        );
+
+        // Anyone holding the token can look it up.
+        let resolve = format!("/api/shares/{}", link.token);
+        let (status, body) = call(&state, request("GET", &resolve)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(parse::<ShareLink>(&body), link);
+        let (_, body) = call(&state, authed(request("GET", &shares), "ada")).await;
+        assert_eq!(parse::<Vec<ShareLink>>(&body), std::slice::from_ref(&link));
+
+        // Links past their expiry or out of uses are gone.
+        let expired = ShareLink {
+            token: "expired".to_string(),
+            expires_at: link.created_at,
+            ..link.clone()
+        };
+        let used = ShareLink {
+            token: "used".to_string(),
+            max_uses: Some(2),
+            uses: 2,
+            ..link.clone()
+        };
+        for stale in [expired, used] {
+            state.store.save_share(&stale).unwrap();
+            let resolve = format!("/api/shares/{}", stale.token);
+            assert_eq!(
+                call(&state, request("GET", &resolve)).await.0,
+                StatusCode::GONE
+            );
+        }
+
+        // Revoking a link disconnects the peers that joined with it.
+        let room = state.room(&meta.id);
+        let (tx, mut rx) = mpsc::channel(16);
+        let join = RoomCmd::Join {
+            peer_id: 1,
+            grant: Grant::Share {
+                token: link.token.clone(),
+                role: link.role,
+            },
+            name: None,
+            session: None,
+            tx,
+        };
+        room.cmd_tx.send(join).await.unwrap();
+        assert!(matches!(rx.recv().await, Some(ServerReply::Join { .. })));
+
+        let revoke = format!("{shares}/{}", link.token);
+        let (status, _) = call(&state, authed(request("DELETE", &revoke), "ada")).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        while rx.recv().await.is_some() {}
+        assert_eq!(
+            call(&state, request("GET", &resolve)).await.0,
+            StatusCode::NOT_FOUND
+        );
+        let (status, _) = call(&state, authed(request("DELETE", &revoke), "ada")).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,168 @@
+//! Token authentication for WebSocket upgrades.
+//!
+//! When an auth secret is configured, every connection must present an
+//! HS256-signed JWT, either as `?token=` (browsers cannot set headers on
+//! a WebSocket) or as an `Authorization: Bearer` header. The token's
+//! `sub` identifies the user and `name` is shown to the other peers.
+use axum::{
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use jsonwebtoken::{decode, Algorithm, DecodingKey, Validation};
+
+/// The claims a token must carry. `exp` is required.
+#[derive(Debug, serde::Deserialize)]
+struct Claims {
+    sub: String,
+    name: String,
+}
+
+/// An authenticated user.
+#[derive(Debug, Clone)]
+pub(crate) struct User {
+    pub(crate) id: String,
+    pub(crate) name: String,
+}
+
+pub(crate) struct Auth {
+    key: DecodingKey,
+    validation: Validation,
+}
+
+impl Auth {
+    pub(crate) fn new(secret: &str) -> Self {
+        let mut validation = Validation::new(Algorithm::HS256);
+        validation.set_required_spec_claims(&["exp", "sub"]);
+
+        Self {
+            key: DecodingKey::from_secret(secret.as_bytes()),
+            validation,
+        }
+    }
+
+    /// Checks the token presented with a request, taken from `query` or
+    /// the `Authorization` header.
+    pub(crate) fn verify(
+        &self,
+        headers: &HeaderMap,
+        query: Option<&str>,
+    ) -> Result<User, AuthError> {
+        let token = query
+            .or_else(|| bearer(headers))
+            .ok_or(AuthError::Missing)?;
+
+        let claims = decode::<Claims>(token, &self.key, &self.validation)
+            .map_err(|e| AuthError::Invalid(e.to_string()))?
+            .claims;
+
+        Ok(User {
+            id: claims.sub,
+            name: claims.name,
+        })
+    }
+}
+
+fn bearer(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+}
+
+#[derive(Debug)]
+pub(crate) enum AuthError {
+    /// No token was presented.
+    Missing,
+    /// The token is malformed, expired or not signed with our key.
+    Invalid(String),
+}
+
+impl core::fmt::Display for AuthError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            AuthError::Missing => write!(f, "missing token"),
+            AuthError::Invalid(e) => write!(f, "invalid token: {e}"),
+        }
+    }
+}
+
+impl IntoResponse for AuthError {
+    fn into_response(self) -> Response {
+        (
+            StatusCode::UNAUTHORIZED,
+            [(header::WWW_AUTHENTICATE, "Bearer")],
+            Json(serde_json::json!({ "error": self.to_string() })),
+        )
+            .into_response()
+    }
+}
+
+/// A secret long enough for the config to accept, for tests.
+#[cfg(test)]
+pub(crate) const TEST_SECRET: &str = "an-auth-secret-of-at-least-32-bytes";
+
+/// A token for user `sub` called `name`, signed with `secret` and
+/// expiring `exp_from_now` seconds from now.
+#[cfg(test)]
+pub(crate) fn test_token(secret: &str, sub: &str, name: &str, exp_from_now: i64) -> String {
+    use jsonwebtoken::{encode, EncodingKey, Header};
+
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
+    let claims = serde_json::json!({
+        "sub": sub,
+        "name": name,
+        "exp": now + exp_from_now,
+    });
+    encode(
+        &Header::new(Algorithm::HS256),
+        &claims,
+        &EncodingKey::from_secret(secret.as_bytes()),
+    )
+    .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn token(secret: &str, exp_from_now: i64) -> String {
+        test_token(secret, "u-42", "Ada", exp_from_now)
+    }
+
+    #[test]
+    fn accepts_a_good_token() {
+        let auth = Auth::new(TEST_SECRET);
+        let user = auth
+            .verify(&HeaderMap::new(), Some(&token(TEST_SECRET, 600)))
+            .unwrap();
+        assert_eq!((user.id.as_str(), user.name.as_str()), ("u-42", "Ada"));
+
+        let mut headers = HeaderMap::new();
+        let bearer = format!("Bearer {}", token(TEST_SECRET, 600));
+        headers.insert(header::AUTHORIZATION, bearer.parse().unwrap());
+        assert_eq!(auth.verify(&headers, None).unwrap().id, "u-42");
+    }
+
+    #[test]
+    fn rejects_an_expired_token() {
+        let auth = Auth::new(TEST_SECRET);
+        let result = auth.verify(&HeaderMap::new(), Some(&token(TEST_SECRET, -600)));
+        assert!(matches!(result, Err(AuthError::Invalid(_))), "{result:?}");
+    }
+
+    #[test]
+    fn rejects_a_token_signed_with_another_key() {
+        let auth = Auth::new(TEST_SECRET);
+        let forged = token("another-secret-of-at-least-32-bytes", 600);
+        let result = auth.verify(&HeaderMap::new(), Some(&forged));
+        assert!(matches!(result, Err(AuthError::Invalid(_))), "{result:?}");
+
+        let result = auth.verify(&HeaderMap::new(), None);
+        assert!(matches!(result, Err(AuthError::Missing)), "{result:?}");
+    }
+}
@@ -0,0 +1,182 @@
+//! Server-side copy of a room's awareness state (cursors, selections,
+//! user info), so newcomers see everyone immediately and peers that go
+//! away are cleared from the other screens.
+use std::{
+    collections::{HashMap, HashSet},
+    time::Duration,
+};
+
+use yrs::{
+    sync::{
+        time::{Clock, SystemClock},
+        Awareness, AwarenessUpdate,
+    },
+    updates::{decoder::Decode, encoder::Encode},
+    Doc,
+};
+
+use shared::server::ServerError;
+
+/// Client states that have not been renewed for this long are dropped.
+/// Yjs clients renew theirs every 15 seconds.
+pub(crate) const AWARENESS_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub(crate) struct RoomAwareness {
+    awareness: Awareness,
+    /// Awareness client ids announced by each peer.
+    owners: HashMap<u64, HashSet<u64>>,
+}
+
+impl RoomAwareness {
+    pub(crate) fn new(doc: Doc) -> Self {
+        Self {
+            awareness: Awareness::new(doc),
+            owners: HashMap::new(),
+        }
+    }
+
+    /// Applies a v1 encoded awareness update sent by `peer_id`. Returns
+    /// the update to relay to the other peers, or `None` if it changed
+    /// nothing.
+    ///
+    /// Each client id belongs to the first peer to announce it until that
+    /// peer leaves or its state times out. Updates touching client ids of
+    /// other peers are refused, so a peer cannot overwrite or clear
+    /// someone else's presence.
+    pub(crate) fn apply(
+        &mut self,
+        peer_id: u64,
+        bytes: &[u8],
+    ) -> Result<Option<Vec<u8>>, ServerError> {
+        let update = AwarenessUpdate::decode_v1(bytes)
+            .map_err(|e| ServerError::InvalidAwareness(e.to_string()))?;
+
+        if let Some(client_id) = update
+            .clients
+            .keys()
+            .find(|c| self.owner(**c).is_some_and(|owner| owner != peer_id))
+        {
+            return Err(ServerError::InvalidAwareness(format!(
+                "client {client_id} belongs to another peer"
+            )));
+        }
+
+        let clients: Vec<u64> = update.clients.keys().copied().collect();
+        let summary = self
+            .awareness
+            .apply_update_summary(update)
+            .map_err(|e| ServerError::InvalidAwareness(e.to_string()))?;
+        self.owners.entry(peer_id).or_default().extend(clients);
+
+        Ok(summary.map(|_| bytes.to_vec()))
+    }
+
+    fn owner(&self, client_id: u64) -> Option<u64> {
+        self.owners
+            .iter()
+            .find(|(_, clients)| clients.contains(&client_id))
+            .map(|(peer_id, _)| *peer_id)
+    }
+
+    /// Encodes every live client state, or `None` if there are none.
+    pub(crate) fn encode(&self) -> Option<Vec<u8>> {
+        let update = self.awareness.update().ok()?;
+        if update.clients.is_empty() {
+            return None;
+        }
+
+        Some(update.encode_v1())
+    }
+
+    /// Clears the states announced by `peer_id`, returning the removal
+    /// update to broadcast.
+    pub(crate) fn remove_peer(&mut self, peer_id: u64) -> Option<Vec<u8>> {
+        let clients = self.owners.remove(&peer_id)?;
+        self.remove_clients(clients)
+    }
+
+    /// Clears states that have not been renewed within `AWARENESS_TIMEOUT`,
+    /// returning the removal update to broadcast.
+    pub(crate) fn remove_outdated(&mut self) -> Option<Vec<u8>> {
+        let now = SystemClock.now();
+        let timeout = AWARENESS_TIMEOUT.as_millis() as u64;
+
+        let outdated: HashSet<u64> = self
+            .awareness
+            .iter()
+            .filter(|(_, state)| {
+                state.data.is_some() && now.saturating_sub(state.last_updated) > timeout
+            })
+            .map(|(client_id, _)| client_id)
+            .collect();
+
+        for clients in self.owners.values_mut() {
+            clients.retain(|c| !outdated.contains(c));
+        }
+
+        self.remove_clients(outdated)
+    }
+
+    fn remove_clients(&mut self, clients: HashSet<u64>) -> Option<Vec<u8>> {
+        if clients.is_empty() {
+            return None;
+        }
+
+        for &client_id in &clients {
+            self.awareness.remove_state(client_id);
+        }
+
+        let update = self.awareness.update_with_clients(clients).ok()?;
+        Some(update.encode_v1())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use yrs::sync::awareness::AwarenessUpdateEntry;
+
+    use super::*;
+
+    fn update(client_id: u64, clock: u32, json: &str) -> Vec<u8> {
+        AwarenessUpdate {
+            clients: HashMap::from([(
+                client_id,
+                AwarenessUpdateEntry {
+                    clock,
+                    json: json.into(),
+                },
+            )]),
+        }
+        .encode_v1()
+    }
+
+    #[test]
+    fn refuses_updates_to_another_peers_client() {
+        let mut awareness = RoomAwareness::new(Doc::new());
+        assert!(awareness
+            .apply(1, &update(7, 1, r#"{"name":"A"}"#))
+            .unwrap()
+            .is_some());
+
+        assert!(awareness
+            .apply(2, &update(7, 2, r#"{"name":"B"}"#))
+            .is_err());
+        assert!(awareness.apply(2, &update(7, 2, "null")).is_err());
+        assert!(awareness.remove_peer(2).is_none());
+
+        let state = AwarenessUpdate::decode_v1(&awareness.encode().unwrap()).unwrap();
+        assert_eq!(&*state.clients[&7].json, r#"{"name":"A"}"#);
+    }
+
+    #[test]
+    fn frees_a_client_when_its_peer_leaves() {
+        let mut awareness = RoomAwareness::new(Doc::new());
+        awareness
+            .apply(1, &update(7, 1, r#"{"name":"A"}"#))
+            .unwrap();
+        assert!(awareness.remove_peer(1).is_some());
+
+        assert!(awareness.apply(2, &update(7, 3, r#"{"name":"B"}"#)).is_ok());
+        assert!(awareness.remove_peer(2).is_some());
+    }
+}
@@ -0,0 +1,463 @@
+//! Server configuration.
+//!
+//! Every setting can come from a command-line flag, a `PROSIA_*`
+//! environment variable or a TOML file given with `--config`, in that
+//! order of precedence, falling back to a built-in default.
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use axum::http::{header::ORIGIN, HeaderMap};
+use clap::Parser;
+
+use crate::store::Backend;
+
+const DEFAULT_BIND: &str = "0.0.0.0:3001";
+const DEFAULT_ROOM_IDLE_SECS: u64 = 300;
+const DEFAULT_MAX_MESSAGE_BYTES: usize = 16 << 20;
+const DEFAULT_MAX_UPDATE_BYTES: usize = 1 << 20;
+const DEFAULT_MAX_DOCUMENT_BYTES: usize = 64 << 20;
+const DEFAULT_UPDATES_PER_SEC: u32 = 50;
+const DEFAULT_UPDATE_BURST: u32 = 200;
+const DEFAULT_MAX_VIOLATIONS: u32 = 20;
+const DEFAULT_SHUTDOWN_TIMEOUT_SECS: u64 = 10;
+/// Shortest HMAC key accepted, in bytes.
+const MIN_AUTH_SECRET_LEN: usize = 32;
+
+/// Command-line flags, each of which can also be set from the
+/// environment.
+#[derive(Debug, Parser)]
+#[command(name = "be", about = "Prosia collaboration server")]
+struct Args {
+    /// TOML file to read settings from.
+    #[arg(long, env = "PROSIA_CONFIG")]
+    config: Option<PathBuf>,
+    /// Address to listen on.
+    #[arg(long, env = "PROSIA_BIND")]
+    bind: Option<String>,
+    /// Storage backend for documents (`fs` or `sqlite`).
+    #[arg(long, env = "PROSIA_STORE")]
+    store: Option<String>,
+    /// Directory or database file the backend stores documents in.
+    #[arg(long, env = "PROSIA_STORE_PATH")]
+    store_path: Option<String>,
+    /// Seconds a room without peers waits before hibernating.
+    #[arg(long, env = "PROSIA_ROOM_IDLE_SECS")]
+    room_idle_secs: Option<u64>,
+    /// Largest WebSocket message accepted from a client, in bytes.
+    #[arg(long, env = "PROSIA_MAX_MESSAGE_BYTES")]
+    max_message_bytes: Option<usize>,
+    /// Largest document or awareness update accepted, in bytes.
+    #[arg(long, env = "PROSIA_MAX_UPDATE_BYTES")]
+    max_update_bytes: Option<usize>,
+    /// Size a document may not grow past, in bytes.
+    #[arg(long, env = "PROSIA_MAX_DOCUMENT_BYTES")]
+    max_document_bytes: Option<usize>,
+    /// Sustained rate of document and awareness updates a client may
+    /// send, per second.
+    #[arg(long, env = "PROSIA_UPDATES_PER_SEC")]
+    updates_per_sec: Option<u32>,
+    /// Updates a client may send in a burst above the sustained rate.
+    #[arg(long, env = "PROSIA_UPDATE_BURST")]
+    update_burst: Option<u32>,
+    /// Refused messages within a minute after which a client is
+    /// disconnected.
+    #[arg(long, env = "PROSIA_MAX_VIOLATIONS")]
+    max_violations: Option<u32>,
+    /// Log output format.
+    #[arg(long, env = "PROSIA_LOG_FORMAT")]
+    log_format: Option<LogFormat>,
+    /// Origins allowed to open WebSocket connections. Any origin is
+    /// allowed when none are given.
+    #[arg(
+        long = "allowed-origin",
+        env = "PROSIA_ALLOWED_ORIGINS",
+        value_delimiter = ','
+    )]
+    allowed_origins: Option<Vec<String>>,
+    /// Rooms to load at startup.
+    #[arg(long = "preload", env = "PROSIA_PRELOAD", value_delimiter = ',')]
+    preload: Option<Vec<String>>,
+    /// Seconds to wait for rooms to save their documents on shutdown.
+    #[arg(long, env = "PROSIA_SHUTDOWN_TIMEOUT_SECS")]
+    shutdown_timeout_secs: Option<u64>,
+    /// Key that connection tokens are signed with. Connections are not
+    /// authenticated when it is unset.
+    #[arg(long, env = "PROSIA_AUTH_SECRET", hide_env_values = true)]
+    auth_secret: Option<String>,
+    /// Break room metrics down by doc id. Off by default, since doc ids
+    /// are private to the people a screenplay is shared with.
+    #[arg(
+        long,
+        env = "PROSIA_METRICS_PER_ROOM",
+        num_args = 0..=1,
+        default_missing_value = "true"
+    )]
+    metrics_per_room: Option<bool>,
+}
+
+/// The settings accepted in the TOML file. Field names match the
+/// long flags, with `_` instead of `-`.
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileConfig {
+    bind: Option<String>,
+    store: Option<String>,
+    store_path: Option<String>,
+    room_idle_secs: Option<u64>,
+    max_message_bytes: Option<usize>,
+    max_update_bytes: Option<usize>,
+    max_document_bytes: Option<usize>,
+    updates_per_sec: Option<u32>,
+    update_burst: Option<u32>,
+    max_violations: Option<u32>,
+    log_format: Option<LogFormat>,
+    allowed_origins: Option<Vec<String>>,
+    preload: Option<Vec<String>>,
+    shutdown_timeout_secs: Option<u64>,
+    auth_secret: Option<String>,
+    metrics_per_room: Option<bool>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum LogFormat {
+    Compact,
+    Pretty,
+    Json,
+}
+
+#[derive(Debug)]
+pub(crate) struct Config {
+    pub(crate) bind: SocketAddr,
+    pub(crate) store: Backend,
+    pub(crate) store_path: String,
+    /// How long a room with no peers waits before hibernating.
+    pub(crate) idle_timeout: Duration,
+    pub(crate) max_message_bytes: usize,
+    pub(crate) max_update_bytes: usize,
+    pub(crate) max_document_bytes: usize,
+    pub(crate) updates_per_sec: u32,
+    pub(crate) update_burst: u32,
+    pub(crate) max_violations: u32,
+    pub(crate) log_format: LogFormat,
+    /// Allowed values of the `Origin` header; empty allows any.
+    pub(crate) allowed_origins: Vec<String>,
+    pub(crate) preload: Vec<String>,
+    /// How long shutdown waits for rooms to persist their documents.
+    pub(crate) shutdown_timeout: Duration,
+    pub(crate) auth_secret: Option<String>,
+    /// Whether `/metrics` labels room gauges with their doc id.
+    pub(crate) metrics_per_room: bool,
+}
+
+impl Config {
+    /// Reads the configuration from the command line, the environment
+    /// and the config file. Exits with a usage message on bad flags.
+    pub(crate) fn load() -> Result<Self, ConfigError> {
+        let args = Args::parse();
+        let file = match &args.config {
+            Some(path) => read_file(path)?,
+            None => FileConfig::default(),
+        };
+
+        Self::resolve(args, file)
+    }
+
+    fn resolve(args: Args, file: FileConfig) -> Result<Self, ConfigError> {
+        let bind = args
+            .bind
+            .or(file.bind)
+            .unwrap_or_else(|| DEFAULT_BIND.to_string());
+        let bind = bind
+            .parse()
+            .map_err(|_| ConfigError::Invalid("bind", format!("not a socket address: {bind}")))?;
+
+        let store: Backend = args
+            .store
+            .or(file.store)
+            .unwrap_or_else(|| "fs".to_string())
+            .parse()
+            .map_err(|e: crate::store::StoreError| ConfigError::Invalid("store", e.to_string()))?;
+        let store_path = args
+            .store_path
+            .or(file.store_path)
+            .unwrap_or_else(|| store.default_path().to_string());
+        if store_path.is_empty() {
+            return Err(ConfigError::Invalid(
+                "store_path",
+                "must not be empty".into(),
+            ));
+        }
+
+        // Every limit and duration must be positive: a zero would refuse
+        // everything, or stop rate limits and timeouts from working.
+        let room_idle_secs = at_least_one(
+            "room_idle_secs",
+            args.room_idle_secs
+                .or(file.room_idle_secs)
+                .unwrap_or(DEFAULT_ROOM_IDLE_SECS),
+        )?;
+        let max_message_bytes = at_least_one(
+            "max_message_bytes",
+            args.max_message_bytes
+                .or(file.max_message_bytes)
+                .unwrap_or(DEFAULT_MAX_MESSAGE_BYTES),
+        )?;
+        let max_update_bytes = at_least_one(
+            "max_update_bytes",
+            args.max_update_bytes
+                .or(file.max_update_bytes)
+                .unwrap_or(DEFAULT_MAX_UPDATE_BYTES),
+        )?;
+        let max_document_bytes = at_least_one(
+            "max_document_bytes",
+            args.max_document_bytes
+                .or(file.max_document_bytes)
+                .unwrap_or(DEFAULT_MAX_DOCUMENT_BYTES),
+        )?;
+        let updates_per_sec = at_least_one(
+            "updates_per_sec",
+            args.updates_per_sec
+                .or(file.updates_per_sec)
+                .unwrap_or(DEFAULT_UPDATES_PER_SEC),
+        )?;
+        let update_burst = at_least_one(
+            "update_burst",
+            args.update_burst
+                .or(file.update_burst)
+                .unwrap_or(DEFAULT_UPDATE_BURST),
+        )?;
+        let max_violations = at_least_one(
+            "max_violations",
+            args.max_violations
+                .or(file.max_violations)
+                .unwrap_or(DEFAULT_MAX_VIOLATIONS),
+        )?;
+        let shutdown_timeout_secs = at_least_one(
+            "shutdown_timeout_secs",
+            args.shutdown_timeout_secs
+                .or(file.shutdown_timeout_secs)
+                .unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT_SECS),
+        )?;
+
+        let allowed_origins = args
+            .allowed_origins
+            .or(file.allowed_origins)
+            .unwrap_or_default();
+        for origin in &allowed_origins {
+            validate_origin(origin)?;
+        }
+
+        let auth_secret = args.auth_secret.or(file.auth_secret);
+        if auth_secret
+            .as_ref()
+            .is_some_and(|s| s.len() < MIN_AUTH_SECRET_LEN)
+        {
+            return Err(ConfigError::Invalid(
+                "auth_secret",
+                format!("must be at least {MIN_AUTH_SECRET_LEN} bytes"),
+            ));
+        }
+
+        Ok(Self {
+            bind,
+            store,
+            store_path,
+            idle_timeout: Duration::from_secs(room_idle_secs),
+            max_message_bytes,
+            max_update_bytes,
+            max_document_bytes,
+            updates_per_sec,
+            update_burst,
+            max_violations,
+            log_format: args
+                .log_format
+                .or(file.log_format)
+                .unwrap_or(LogFormat::Compact),
+            allowed_origins,
+            preload: args
+                .preload
+                .or(file.preload)
+                .unwrap_or_else(|| vec!["lobby".to_string()]),
+            shutdown_timeout: Duration::from_secs(shutdown_timeout_secs),
+            auth_secret,
+            metrics_per_room: args
+                .metrics_per_room
+                .or(file.metrics_per_room)
+                .unwrap_or(false),
+        })
+    }
+
+    /// Whether a request carrying `headers` may open a connection.
+    /// Requests without an `Origin` header come from non-browser
+    /// clients and are always let through.
+    pub(crate) fn allows_origin(&self, headers: &HeaderMap) -> bool {
+        if self.allowed_origins.is_empty() {
+            return true;
+        }
+
+        match headers.get(ORIGIN).map(|o| o.to_str()) {
+            None => true,
+            Some(Ok(origin)) => self.allowed_origins.iter().any(|o| o == origin),
+            Some(Err(_)) => false,
+        }
+    }
+}
+
+#[cfg(test)]
+impl Config {
+    /// The defaults overridden by command-line `flags`, for tests.
+    pub(crate) fn for_tests(flags: &[&str]) -> Self {
+        let args =
+            Args::try_parse_from(std::iter::once("be").chain(flags.iter().copied())).unwrap();
+        Self::resolve(args, FileConfig::default()).unwrap()
+    }
+}
+
+fn at_least_one<T: PartialEq + Default>(name: &'static str, value: T) -> Result<T, ConfigError> {
+    if value == T::default() {
+        return Err(ConfigError::Invalid(name, "must be at least 1".into()));
+    }
+
+    Ok(value)
+}
+
+fn read_file(path: &Path) -> Result<FileConfig, ConfigError> {
+    let text =
+        std::fs::read_to_string(path).map_err(|e| ConfigError::Read(path.to_path_buf(), e))?;
+    toml::from_str(&text).map_err(|e| ConfigError::Parse(path.to_path_buf(), e))
+}
+
+/// Origins are compared verbatim with the `Origin` header, so they must
+/// be a bare `scheme://host[:port]`.
+fn validate_origin(origin: &str) -> Result<(), ConfigError> {
+    let host = origin
+        .strip_prefix("http://")
+        .or_else(|| origin.strip_prefix("https://"));
+
+    match host {
+        Some(host) if !host.is_empty() && !host.contains('/') => Ok(()),
+        _ => Err(ConfigError::Invalid(
+            "allowed_origins",
+            format!("expected scheme://host[:port], got {origin}"),
+        )),
+    }
+}
+
+#[derive(Debug)]
+pub(crate) enum ConfigError {
+    Read(PathBuf, std::io::Error),
+    Parse(PathBuf, toml::de::Error),
+    /// A setting has a value the server cannot use.
+    Invalid(&'static str, String),
+}
+
+impl core::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            ConfigError::Read(path, e) => write!(f, "cannot read {}: {e}", path.display()),
+            ConfigError::Parse(path, e) => write!(f, "invalid config file {}: {e}", path.display()),
+            ConfigError::Invalid(setting, e) => write!(f, "invalid {setting}: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resolve(flags: &[&str], file: &str) -> Result<Config, ConfigError> {
+        let args =
+            Args::try_parse_from(std::iter::once("be").chain(flags.iter().copied())).unwrap();
+        Config::resolve(args, toml::from_str(file).unwrap())
+    }
+
+    fn invalid_setting(result: Result<Config, ConfigError>) -> &'static str {
+        match result {
+            Err(ConfigError::Invalid(setting, _)) => setting,
+            other => panic!("expected an invalid setting, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn flags_override_the_file_and_the_file_overrides_defaults() {
+        let file = r#"
+            bind = "127.0.0.1:4000"
+            room_idle_secs = 5
+            updates_per_sec = 7
+        "#;
+        let config = resolve(
+            &["--bind", "127.0.0.1:5000", "--updates-per-sec", "9"],
+            file,
+        )
+        .unwrap();
+
+        assert_eq!(config.bind, "127.0.0.1:5000".parse().unwrap());
+        assert_eq!(config.updates_per_sec, 9);
+        assert_eq!(config.idle_timeout, Duration::from_secs(5));
+        assert_eq!(config.update_burst, DEFAULT_UPDATE_BURST);
+        assert_eq!(config.store, Backend::Fs);
+        assert_eq!(config.store_path, "data");
+        assert_eq!(config.preload, ["lobby"]);
+        assert!(!config.metrics_per_room);
+
+        let config = resolve(&["--metrics-per-room"], "metrics_per_room = false").unwrap();
+        assert!(config.metrics_per_room);
+    }
+
+    #[test]
+    fn rejects_invalid_settings() {
+        let short_secret = format!("auth_secret = \"{}\"", "x".repeat(MIN_AUTH_SECRET_LEN - 1));
+        for (file, setting) in [
+            ("bind = \"localhost\"", "bind"),
+            ("store = \"tape\"", "store"),
+            ("store_path = \"\"", "store_path"),
+            (
+                "allowed_origins = [\"https://example.com/app\"]",
+                "allowed_origins",
+            ),
+            ("allowed_origins = [\"example.com\"]", "allowed_origins"),
+            (short_secret.as_str(), "auth_secret"),
+        ] {
+            assert_eq!(invalid_setting(resolve(&[], file)), setting, "{file}");
+        }
+
+        let secret = "x".repeat(MIN_AUTH_SECRET_LEN);
+        assert!(resolve(&["--auth-secret", &secret], "").is_ok());
+    }
+
+    #[test]
+    fn rejects_zero_limits_and_durations() {
+        for setting in [
+            "room_idle_secs",
+            "max_message_bytes",
+            "max_update_bytes",
+            "max_document_bytes",
+            "updates_per_sec",
+            "update_burst",
+            "max_violations",
+            "shutdown_timeout_secs",
+        ] {
+            let file = format!("{setting} = 0");
+            assert_eq!(invalid_setting(resolve(&[], &file)), setting, "{file}");
+
+            let flag = format!("--{}=0", setting.replace('_', "-"));
+            assert_eq!(invalid_setting(resolve(&[&flag], "")), setting, "{flag}");
+
+            let flag = format!("--{}=-1", setting.replace('_', "-"));
+            assert!(Args::try_parse_from(["be", &flag]).is_err(), "{flag}");
+            let file = format!("{setting} = -1");
+            assert!(toml::from_str::<FileConfig>(&file).is_err(), "{file}");
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_file_settings() {
+        assert!(toml::from_str::<FileConfig>("bind_address = \"0.0.0.0:80\"").is_err());
+    }
+}
@@ -0,0 +1,202 @@
+//! Per-connection limits on what a client may push into a room.
+//!
+//! Document and awareness updates are capped in size and rate limited
+//! with a token bucket. A client sending document updates too fast is
+//! slowed down rather than refused, since a dropped update would leave
+//! it with changes the room never sees; awareness updates past the rate
+//! are refused. Each refused message is a strike; a client that collects
+//! too many strikes without a minute's break is disconnected.
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use crate::config::Config;
+
+use shared::server::ServerError;
+
+/// Strikes older than this are forgiven.
+const STRIKE_WINDOW: Duration = Duration::from_secs(60);
+
+/// A token bucket holding up to `burst` tokens, refilled at `per_sec`.
+struct TokenBucket {
+    tokens: f64,
+    burst: f64,
+    per_sec: f64,
+    refilled: Instant,
+}
+
+impl TokenBucket {
+    fn new(per_sec: u32, burst: u32) -> Self {
+        Self {
+            tokens: f64::from(burst),
+            burst: f64::from(burst),
+            per_sec: f64::from(per_sec),
+            refilled: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.per_sec).min(self.burst);
+        self.refilled = now;
+    }
+
+    /// Takes a token if one is available.
+    fn try_take(&mut self) -> bool {
+        self.refill();
+        if self.tokens < 1.0 {
+            return false;
+        }
+
+        self.tokens -= 1.0;
+        true
+    }
+
+    /// Waits until a token is available and takes it.
+    async fn take(&mut self) {
+        self.refill();
+        if self.tokens < 1.0 {
+            let wait = (1.0 - self.tokens) / self.per_sec;
+            tokio::time::sleep(Duration::from_secs_f64(wait)).await;
+            self.refill();
+        }
+
+        self.tokens -= 1.0;
+    }
+}
+
+pub(crate) struct Limiter {
+    bucket: TokenBucket,
+    max_update_bytes: usize,
+    max_strikes: u32,
+    strikes: u32,
+    last_strike: Option<Instant>,
+}
+
+impl Limiter {
+    pub(crate) fn new(config: &Config) -> Self {
+        Self {
+            bucket: TokenBucket::new(config.updates_per_sec, config.update_burst),
+            max_update_bytes: config.max_update_bytes,
+            max_strikes: config.max_violations,
+            strikes: 0,
+            last_strike: None,
+        }
+    }
+
+    /// Checks an awareness update of `len` bytes against the size cap and
+    /// the rate limit.
+    pub(crate) fn check(&mut self, len: usize) -> Result<(), ServerError> {
+        if len > self.max_update_bytes {
+            return Err(ServerError::TooLarge(self.max_update_bytes));
+        }
+        if !self.bucket.try_take() {
+            return Err(ServerError::RateLimited);
+        }
+
+        Ok(())
+    }
+
+    /// Checks a document update of `len` bytes against the size cap,
+    /// then waits for the rate limit to let it through.
+    pub(crate) async fn admit(&mut self, len: usize) -> Result<(), ServerError> {
+        if len > self.max_update_bytes {
+            return Err(ServerError::TooLarge(self.max_update_bytes));
+        }
+
+        self.bucket.take().await;
+        Ok(())
+    }
+
+    /// Records a refused message.
+    pub(crate) fn strike(&mut self) {
+        if self
+            .last_strike
+            .is_some_and(|last| last.elapsed() > STRIKE_WINDOW)
+        {
+            self.strikes = 0;
+        }
+
+        self.strikes += 1;
+        self.last_strike = Some(Instant::now());
+    }
+
+    /// Whether the client has been refused often enough to be dropped.
+    pub(crate) fn exhausted(&self) -> bool {
+        self.strikes > self.max_strikes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(per_sec: u32, burst: u32, max_violations: u32) -> Limiter {
+        Limiter {
+            bucket: TokenBucket::new(per_sec, burst),
+            max_update_bytes: 100,
+            max_strikes: max_violations,
+            strikes: 0,
+            last_strike: None,
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn bucket_refills_at_the_sustained_rate() {
+        let mut bucket = TokenBucket::new(10, 3);
+        assert!((0..3).all(|_| bucket.try_take()));
+        assert!(!bucket.try_take());
+
+        tokio::time::advance(Duration::from_millis(100)).await;
+        assert!(bucket.try_take());
+        assert!(!bucket.try_take());
+
+        // Never refills past the burst.
+        tokio::time::advance(Duration::from_secs(60)).await;
+        assert!((0..3).all(|_| bucket.try_take()));
+        assert!(!bucket.try_take());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn document_updates_wait_for_a_token() {
+        let mut limiter = limiter(10, 1, 0);
+        let start = Instant::now();
+        limiter.admit(10).await.unwrap();
+        limiter.admit(10).await.unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(100));
+
+        assert!(matches!(
+            limiter.admit(101).await,
+            Err(ServerError::TooLarge(100))
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn awareness_updates_past_the_rate_are_refused() {
+        let mut limiter = limiter(10, 1, 0);
+        assert!(limiter.check(10).is_ok());
+        assert!(matches!(limiter.check(10), Err(ServerError::RateLimited)));
+        assert!(matches!(
+            limiter.check(101),
+            Err(ServerError::TooLarge(100))
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn strikes_are_forgiven_after_a_quiet_minute() {
+        let mut limiter = limiter(10, 1, 2);
+        limiter.strike();
+        limiter.strike();
+        assert!(!limiter.exhausted());
+
+        tokio::time::advance(STRIKE_WINDOW + Duration::from_secs(1)).await;
+        limiter.strike();
+        limiter.strike();
+        assert!(!limiter.exhausted());
+
+        tokio::time::advance(Duration::from_secs(30)).await;
+        limiter.strike();
+        assert!(limiter.exhausted());
+    }
+}
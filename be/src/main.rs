@@ -1,48 +1,611 @@
-use std::sync::Arc;
+use std::{
+    process::ExitCode,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
 
 use axum::{
-    extract::{ws::WebSocketUpgrade, Path, State},
-    response::IntoResponse,
+    extract::{ws::WebSocketUpgrade, Path, Query, State},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
     routing::get,
-    Router,
+    Json, Router,
 };
 use dashmap::DashMap;
 
+use config::{Config, LogFormat};
+use socket::Joiner;
+use state::Grant;
+
+mod api;
+mod auth;
+mod awareness;
+mod config;
+mod limits;
+mod metrics;
 mod peers;
 mod room;
+mod schema;
 mod socket;
 mod state;
+mod stats;
+mod store;
+mod yws;
+
+/// Query parameters accepted on the WebSocket upgrade.
+#[derive(serde::Deserialize)]
+struct JoinParams {
+    /// Display name shown to the other peers in the room.
+    name: Option<String>,
+    /// Session token from an earlier `Join` reply, to resume as that peer.
+    session: Option<String>,
+    /// Signed token identifying the user, when authentication is on.
+    token: Option<String>,
+    /// Share link token, to join with the role the link grants.
+    share: Option<String>,
+}
+
+async fn stats_handler() -> impl IntoResponse {
+    Json(stats::Stats::read())
+}
+
+/// Applies the configured origin check, authentication and message size
+/// limit to a WebSocket upgrade for `doc_id`, and works out who the
+/// connection joins as.
+///
+/// A connection with a share link joins anonymously with the link's role,
+/// whether authentication is on or not. Every connection gets a peer id
+/// of its own, so a user can have the document open in several tabs; a
+/// session token only resumes a peer that joined as the same user or
+/// with the same link.
+async fn upgrade(
+    state: &state::AppState,
+    doc_id: &str,
+    headers: &HeaderMap,
+    params: JoinParams,
+    ws: WebSocketUpgrade,
+) -> Result<(WebSocketUpgrade, Joiner), Response> {
+    if state.shutting_down.load(Ordering::SeqCst) {
+        return Err(StatusCode::SERVICE_UNAVAILABLE.into_response());
+    }
+
+    if !state.config.allows_origin(headers) {
+        tracing::warn!(origin = ?headers.get("origin"), "rejected connection from origin");
+        return Err(StatusCode::FORBIDDEN.into_response());
+    }
+
+    let joiner = match (params.share, &state.auth) {
+        (Some(token), _) => {
+            let role = api::share_role(state, doc_id, &token)
+                .await
+                .map_err(IntoResponse::into_response)?;
+            Joiner {
+                peer_id: rand::random(),
+                grant: Grant::Share { token, role },
+                name: params.name,
+                session: params.session,
+            }
+        }
+        (None, Some(auth)) => {
+            let user = auth
+                .verify(headers, params.token.as_deref())
+                .inspect_err(|e| tracing::warn!(%doc_id, "rejected connection: {}", e))
+                .map_err(IntoResponse::into_response)?;
+            Joiner {
+                peer_id: rand::random(),
+                grant: Grant::Acl(Some(user.id)),
+                name: Some(user.name),
+                session: params.session,
+            }
+        }
+        (None, None) => Joiner {
+            peer_id: rand::random(),
+            grant: Grant::Acl(None),
+            name: params.name,
+            session: params.session,
+        },
+    };
+
+    api::can_open(state, doc_id, &joiner.grant)
+        .await
+        .map_err(IntoResponse::into_response)?;
+
+    // A share link is only used up by connections that get in as a new
+    // peer; reconnecting to a held session was paid for already.
+    if let Grant::Share { token, .. } = &joiner.grant {
+        let resumes = match &joiner.session {
+            Some(session) => socket::holds_session(state, doc_id, session, &joiner.grant).await,
+            None => false,
+        };
+        if !resumes {
+            api::redeem_share(state, doc_id, token)
+                .await
+                .map_err(IntoResponse::into_response)?;
+        }
+    }
+
+    Ok((ws.max_message_size(state.config.max_message_bytes), joiner))
+}
 
 async fn ws_handler(
     State(state): State<state::AppState>,
     Path(doc_id): Path<String>,
+    Query(params): Query<JoinParams>,
+    headers: HeaderMap,
+    ws: WebSocketUpgrade,
+) -> Response {
+    match upgrade(&state, &doc_id, &headers, params, ws).await {
+        Ok((ws, joiner)) => {
+            ws.on_upgrade(move |socket| socket::handle_connect(state, doc_id, joiner, socket))
+        }
+        Err(response) => response,
+    }
+}
+
+async fn yws_handler(
+    State(state): State<state::AppState>,
+    Path(doc_id): Path<String>,
+    Query(params): Query<JoinParams>,
+    headers: HeaderMap,
     ws: WebSocketUpgrade,
-) -> impl IntoResponse {
-    ws.on_upgrade(move |socket| socket::handle_connect(state, doc_id, socket))
+) -> Response {
+    match upgrade(&state, &doc_id, &headers, params, ws).await {
+        Ok((ws, joiner)) => {
+            ws.on_upgrade(move |socket| yws::handle_connect(state, doc_id, joiner, socket))
+        }
+        Err(response) => response,
+    }
+}
+
+/// Every route the server answers.
+fn app(state: state::AppState) -> Router {
+    Router::new()
+        .route("/ws/{doc_id}", get(ws_handler))
+        .route("/yws/{doc_id}", get(yws_handler))
+        .route("/stats", get(stats_handler))
+        .route("/metrics", get(metrics::metrics_handler))
+        .merge(api::router())
+        .with_state(state)
+}
+
+fn init_logging(format: LogFormat) {
+    let fmt = tracing_subscriber::fmt().with_target(false);
+    match format {
+        LogFormat::Compact => fmt.compact().init(),
+        LogFormat::Pretty => fmt.pretty().init(),
+        LogFormat::Json => fmt.json().init(),
+    }
 }
 
 #[tokio::main]
-async fn main() {
-    tracing_subscriber::fmt()
-        .with_target(false)
-        .compact()
-        .init();
+async fn main() -> ExitCode {
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("error: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    init_logging(config.log_format);
 
-    let mut map = DashMap::new();
-    let handle = room::spawn_room("lobby".to_string());
+    tracing::info!(backend = ?config.store, path = %config.store_path, "opening document store");
+    let store = match config.store.open(&config.store_path) {
+        Ok(store) => store,
+        Err(e) => {
+            tracing::error!("failed to open document store: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    if config.auth_secret.is_none() {
+        tracing::warn!("no auth secret configured, connections are not authenticated");
+    }
+
+    let listener = match tokio::net::TcpListener::bind(config.bind).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            tracing::error!("failed to listen on {}: {}", config.bind, e);
+            return ExitCode::FAILURE;
+        }
+    };
 
-    map.insert("lobby".to_string(), handle);
     let state = state::AppState {
-        rooms: Arc::new(map),
+        rooms: Arc::new(DashMap::new()),
+        store,
+        auth: config
+            .auth_secret
+            .as_deref()
+            .map(|secret| Arc::new(auth::Auth::new(secret))),
+        config: Arc::new(config),
+        shutting_down: Arc::new(AtomicBool::new(false)),
     };
 
-    let app = Router::new()
-        .route("/ws/{doc_id}", get(ws_handler))
-        .with_state(state);
+    for doc_id in &state.config.preload {
+        let handle = room::spawn_room(doc_id.clone(), state.clone());
+        state.rooms.insert(doc_id.clone(), handle);
+    }
 
-    tracing::info!("server listening on 3001");
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:3001").await.unwrap();
-    axum::serve(listener, app.into_make_service())
+    let app = app(state.clone());
+
+    tracing::info!("server listening on {}", state.config.bind);
+    let shutdown = {
+        let state = state.clone();
+        async move {
+            shutdown_signal().await;
+            tracing::info!("shutdown requested, refusing new connections");
+            state.shutting_down.store(true, Ordering::SeqCst);
+        }
+    };
+    if let Err(e) = axum::serve(listener, app.into_make_service())
+        .with_graceful_shutdown(shutdown)
         .await
-        .unwrap();
+    {
+        tracing::error!("server error: {}", e);
+        return ExitCode::FAILURE;
+    }
+
+    if !state.shutdown(state.config.shutdown_timeout).await {
+        tracing::warn!("gave up waiting for rooms to shut down");
+        return ExitCode::FAILURE;
+    }
+
+    tracing::info!("all rooms saved, exiting");
+    ExitCode::SUCCESS
+}
+
+/// Resolves on Ctrl-C, or on SIGTERM where there is one.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            tracing::error!("failed to listen for Ctrl-C: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                sigterm.recv().await;
+            }
+            Err(e) => {
+                tracing::error!("failed to listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, time::Duration};
+
+    use futures::{SinkExt, StreamExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_tungstenite::{connect_async, tungstenite::Message, MaybeTlsStream, WebSocketStream};
+    use yrs::{
+        sync::{
+            awareness::{AwarenessUpdate, AwarenessUpdateEntry},
+            Message as YMessage, SyncMessage,
+        },
+        updates::{decoder::Decode, encoder::Encode},
+        Doc, ReadTxn, StateVector, Transact, Update,
+    };
+
+    use super::*;
+
+    use shared::{
+        api::{Role, ShareLink},
+        screenplay::{ydoc, Element, Screenplay, ScreenplayElementKind as Kind},
+        server::{ServerError, ServerReply, ServerRequest},
+    };
+
+    type Client = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+    /// Serves the app over `state` on a free local port.
+    async fn serve(state: state::AppState) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app(state).into_make_service()).await });
+        addr
+    }
+
+    /// Connects to `path` and reads the join replies, returning the
+    /// client and its peer id.
+    async fn join(addr: SocketAddr, path: &str) -> (Client, u64) {
+        let (mut client, _) = connect_async(format!("ws://{addr}{path}")).await.unwrap();
+        let ServerReply::Join { id, .. } = recv(&mut client).await else {
+            panic!("first reply was not a join");
+        };
+        assert!(matches!(recv(&mut client).await, ServerReply::SyncStep1(_)));
+
+        (client, id)
+    }
+
+    /// The status a connection to `path` is turned away with.
+    async fn refused(addr: SocketAddr, path: &str) -> StatusCode {
+        match connect_async(format!("ws://{addr}{path}")).await {
+            Err(tokio_tungstenite::tungstenite::Error::Http(response)) => response.status(),
+            Err(e) => panic!("connection failed: {e}"),
+            Ok(_) => panic!("connection to {path} was accepted"),
+        }
+    }
+
+    /// The next reply from the server, skipping its pings.
+    async fn recv(client: &mut Client) -> ServerReply {
+        loop {
+            let msg = tokio::time::timeout(Duration::from_secs(5), client.next())
+                .await
+                .expect("no reply from the server")
+                .expect("connection closed")
+                .unwrap();
+            match msg {
+                Message::Binary(bytes) => return serde_json::from_slice(&bytes).unwrap(),
+                Message::Ping(_) | Message::Pong(_) => continue,
+                other => panic!("unexpected frame: {other:?}"),
+            }
+        }
+    }
+
+    /// The next y-websocket message from the server, skipping its pings.
+    async fn recv_y(client: &mut Client) -> YMessage {
+        loop {
+            let msg = tokio::time::timeout(Duration::from_secs(5), client.next())
+                .await
+                .expect("no message from the server")
+                .expect("connection closed")
+                .unwrap();
+            match msg {
+                Message::Binary(bytes) => return YMessage::decode_v1(&bytes).unwrap(),
+                Message::Ping(_) | Message::Pong(_) => continue,
+                other => panic!("unexpected frame: {other:?}"),
+            }
+        }
+    }
+
+    async fn send_y(client: &mut Client, msg: YMessage) {
+        client
+            .send(Message::Binary(msg.encode_v1().into()))
+            .await
+            .unwrap();
+    }
+
+    async fn send(client: &mut Client, request: &ServerRequest) {
+        let body = serde_json::to_vec(request).unwrap();
+        client.send(Message::Binary(body.into())).await.unwrap();
+    }
+
+    /// An update creating a screenplay with a single action.
+    fn action(text: &str) -> Vec<u8> {
+        let doc = Doc::new();
+        let screenplay = Screenplay {
+            elements: vec![Element::new(Kind::Action, text)],
+            ..Screenplay::default()
+        };
+        ydoc::write(&mut doc.transact_mut(), &screenplay);
+        let update = doc
+            .transact()
+            .encode_state_as_update_v1(&StateVector::default());
+        update
+    }
+
+    fn awareness(client_id: u64, name: &str) -> AwarenessUpdate {
+        let entry = AwarenessUpdateEntry {
+            clock: 1,
+            json: format!(r#"{{"name":"{name}"}}"#).into(),
+        };
+        AwarenessUpdate {
+            clients: [(client_id, entry)].into(),
+        }
+    }
+
+    fn read(update: &[u8]) -> Screenplay {
+        let doc = Doc::new();
+        doc.transact_mut()
+            .apply_update(Update::decode_v1(update).unwrap())
+            .unwrap();
+        let screenplay = ydoc::read(&doc.transact()).unwrap();
+        screenplay
+    }
+
+    #[tokio::test]
+    async fn routes_client_requests_to_the_room() {
+        let addr = serve(state::AppState::for_tests(Config::for_tests(&[]))).await;
+        let (mut a, a_id) = join(addr, "/ws/doc?name=A").await;
+        let (mut b, b_id) = join(addr, "/ws/doc?name=B").await;
+        assert!(matches!(recv(&mut a).await, ServerReply::PeerJoined(p) if p.id == b_id));
+
+        let update = action("She waits.");
+        send(&mut a, &ServerRequest::Update(update.clone())).await;
+        assert!(matches!(recv(&mut b).await, ServerReply::Update(bytes) if bytes == update));
+
+        send(
+            &mut b,
+            &ServerRequest::Awareness(awareness(7, "B").encode_v1()),
+        )
+        .await;
+        assert!(matches!(recv(&mut a).await, ServerReply::Awareness(_)));
+
+        send(&mut a, &ServerRequest::Snapshot).await;
+        let ServerReply::Snapshot(snapshot) = recv(&mut a).await else {
+            panic!("snapshot request was not answered with a snapshot");
+        };
+        assert_eq!(read(&snapshot).elements[0].text, "She waits.");
+
+        // A malformed frame is refused without closing the connection.
+        a.send(Message::Text(r#"{"type":"Shout"}"#.into()))
+            .await
+            .unwrap();
+        assert!(matches!(
+            recv(&mut a).await,
+            ServerReply::Error(ServerError::MalformedRequest(_))
+        ));
+        send(&mut a, &ServerRequest::Ping).await;
+        assert!(matches!(recv(&mut a).await, ServerReply::PingPong));
+
+        send(&mut a, &ServerRequest::Leave).await;
+        assert!(matches!(recv(&mut b).await, ServerReply::PeerLeft { id } if id == a_id));
+    }
+
+    #[tokio::test]
+    async fn speaks_y_websocket_to_the_same_room() {
+        let addr = serve(state::AppState::for_tests(Config::for_tests(&[]))).await;
+        let (mut y, _) = connect_async(format!("ws://{addr}/yws/doc")).await.unwrap();
+        assert!(matches!(
+            recv_y(&mut y).await,
+            YMessage::Sync(SyncMessage::SyncStep1(_))
+        ));
+        let (mut json, _) = join(addr, "/ws/doc").await;
+
+        let update = action("She waits.");
+        send_y(&mut y, YMessage::Sync(SyncMessage::Update(update.clone()))).await;
+        assert!(matches!(recv(&mut json).await, ServerReply::Update(bytes) if bytes == update));
+
+        let update = action("He stays.");
+        send(&mut json, &ServerRequest::Update(update.clone())).await;
+        assert!(matches!(
+            recv_y(&mut y).await,
+            YMessage::Sync(SyncMessage::Update(bytes)) if bytes == update
+        ));
+
+        // The y-websocket handshake is answered with what the client lacks.
+        send_y(
+            &mut y,
+            YMessage::Sync(SyncMessage::SyncStep1(StateVector::default())),
+        )
+        .await;
+        let YMessage::Sync(SyncMessage::SyncStep2(diff)) = recv_y(&mut y).await else {
+            panic!("SyncStep1 was not answered with SyncStep2");
+        };
+        assert_eq!(read(&diff).elements.len(), 2);
+
+        send(
+            &mut json,
+            &ServerRequest::Awareness(awareness(7, "A").encode_v1()),
+        )
+        .await;
+        assert!(matches!(recv_y(&mut y).await, YMessage::Awareness(_)));
+        send_y(&mut y, YMessage::AwarenessQuery).await;
+        let YMessage::Awareness(current) = recv_y(&mut y).await else {
+            panic!("awareness query was not answered");
+        };
+        assert!(current.clients.contains_key(&7));
+
+        send_y(&mut y, YMessage::Awareness(awareness(8, "Y"))).await;
+        assert!(matches!(recv(&mut json).await, ServerReply::Awareness(_)));
+    }
+
+    #[tokio::test]
+    async fn refuses_connections_while_shutting_down() {
+        let state = state::AppState::for_tests(Config::for_tests(&[]));
+        state.shutting_down.store(true, Ordering::SeqCst);
+        let addr = serve(state).await;
+
+        assert_eq!(
+            refused(addr, "/ws/doc").await,
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+    }
+
+    #[tokio::test]
+    async fn refuses_upgrades_without_a_valid_token() {
+        let config = Config::for_tests(&["--auth-secret", auth::TEST_SECRET]);
+        let addr = serve(state::AppState::for_tests(config)).await;
+
+        let expired = auth::test_token(auth::TEST_SECRET, "u-1", "Ada", -600);
+        let forged = auth::test_token("another-secret-of-at-least-32-bytes", "u-1", "Ada", 600);
+        for route in ["ws", "yws"] {
+            for query in [
+                String::new(),
+                format!("?token={expired}"),
+                format!("?token={forged}"),
+            ] {
+                let path = format!("/{route}/doc{query}");
+                assert_eq!(
+                    refused(addr, &path).await,
+                    StatusCode::UNAUTHORIZED,
+                    "{path}"
+                );
+            }
+        }
+
+        // A good token gets in as its user, whatever name is asked for.
+        let token = auth::test_token(auth::TEST_SECRET, "u-1", "Ada", 600);
+        let (mut first, _) = join(addr, &format!("/ws/doc?token={token}")).await;
+        let (_, id) = join(addr, &format!("/ws/doc?token={token}&name=Mallory")).await;
+        let ServerReply::PeerJoined(info) = recv(&mut first).await else {
+            panic!("second tab was not announced");
+        };
+        assert_eq!(info.id, id);
+        assert_eq!(
+            (info.user.as_deref(), info.name.as_str()),
+            (Some("u-1"), "Ada")
+        );
+    }
+
+    /// A share link to `doc_id` granting viewer access, expiring
+    /// `expires_in` seconds from now.
+    fn share(token: &str, doc_id: &str, expires_in: i64, max_uses: Option<u32>) -> ShareLink {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        ShareLink {
+            token: token.to_string(),
+            doc_id: doc_id.to_string(),
+            role: Role::Viewer,
+            created_at: now,
+            expires_at: now.saturating_add_signed(expires_in),
+            max_uses,
+            uses: 0,
+        }
+    }
+
+    #[tokio::test]
+    async fn admits_share_links_until_they_expire_or_run_out() {
+        let config = Config::for_tests(&["--auth-secret", auth::TEST_SECRET]);
+        let state = state::AppState::for_tests(config);
+        for link in [
+            share("once", "doc", 600, Some(1)),
+            share("expired", "doc", -1, None),
+            share("elsewhere", "other", 600, None),
+        ] {
+            state.store.save_share(&link).unwrap();
+        }
+        let addr = serve(state).await;
+
+        // A link stands in for a token.
+        let (mut client, _) = connect_async(format!("ws://{addr}/ws/doc?share=once"))
+            .await
+            .unwrap();
+        let ServerReply::Join {
+            id, session, role, ..
+        } = recv(&mut client).await
+        else {
+            panic!("first reply was not a join");
+        };
+        assert_eq!(role, Role::Viewer);
+
+        for token in ["once", "expired", "elsewhere", "unknown"] {
+            let path = format!("/ws/doc?share={token}");
+            assert_eq!(refused(addr, &path).await, StatusCode::FORBIDDEN, "{token}");
+        }
+
+        // The link's holder can still come back as the same peer.
+        drop(client);
+        let path = format!("/ws/doc?share=once&session={session}");
+        let (_, resumed) = join(addr, &path).await;
+        assert_eq!(resumed, id);
+    }
 }
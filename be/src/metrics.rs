@@ -0,0 +1,232 @@
+//! `/metrics` in the Prometheus text exposition format.
+use std::fmt::Write;
+
+use axum::{extract::State, http::header, response::IntoResponse};
+
+use crate::{
+    state::AppState,
+    stats::{self, Stats, BUCKETS},
+};
+
+pub(crate) async fn metrics_handler(State(state): State<AppState>) -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        render(&state),
+    )
+}
+
+fn render(state: &AppState) -> String {
+    let mut out = String::new();
+    let stats = Stats::read();
+
+    let counters = [
+        (
+            "prosia_updates_applied_total",
+            "Client updates applied to a document.",
+            stats.updates_applied,
+        ),
+        (
+            "prosia_updates_rejected_total",
+            "Client updates that were refused or could not be applied.",
+            stats.updates_rejected,
+        ),
+        (
+            "prosia_bytes_in_total",
+            "Payload bytes received from clients.",
+            stats.bytes_in,
+        ),
+        (
+            "prosia_bytes_out_total",
+            "Payload bytes sent to clients.",
+            stats.bytes_out,
+        ),
+        (
+            "prosia_dropped_sends_total",
+            "Replies dropped because a peer's queue was full.",
+            stats.dropped_sends,
+        ),
+        (
+            "prosia_resyncs_total",
+            "Lagging peers brought back in sync.",
+            stats.resyncs,
+        ),
+        (
+            "prosia_slow_disconnects_total",
+            "Peers disconnected for lagging too long.",
+            stats.slow_disconnects,
+        ),
+        (
+            "prosia_dead_disconnects_total",
+            "Peers disconnected for missing heartbeats.",
+            stats.dead_disconnects,
+        ),
+        (
+            "prosia_abuse_disconnects_total",
+            "Clients disconnected for having too many messages refused.",
+            stats.abuse_disconnects,
+        ),
+    ];
+    for (name, help, value) in counters {
+        header(&mut out, name, help, "counter");
+        let _ = writeln!(out, "{name} {value}");
+    }
+
+    header(&mut out, "prosia_rooms", "Rooms loaded in memory.", "gauge");
+    let _ = writeln!(out, "prosia_rooms {}", state.rooms.len());
+
+    let rooms: Vec<_> = state
+        .rooms
+        .iter()
+        .map(|r| (escape(r.key()), r.peer_count(), r.queue_depth()))
+        .collect();
+
+    header(
+        &mut out,
+        "prosia_peers",
+        "Peers connected to any room.",
+        "gauge",
+    );
+    let peers: usize = rooms.iter().map(|(_, peers, _)| peers).sum();
+    let _ = writeln!(out, "prosia_peers {peers}");
+
+    header(
+        &mut out,
+        "prosia_room_queue_depth_max",
+        "Commands waiting in the fullest room queue.",
+        "gauge",
+    );
+    let depth = rooms.iter().map(|(_, _, depth)| *depth).max().unwrap_or(0);
+    let _ = writeln!(out, "prosia_room_queue_depth_max {depth}");
+
+    // Doc ids are only exposed when the operator asked for them.
+    if state.config.metrics_per_room {
+        room_gauges(&mut out, &rooms);
+    }
+
+    let name = "prosia_snapshot_encode_seconds";
+    header(
+        &mut out,
+        name,
+        "Time spent encoding document snapshots.",
+        "histogram",
+    );
+    let (buckets, count, sum) = stats::SNAPSHOT_ENCODE.read();
+    for (bound, cumulative) in BUCKETS.iter().zip(buckets) {
+        let _ = writeln!(out, "{name}_bucket{{le=\"{bound}\"}} {cumulative}");
+    }
+    let _ = writeln!(out, "{name}_bucket{{le=\"+Inf\"}} {count}");
+    let _ = writeln!(out, "{name}_sum {sum}");
+    let _ = writeln!(out, "{name}_count {count}");
+
+    out
+}
+
+/// Per-room gauges, labelled with the room's doc id.
+fn room_gauges(out: &mut String, rooms: &[(String, usize, usize)]) {
+    header(
+        out,
+        "prosia_room_peers",
+        "Peers connected to a room.",
+        "gauge",
+    );
+    for (doc_id, peers, _) in rooms {
+        let _ = writeln!(out, "prosia_room_peers{{doc_id=\"{doc_id}\"}} {peers}");
+    }
+
+    header(
+        out,
+        "prosia_room_queue_depth",
+        "Commands waiting in a room's queue.",
+        "gauge",
+    );
+    for (doc_id, _, depth) in rooms {
+        let _ = writeln!(
+            out,
+            "prosia_room_queue_depth{{doc_id=\"{doc_id}\"}} {depth}"
+        );
+    }
+}
+
+fn header(out: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+/// Escapes a label value.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::{
+        config::Config,
+        socket::request_snapshot,
+        state::{Grant, RoomCmd},
+    };
+
+    use shared::server::ServerReply;
+
+    /// A server with one peer in the room `doc_id`.
+    async fn one_peer(flags: &[&str], doc_id: &str) -> (AppState, mpsc::Receiver<ServerReply>) {
+        let state = AppState::for_tests(Config::for_tests(flags));
+        let room = state.room(doc_id);
+        let (tx, rx) = mpsc::channel(16);
+        let join = RoomCmd::Join {
+            peer_id: 1,
+            grant: Grant::Acl(None),
+            name: None,
+            session: None,
+            tx,
+        };
+        room.cmd_tx.send(join).await.unwrap();
+        // The room has published its peer count once it answers.
+        request_snapshot(&room, None).await.unwrap();
+
+        (state, rx)
+    }
+
+    #[tokio::test]
+    async fn reports_rooms_peers_and_snapshot_latency() {
+        let (state, _peer) = one_peer(&[], "doc").await;
+        let out = render(&state);
+
+        for line in [
+            "# TYPE prosia_updates_applied_total counter",
+            "# TYPE prosia_dropped_sends_total counter",
+            "prosia_rooms 1",
+            "prosia_peers 1",
+            "# TYPE prosia_snapshot_encode_seconds histogram",
+        ] {
+            assert!(out.lines().any(|l| l == line), "missing {line:?} in\n{out}");
+        }
+        let count = out
+            .lines()
+            .find_map(|l| l.strip_prefix("prosia_snapshot_encode_seconds_count "))
+            .unwrap();
+        assert!(count.parse::<u64>().unwrap() >= 1);
+        assert!(!out.contains("doc_id"), "{out}");
+    }
+
+    #[tokio::test]
+    async fn labels_rooms_only_when_asked_to() {
+        let (state, _peer) = one_peer(&["--metrics-per-room"], "a\"b").await;
+        let out = render(&state);
+
+        assert!(
+            out.lines()
+                .any(|l| l == r#"prosia_room_peers{doc_id="a\"b"} 1"#),
+            "{out}"
+        );
+        assert!(
+            out.contains(r#"prosia_room_queue_depth{doc_id="a\"b"}"#),
+            "{out}"
+        );
+    }
+}
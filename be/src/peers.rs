@@ -1,39 +1,355 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, time::Duration};
 
-use tokio::sync::mpsc;
+use tokio::{
+    sync::mpsc::{self, error::TrySendError},
+    time::Instant,
+};
+use yrs::StateVector;
 
-use shared::server::ServerReply;
+use crate::{state::Grant, stats};
+
+use shared::{
+    api::{Acl, ClientStats, Role},
+    server::{PeerInfo, ServerReply},
+};
+
+/// Colours handed out to peers, picked by peer id.
+const PEER_COLORS: [&str; 8] = [
+    "#e6194b", "#3cb44b", "#4363d8", "#f58231", "#911eb4", "#42d4f4", "#f032e6", "#9a6324",
+];
+
+pub struct Peer {
+    pub tx: mpsc::Sender<ServerReply>,
+    pub info: PeerInfo,
+    /// The state the peer reported in its last SyncStep1. It has at least
+    /// this much of the document, so resyncs are diffed against it.
+    pub state_vector: StateVector,
+    /// Set once a reply to the peer had to be dropped.
+    pub lag: Option<Lag>,
+    /// When the peer's current connection joined the room.
+    pub joined: Instant,
+    /// When the room last heard from the peer.
+    pub last_seen: Instant,
+    /// Token the peer's next connection can present to resume as this
+    /// peer. Replaced on every join.
+    pub session: String,
+    /// How the peer got in, which decides its role.
+    pub grant: Grant,
+    /// What the peer may do, per the document's ACL.
+    pub role: Role,
+}
+
+/// A peer whose connection dropped. It keeps its identity and awareness
+/// states until it resumes its session or the grace window runs out.
+pub struct Away {
+    pub info: PeerInfo,
+    pub session: String,
+    pub grant: Grant,
+    pub since: Instant,
+}
+
+/// Bookkeeping for a peer whose queue overflowed. Nothing more is sent to
+/// it until its queue drains and it is resynced.
+pub struct Lag {
+    pub since: Instant,
+    pub dropped: u64,
+}
+
+impl Peer {
+    /// Queues `reply` unless the peer is lagging, marking it as lagging if
+    /// its queue is full.
+    fn try_send(&mut self, reply: ServerReply) {
+        if let Some(lag) = &mut self.lag {
+            lag.dropped += 1;
+            stats::incr(&stats::DROPPED_SENDS);
+            return;
+        }
+
+        if let Err(TrySendError::Full(_)) = self.tx.try_send(reply) {
+            tracing::warn!(peer_id = %self.info.id, "peer queue full, marking out of sync");
+            stats::incr(&stats::DROPPED_SENDS);
+            self.lag = Some(Lag {
+                since: Instant::now(),
+                dropped: 1,
+            });
+        }
+    }
+}
 
 pub struct Peers {
-    pub peers: HashMap<u64, mpsc::Sender<ServerReply>>,
+    pub peers: HashMap<u64, Peer>,
+    pub away: HashMap<u64, Away>,
 }
 
 impl Peers {
     pub fn new() -> Self {
         Self {
             peers: HashMap::new(),
+            away: HashMap::new(),
         }
     }
 
-    pub fn notify(&self, from: u64, reply: ServerReply) {
-        for (peer_id, tx) in &self.peers {
+    pub fn notify(&mut self, from: u64, reply: ServerReply) {
+        for (peer_id, peer) in &mut self.peers {
             if *peer_id == from {
                 continue;
             }
 
-            let _ = tx.try_send(reply.clone());
+            peer.try_send(reply.clone());
+        }
+    }
+
+    pub fn broadcast(&mut self, reply: ServerReply) {
+        for peer in self.peers.values_mut() {
+            peer.try_send(reply.clone());
+        }
+    }
+
+    pub fn send(&mut self, peer_id: u64, reply: ServerReply) {
+        if let Some(peer) = self.peers.get_mut(&peer_id) {
+            peer.try_send(reply);
+        }
+    }
+
+    /// Everyone the other peers should see in the room, including peers
+    /// that are away but may still resume.
+    pub fn infos(&self) -> Vec<PeerInfo> {
+        self.peers
+            .values()
+            .map(|p| p.info.clone())
+            .chain(self.away.values().map(|a| a.info.clone()))
+            .collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.peers.is_empty()
+    }
+
+    pub fn add(
+        &mut self,
+        info: PeerInfo,
+        session: String,
+        grant: Grant,
+        role: Role,
+        tx: mpsc::Sender<ServerReply>,
+    ) {
+        let peer = Peer {
+            tx,
+            info,
+            state_vector: StateVector::default(),
+            lag: None,
+            joined: Instant::now(),
+            last_seen: Instant::now(),
+            session,
+            grant,
+            role,
+        };
+        self.peers.insert(peer.info.id, peer);
+    }
+
+    /// Removes `peer_id` whether it is connected or away. Returns whether
+    /// it was in the room.
+    pub fn remove(&mut self, peer_id: &u64) -> bool {
+        let connected = self.peers.remove(peer_id).is_some();
+        let away = self.away.remove(peer_id).is_some();
+        connected || away
+    }
+
+    pub fn role(&self, peer_id: u64) -> Option<Role> {
+        self.peers.get(&peer_id).map(|p| p.role)
+    }
+
+    /// Gives every connected peer its role under `acl`, telling those
+    /// whose role changed. Returns the peers `acl` no longer lets in.
+    pub fn apply_acl(&mut self, acl: &Acl) -> Vec<u64> {
+        let mut revoked = Vec::new();
+        for (peer_id, peer) in &mut self.peers {
+            match peer.grant.role(acl) {
+                Some(role) if role != peer.role => {
+                    peer.role = role;
+                    peer.try_send(ServerReply::RoleChanged(role));
+                }
+                Some(_) => {}
+                None => revoked.push(*peer_id),
+            }
         }
+
+        revoked
     }
 
-    pub fn ids(&self) -> Vec<u64> {
-        self.peers.keys().cloned().collect()
+    /// Connected peers that joined with the share link `token`.
+    pub fn using_share(&self, token: &str) -> Vec<u64> {
+        self.peers
+            .values()
+            .filter(|p| matches!(&p.grant, Grant::Share { token: t, .. } if t == token))
+            .map(|p| p.info.id)
+            .collect()
     }
 
-    pub fn add(&mut self, peer_id: u64, tx: mpsc::Sender<ServerReply>) {
-        self.peers.insert(peer_id, tx);
+    /// Whether `session` is the current session of connected peer `peer_id`.
+    pub fn is_current(&self, peer_id: u64, session: &str) -> bool {
+        self.peers
+            .get(&peer_id)
+            .is_some_and(|p| p.session == session)
     }
 
-    pub fn remove(&mut self, peer_id: &u64) {
-        self.peers.remove(peer_id);
+    /// Moves a connected peer to the away list, dropping its channel.
+    pub fn park(&mut self, peer_id: u64) {
+        if let Some(peer) = self.peers.remove(&peer_id) {
+            self.away.insert(
+                peer_id,
+                Away {
+                    info: peer.info,
+                    session: peer.session,
+                    grant: peer.grant,
+                    since: Instant::now(),
+                },
+            );
+        }
+    }
+
+    /// Whether [`Peers::resume`] would find a peer for `session` and
+    /// `grant`.
+    pub fn holds(&self, session: &str, grant: &Grant) -> bool {
+        let away = self.away.values().map(|a| (&a.session, &a.grant));
+        let connected = self.peers.values().map(|p| (&p.session, &p.grant));
+        away.chain(connected)
+            .any(|(s, g)| s == session && g.same_holder(grant))
+    }
+
+    /// Takes the peer holding `session` out of the room so a new
+    /// connection can join as it. A peer that is still connected is
+    /// taken over, which closes its old connection. Only a connection
+    /// with the same holder as the peer (see [`Grant::same_holder`]) can
+    /// resume it.
+    pub fn resume(&mut self, session: &str, grant: &Grant) -> Option<PeerInfo> {
+        if let Some(peer_id) = self
+            .away
+            .iter()
+            .find(|(_, a)| a.session == session && a.grant.same_holder(grant))
+            .map(|(id, _)| *id)
+        {
+            return self.away.remove(&peer_id).map(|a| a.info);
+        }
+
+        let peer_id = self
+            .peers
+            .iter()
+            .find(|(_, p)| p.session == session && p.grant.same_holder(grant))
+            .map(|(id, _)| *id)?;
+        self.peers.remove(&peer_id).map(|p| p.info)
+    }
+
+    /// Connection details of every peer, for the stats API.
+    pub fn stats(&self) -> Vec<ClientStats> {
+        let connected = self.peers.values().map(|p| ClientStats {
+            id: p.info.id,
+            name: p.info.name.clone(),
+            connected_secs: p.joined.elapsed().as_secs(),
+            idle_secs: p.last_seen.elapsed().as_secs(),
+            away: false,
+            lagging: p.lag.is_some(),
+        });
+        let away = self.away.values().map(|a| ClientStats {
+            id: a.info.id,
+            name: a.info.name.clone(),
+            connected_secs: 0,
+            idle_secs: a.since.elapsed().as_secs(),
+            away: true,
+            lagging: false,
+        });
+
+        connected.chain(away).collect()
+    }
+
+    /// Away peers whose grace window of `grace` has run out.
+    pub fn expired(&self, grace: Duration) -> Vec<u64> {
+        self.away
+            .values()
+            .filter(|a| a.since.elapsed() > grace)
+            .map(|a| a.info.id)
+            .collect()
+    }
+
+    pub fn set_state_vector(&mut self, peer_id: u64, state_vector: StateVector) {
+        if let Some(peer) = self.peers.get_mut(&peer_id) {
+            peer.state_vector = state_vector;
+        }
+    }
+
+    /// Records that `peer_id` is still alive.
+    pub fn touch(&mut self, peer_id: u64) {
+        if let Some(peer) = self.peers.get_mut(&peer_id) {
+            peer.last_seen = Instant::now();
+        }
+    }
+
+    /// Peers the room has not heard from for longer than `timeout`.
+    pub fn silent(&self, timeout: Duration) -> Vec<u64> {
+        self.peers
+            .values()
+            .filter(|p| p.last_seen.elapsed() > timeout)
+            .map(|p| p.info.id)
+            .collect()
+    }
+
+    /// Lagging peers whose queue has fully drained and can be resynced.
+    pub fn drained(&self) -> Vec<u64> {
+        self.peers
+            .values()
+            .filter(|p| p.lag.is_some() && p.tx.capacity() == p.tx.max_capacity())
+            .map(|p| p.info.id)
+            .collect()
+    }
+
+    /// Peers that have been lagging for longer than `limit`.
+    pub fn stalled(&self, limit: Duration) -> Vec<u64> {
+        self.peers
+            .values()
+            .filter(|p| {
+                p.lag
+                    .as_ref()
+                    .is_some_and(|lag| lag.since.elapsed() > limit)
+            })
+            .map(|p| p.info.id)
+            .collect()
+    }
+
+    /// Clears the lag of `peer_id` and queues `replies` to bring it back
+    /// in sync.
+    pub fn resync(&mut self, peer_id: u64, replies: Vec<ServerReply>) {
+        let Some(peer) = self.peers.get_mut(&peer_id) else {
+            return;
+        };
+
+        if let Some(lag) = peer.lag.take() {
+            tracing::info!(%peer_id, dropped = lag.dropped, "resyncing lagging peer");
+            stats::incr(&stats::RESYNCS);
+        }
+
+        for reply in replies {
+            peer.try_send(reply);
+        }
+    }
+}
+
+/// Generates a fresh session token.
+pub fn new_session() -> String {
+    format!("{:032x}", rand::random::<u128>())
+}
+
+/// Builds the presence info for a peer joining with `grant`, falling back
+/// to a generated name when the client did not supply one.
+pub fn peer_info(peer_id: u64, grant: &Grant, name: Option<String>) -> PeerInfo {
+    let name = name
+        .map(|n| n.trim().chars().take(64).collect::<String>())
+        .filter(|n| !n.is_empty())
+        .unwrap_or_else(|| format!("Writer {}", peer_id % 10_000));
+
+    PeerInfo {
+        id: peer_id,
+        user: grant.user().map(str::to_string),
+        name,
+        color: PEER_COLORS[(peer_id % PEER_COLORS.len() as u64) as usize].to_string(),
     }
 }
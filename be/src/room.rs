@@ -1,76 +1,1141 @@
-use std::collections::HashMap;
+use std::time::Duration;
 
-use tokio::sync::mpsc;
+use tokio::{sync::mpsc, time::Instant};
 use yrs::{
-    doc,
-    types::text,
-    updates::{decoder::Decode, encoder::Encode},
-    Any, Array, ArrayPrelim, Doc, GetString, ReadTxn, Text, Transact,
+    updates::decoder::Decode, updates::encoder::Encode, Doc, ReadTxn, StateVector, Transact,
 };
 
 use crate::{
-    peers::Peers,
-    state::{RoomCmd, RoomHandle},
+    awareness::{RoomAwareness, AWARENESS_TIMEOUT},
+    peers::{new_session, peer_info, Peers},
+    schema::{decode_update, Applied, Schema},
+    state::{AppState, RoomCmd, RoomHandle, UpdateVersion, PING_INTERVAL},
+    stats,
+    store::{StoredDoc, UpdateLog},
 };
 
-use shared::server::ServerReply;
+use shared::{
+    api::{Acl, Role},
+    screenplay::ydoc,
+    server::{ServerError, ServerReply},
+};
+
+/// How often lagging peers are checked for a drained queue.
+const RESYNC_INTERVAL: Duration = Duration::from_millis(250);
+/// Peers still lagging after this long are disconnected.
+const MAX_LAG: Duration = Duration::from_secs(10);
+/// Peers silent for this long have missed three pings and are
+/// disconnected.
+const PEER_TIMEOUT: Duration = Duration::from_secs(3 * PING_INTERVAL.as_secs());
+/// How long a dropped peer can take to reconnect and resume its session
+/// before the others are told it left.
+const SESSION_GRACE: Duration = Duration::from_secs(30);
 
-#[tracing::instrument]
-pub fn spawn_room(doc_id: String) -> RoomHandle {
+/// Spawns the actor owning the document `doc_id`.
+///
+/// Once the room has had no peers for `state.config.idle_timeout` it flushes
+/// its document to storage, removes itself from `state.rooms` and exits.
+/// The next connection spawns it again from storage.
+#[tracing::instrument(skip(state))]
+pub fn spawn_room(doc_id: String, state: AppState) -> RoomHandle {
     tracing::info!(%doc_id, "spawning room");
     let (cmd_tx, mut cmd_rx) = mpsc::channel::<RoomCmd>(128);
+    let handle = RoomHandle::new(cmd_tx);
+    let this = handle.clone();
 
     tokio::spawn(async move {
         let doc_id = doc_id.clone();
-        let doc = yrs::Doc::new();
-        let text = doc.get_or_insert_text(doc_id.as_str());
+        // A document that could not be read back must not be written to:
+        // appending would reuse sequence numbers of entries still in the
+        // store and compacting would replace them with a partial state.
+        // Close the room instead, which turns every queued connection away.
+        let loaded = UpdateLog::load(state.store.clone(), &doc_id).await;
+        let (mut log, doc) = match loaded.map(|(log, stored)| (log, restore_doc(stored))) {
+            Ok((log, Ok(doc))) => (log, doc),
+            Ok((_, Err(e))) => {
+                tracing::error!(%doc_id, "failed to restore document, closing room: {}", e);
+                state.remove_room(&doc_id, &this);
+                return;
+            }
+            Err(e) => {
+                tracing::error!(%doc_id, "failed to load document, closing room: {}", e);
+                state.remove_room(&doc_id, &this);
+                return;
+            }
+        };
+        if let Some(update) = migrate(&doc_id, &doc) {
+            log.append(&update).await;
+        }
+        log.maybe_compact(&doc);
+        let mut schema = Schema::new(&doc_id, &doc);
+        let mut acl = load_acl(&state, &doc_id).await;
 
         let mut peers = Peers::new();
+        let mut awareness = RoomAwareness::new(doc.clone());
+        let mut awareness_check = tokio::time::interval(AWARENESS_TIMEOUT / 2);
+        let mut resync_check = tokio::time::interval(RESYNC_INTERVAL);
+        let mut heartbeat_check = tokio::time::interval(PING_INTERVAL);
+        let mut idle_since = Some(Instant::now());
+        let mut shutdown_ack = None;
 
-        let apply_update = |bytes: &[u8]| {
-            let _ = bytes;
+        loop {
+            this.set_peer_count(peers.peers.len());
+            let cmd = tokio::select! {
+                cmd = cmd_rx.recv() => match cmd {
+                    Some(cmd) => cmd,
+                    None => break,
+                },
+                _ = idle(idle_since, state.config.idle_timeout) => {
+                    if state.evict_idle(&doc_id, &this) {
+                        tracing::info!(%doc_id, "room idle, hibernating");
+                        break;
+                    }
 
-            let mut txn = doc.transact_mut();
-            text.insert(&mut txn, 0, "Hello from peer!");
-        };
+                    // Someone is connecting; wait for them to join.
+                    idle_since = Some(Instant::now());
+                    continue;
+                }
+                _ = awareness_check.tick() => {
+                    if let Some(removed) = awareness.remove_outdated() {
+                        tracing::debug!(%doc_id, "removing outdated awareness states");
+                        peers.broadcast(ServerReply::Awareness(removed));
+                    }
+                    continue;
+                }
+                _ = resync_check.tick() => {
+                    for peer_id in peers.stalled(MAX_LAG) {
+                        tracing::warn!(%doc_id, %peer_id, "disconnecting peer that stopped reading");
+                        stats::incr(&stats::SLOW_DISCONNECTS);
+                        remove_peer(&doc_id, &mut peers, &mut awareness, peer_id);
+                    }
 
-        let encode_snapshot = || -> Vec<u8> {
-            let txn = doc.transact();
-            let s = text.get_string(&txn);
-            tracing::info!(%doc_id, "encoding snapshot: {}", s);
+                    for peer_id in peers.drained() {
+                        let replies = resync_replies(&doc, &peers, &awareness, peer_id);
+                        peers.resync(peer_id, replies);
+                    }
 
-            s.into_bytes()
-        };
+                    if peers.is_empty() && idle_since.is_none() {
+                        idle_since = Some(Instant::now());
+                    }
+                    continue;
+                }
+                _ = heartbeat_check.tick() => {
+                    for peer_id in peers.silent(PEER_TIMEOUT) {
+                        tracing::warn!(%doc_id, %peer_id, "disconnecting unresponsive peer");
+                        stats::incr(&stats::DEAD_DISCONNECTS);
+                        remove_peer(&doc_id, &mut peers, &mut awareness, peer_id);
+                    }
+
+                    for peer_id in peers.expired(SESSION_GRACE) {
+                        remove_peer(&doc_id, &mut peers, &mut awareness, peer_id);
+                    }
+
+                    if peers.is_empty() && idle_since.is_none() {
+                        idle_since = Some(Instant::now());
+                    }
+                    continue;
+                }
+            };
+
+            if let Some(peer_id) = cmd.peer_id() {
+                peers.touch(peer_id);
+            }
 
-        while let Some(cmd) = cmd_rx.recv().await {
             match cmd {
-                RoomCmd::Join { peer_id, tx } => {
-                    tracing::info!(%doc_id, %peer_id, "peer joined");
+                RoomCmd::Join {
+                    peer_id,
+                    grant,
+                    name,
+                    session,
+                    tx,
+                } => {
+                    let Some(role) = grant.role(&acl) else {
+                        // Dropping `tx` turns the connection away.
+                        tracing::warn!(%doc_id, ?grant, "refused peer without access");
+                        continue;
+                    };
+
+                    // The others never saw a resumed peer leave, so they
+                    // are not told it joined either.
+                    let existing = session.as_deref().and_then(|s| peers.resume(s, &grant));
+                    let info = match existing {
+                        Some(info) => {
+                            tracing::info!(%doc_id, peer_id = %info.id, "peer resumed session");
+                            info
+                        }
+                        None => {
+                            let info = peer_info(peer_id, &grant, name);
+                            tracing::info!(%doc_id, %peer_id, name = %info.name, "peer joined");
+                            peers.notify(peer_id, ServerReply::PeerJoined(info.clone()));
+                            info
+                        }
+                    };
+
+                    let session = new_session();
                     let _ = tx.try_send(ServerReply::Join {
-                        id: peer_id,
-                        peers: peers.ids(),
+                        id: info.id,
+                        session: session.clone(),
+                        peers: peers.infos(),
+                        role,
                     });
 
-                    peers.add(peer_id, tx);
+                    // Open the handshake: the peer answers with SyncStep2
+                    // carrying whatever the server is missing.
+                    let state_vector = doc.transact().state_vector().encode_v1();
+                    let _ = tx.try_send(ServerReply::SyncStep1(state_vector));
+
+                    if let Some(current) = awareness.encode() {
+                        let _ = tx.try_send(ServerReply::Awareness(current));
+                    }
+
+                    peers.add(info, session, grant, role, tx);
+                    idle_since = None;
                 }
-                RoomCmd::Leave { peer_id } => {
-                    peers.remove(&peer_id);
-                    tracing::info!(%doc_id, %peer_id, "peer left");
+                RoomCmd::Leave {
+                    peer_id,
+                    session,
+                    resumable,
+                } => {
+                    if !peers.is_current(peer_id, &session) {
+                        // Another connection has taken over the session.
+                        continue;
+                    }
+
+                    if resumable {
+                        tracing::info!(%doc_id, %peer_id, "peer disconnected, holding session");
+                        peers.park(peer_id);
+                    } else {
+                        remove_peer(&doc_id, &mut peers, &mut awareness, peer_id);
+                    }
+
+                    if peers.is_empty() {
+                        idle_since = Some(Instant::now());
+                    }
                 }
-                RoomCmd::ClientUpdate { peer_id, bytes } => {
-                    apply_update(&bytes);
-                    peers.notify(peer_id, ServerReply::Update(bytes.clone()));
+                RoomCmd::ClientUpdate {
+                    peer_id,
+                    bytes,
+                    version,
+                } if !peers.role(peer_id).is_some_and(Role::can_edit) => {
+                    // Read-only peers still answer the handshake with a
+                    // SyncStep2, which is empty unless they made changes.
+                    match decode_update(&bytes, version) {
+                        Ok(None) => {
+                            tracing::debug!(%doc_id, %peer_id, "ignoring empty update");
+                        }
+                        Ok(Some(_)) => {
+                            let role = peers.role(peer_id).unwrap_or(Role::Viewer);
+                            stats::incr(&stats::UPDATES_REJECTED);
+                            tracing::debug!(%doc_id, %peer_id, %role, "rejected update from read-only peer");
+                            peers.send(peer_id, ServerReply::Error(ServerError::ReadOnly(role)));
+                        }
+                        Err(e) => {
+                            stats::incr(&stats::UPDATES_REJECTED);
+                            tracing::warn!(%doc_id, %peer_id, "rejected update: {}", e);
+                            peers.send(peer_id, ServerReply::Error(e));
+                        }
+                    }
+                }
+                RoomCmd::ClientUpdate {
+                    peer_id,
+                    bytes,
+                    version,
+                } => {
+                    let limit = state.config.max_document_bytes;
+                    if log.size() + bytes.len() > limit && adds_content(&doc, &bytes, version) {
+                        // The log over-counts updates that overwrite each
+                        // other; snapshot the document for its real size
+                        // before refusing.
+                        log.compact(&doc).await;
+                        if log.size() + bytes.len() > limit {
+                            stats::incr(&stats::UPDATES_REJECTED);
+                            tracing::warn!(%doc_id, %peer_id, size = log.size(), "rejected update past the document size limit");
+                            peers.send(
+                                peer_id,
+                                ServerReply::Error(ServerError::DocumentTooLarge(limit)),
+                            );
+                            continue;
+                        }
+                    }
+
+                    match schema.apply(&doc, &bytes, version) {
+                        Ok(Applied::Update(update)) => {
+                            stats::incr(&stats::UPDATES_APPLIED);
+                            log.append(&update).await;
+                            log.maybe_compact(&doc);
+                            peers.notify(peer_id, ServerReply::Update(update));
+                        }
+                        Ok(Applied::RolledBack { update, error }) => {
+                            stats::incr(&stats::UPDATES_REJECTED);
+                            tracing::warn!(%doc_id, %peer_id, "rolled back update: {}", error);
+                            log.append(&update).await;
+                            log.maybe_compact(&doc);
+                            peers.broadcast(ServerReply::Update(update));
+                            peers.send(
+                                peer_id,
+                                ServerReply::Error(ServerError::SchemaViolation(error.to_string())),
+                            );
+                        }
+                        Ok(Applied::Empty) => {
+                            tracing::debug!(%doc_id, %peer_id, "ignoring empty update");
+                        }
+                        Err(e) => {
+                            stats::incr(&stats::UPDATES_REJECTED);
+                            tracing::warn!(%doc_id, %peer_id, "rejected update: {}", e);
+                            peers.send(peer_id, ServerReply::Error(e));
+                        }
+                    }
                 }
                 RoomCmd::ClientAwareness { peer_id, bytes } => {
-                    peers.notify(peer_id, ServerReply::Awareness(bytes.clone()));
+                    match awareness.apply(peer_id, &bytes) {
+                        Ok(Some(update)) => {
+                            peers.notify(peer_id, ServerReply::Awareness(update));
+                        }
+                        Ok(None) => {}
+                        Err(e) => {
+                            tracing::warn!(%doc_id, %peer_id, "rejected awareness: {}", e);
+                            peers.send(peer_id, ServerReply::Error(e));
+                        }
+                    }
+                }
+                RoomCmd::AwarenessQuery { peer_id } => {
+                    if let Some(current) = awareness.encode() {
+                        peers.send(peer_id, ServerReply::Awareness(current));
+                    }
                 }
-                RoomCmd::Snapshot { peer_id: _, tx } => {
-                    let snap = encode_snapshot();
+                RoomCmd::SyncStep1 {
+                    peer_id,
+                    state_vector,
+                } => match StateVector::decode_v1(&state_vector) {
+                    Ok(state_vector) => {
+                        let update = doc.transact().encode_state_as_update_v1(&state_vector);
+                        peers.set_state_vector(peer_id, state_vector);
+                        peers.send(peer_id, ServerReply::SyncStep2(update));
+                    }
+                    Err(e) => {
+                        let e = ServerError::InvalidStateVector(e.to_string());
+                        tracing::warn!(%doc_id, %peer_id, "rejected state vector: {}", e);
+                        peers.send(peer_id, ServerReply::Error(e));
+                    }
+                },
+                RoomCmd::Snapshot { peer_id, tx } => {
+                    tracing::debug!(%doc_id, ?peer_id, "encoding snapshot");
+                    let snap = stats::time_snapshot(|| {
+                        doc.transact()
+                            .encode_state_as_update_v1(&StateVector::default())
+                    });
                     let _ = tx.send(snap);
                 }
+                RoomCmd::Heartbeat { .. } => {}
+                RoomCmd::RequestSync { peer_id } => {
+                    let state_vector = doc.transact().state_vector().encode_v1();
+                    peers.send(peer_id, ServerReply::SyncStep1(state_vector));
+                }
+                RoomCmd::HoldsSession { session, grant, tx } => {
+                    let _ = tx.send(peers.holds(&session, &grant));
+                }
+                RoomCmd::Stats { tx } => {
+                    let _ = tx.send(peers.stats());
+                }
+                RoomCmd::SetAcl { acl: new_acl } => {
+                    tracing::info!(%doc_id, "access list changed");
+                    for peer_id in peers.apply_acl(&new_acl) {
+                        tracing::info!(%doc_id, %peer_id, "disconnecting peer whose access was revoked");
+                        remove_peer(&doc_id, &mut peers, &mut awareness, peer_id);
+                    }
+                    acl = new_acl;
+
+                    if peers.is_empty() && idle_since.is_none() {
+                        idle_since = Some(Instant::now());
+                    }
+                }
+                RoomCmd::RevokeShare { token } => {
+                    for peer_id in peers.using_share(&token) {
+                        tracing::info!(%doc_id, %peer_id, "disconnecting peer whose share link was revoked");
+                        remove_peer(&doc_id, &mut peers, &mut awareness, peer_id);
+                    }
+
+                    if peers.is_empty() && idle_since.is_none() {
+                        idle_since = Some(Instant::now());
+                    }
+                }
+                RoomCmd::Shutdown { reason, ack } => {
+                    tracing::info!(%doc_id, %reason, "room shutting down");
+                    peers.broadcast(ServerReply::Closing { reason });
+                    shutdown_ack = Some(ack);
+                    break;
+                }
             }
         }
+
+        // Dropping the peers closes their connections.
+        drop(peers);
+        log.compact(&doc).await;
+        tracing::info!(%doc_id, "room closed");
+
+        if let Some(ack) = shutdown_ack {
+            let _ = ack.send(());
+        }
     });
 
-    RoomHandle::new(cmd_tx)
+    handle
+}
+
+/// Drops `peer_id` from the room, telling the others it left and
+/// clearing its awareness states. Dropping the peer's channel closes its
+/// connection if it is still open.
+fn remove_peer(doc_id: &str, peers: &mut Peers, awareness: &mut RoomAwareness, peer_id: u64) {
+    if peers.remove(&peer_id) {
+        tracing::info!(%doc_id, %peer_id, "peer left");
+        peers.notify(peer_id, ServerReply::PeerLeft { id: peer_id });
+    }
+
+    if let Some(removed) = awareness.remove_peer(peer_id) {
+        peers.notify(peer_id, ServerReply::Awareness(removed));
+    }
+}
+
+/// Everything a peer that dropped replies needs to catch up: the current
+/// peer list, the document changes since its last known state vector and
+/// the full awareness state.
+fn resync_replies(
+    doc: &Doc,
+    peers: &Peers,
+    awareness: &RoomAwareness,
+    peer_id: u64,
+) -> Vec<ServerReply> {
+    let Some(peer) = peers.peers.get(&peer_id) else {
+        return Vec::new();
+    };
+
+    let others = peers
+        .infos()
+        .into_iter()
+        .filter(|info| info.id != peer_id)
+        .collect();
+    let update = doc.transact().encode_state_as_update_v1(&peer.state_vector);
+
+    let mut replies = vec![
+        ServerReply::Join {
+            id: peer_id,
+            session: peer.session.clone(),
+            peers: others,
+            role: peer.role,
+        },
+        ServerReply::SyncStep2(update),
+    ];
+    if let Some(current) = awareness.encode() {
+        replies.push(ServerReply::Awareness(current));
+    }
+
+    replies
+}
+
+/// Resolves once a room that has been empty since `since` has been idle
+/// for `timeout`. Never resolves while the room has peers.
+async fn idle(since: Option<Instant>, timeout: Duration) {
+    match since {
+        Some(since) => tokio::time::sleep_until(since + timeout).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Decodes `bytes` as a yrs update and applies it to `doc`.
+fn apply_update(doc: &Doc, bytes: &[u8], version: UpdateVersion) -> Result<(), ServerError> {
+    let Some((update, _)) = decode_update(bytes, version)? else {
+        return Ok(());
+    };
+
+    doc.transact_mut()
+        .apply_update(update)
+        .map_err(|e| ServerError::InvalidUpdate(e.to_string()))
+}
+
+/// Loads the ACL of `doc_id`. If it cannot be read, everyone is let in
+/// read-only rather than locking out the owner or opening the document
+/// to edits.
+async fn load_acl(state: &AppState, doc_id: &str) -> Acl {
+    let store = state.store.clone();
+    let id = doc_id.to_string();
+    match tokio::task::spawn_blocking(move || store.load_acl(&id)).await {
+        Ok(Ok(acl)) => acl.unwrap_or_default(),
+        Ok(Err(e)) => {
+            tracing::error!(%doc_id, "failed to load access list: {}", e);
+            read_only()
+        }
+        Err(e) => {
+            tracing::error!(%doc_id, "access list load task failed: {}", e);
+            read_only()
+        }
+    }
+}
+
+fn read_only() -> Acl {
+    Acl {
+        members: Default::default(),
+        default_role: Some(Role::Viewer),
+    }
+}
+
+/// Moves a document still in the plain-text layout into blocks.
+/// Returns the update to log if it was migrated.
+fn migrate(doc_id: &str, doc: &Doc) -> Option<Vec<u8>> {
+    let mut txn = doc.transact_mut();
+    if !ydoc::migrate_plain_text(&mut txn, doc_id) {
+        return None;
+    }
+
+    tracing::info!(%doc_id, "migrated plain-text document to blocks");
+    Some(txn.encode_update_v1())
+}
+
+/// Whether `bytes` inserts anything `doc` does not have yet. Updates that
+/// only delete are let into a full document so it can shrink again; ones
+/// that cannot be decoded are left for `Schema::apply` to refuse.
+fn adds_content(doc: &Doc, bytes: &[u8], version: UpdateVersion) -> bool {
+    let Ok(Some((update, _))) = decode_update(bytes, version) else {
+        return false;
+    };
+
+    let known = doc.transact().state_vector();
+    update.extends(&known)
+        || update
+            .state_vector_lower()
+            .iter()
+            .any(|(client, clock)| *clock >= known.get(client))
+}
+
+/// Rebuilds a document by replaying its stored snapshot and update log.
+/// Fails if any stored entry cannot be applied.
+fn restore_doc(stored: Option<StoredDoc>) -> Result<Doc, ServerError> {
+    let doc = Doc::new();
+    let Some(stored) = stored else {
+        return Ok(doc);
+    };
+
+    let updates = stored.updates.into_iter().map(|(_, update)| update);
+    for update in stored.snapshot.into_iter().chain(updates) {
+        apply_update(&doc, &update, UpdateVersion::V1)?;
+    }
+
+    Ok(doc)
+}
+
+#[cfg(test)]
+mod tests {
+    use yrs::{
+        sync::awareness::{AwarenessUpdate, AwarenessUpdateEntry},
+        updates::encoder::Encode,
+        Update, WriteTxn,
+    };
+
+    use super::*;
+    use crate::{
+        config::Config,
+        socket::{request_snapshot, Joiner},
+        state::Grant,
+    };
+
+    use shared::{
+        api::ClientStats,
+        screenplay::{Element, Screenplay, ScreenplayElementKind as Kind},
+        server::PeerInfo,
+    };
+
+    fn test_state(flags: &[&str]) -> AppState {
+        AppState::for_tests(Config::for_tests(flags))
+    }
+
+    /// A peer that joined a room, and the replies the room sends it.
+    struct Client {
+        id: u64,
+        session: String,
+        role: Role,
+        /// The other peers in the room when the peer joined.
+        peers: Vec<PeerInfo>,
+        /// The room's state vector when the peer joined.
+        state_vector: StateVector,
+        rx: mpsc::Receiver<ServerReply>,
+    }
+
+    impl Client {
+        async fn recv(&mut self) -> ServerReply {
+            tokio::time::timeout(Duration::from_secs(5), self.rx.recv())
+                .await
+                .expect("no reply from the room")
+                .expect("room dropped the peer")
+        }
+    }
+
+    /// An anonymous peer with a fresh id.
+    fn anonymous() -> Joiner {
+        Joiner {
+            peer_id: rand::random(),
+            grant: Grant::Acl(None),
+            name: None,
+            session: None,
+        }
+    }
+
+    /// The signed-in user `id`, with a fresh peer id.
+    fn user(id: &str) -> Joiner {
+        Joiner {
+            grant: Grant::Acl(Some(id.to_string())),
+            ..anonymous()
+        }
+    }
+
+    async fn join(room: &RoomHandle) -> Client {
+        join_as(room, anonymous(), 64).await
+    }
+
+    /// Joins as `joiner` over a reply channel holding `capacity` replies,
+    /// and reads the join replies.
+    async fn join_as(room: &RoomHandle, joiner: Joiner, capacity: usize) -> Client {
+        let (tx, mut rx) = mpsc::channel(capacity);
+        let join = RoomCmd::Join {
+            peer_id: joiner.peer_id,
+            grant: joiner.grant,
+            name: joiner.name,
+            session: joiner.session,
+            tx,
+        };
+        room.cmd_tx.send(join).await.unwrap();
+
+        let Some(ServerReply::Join {
+            id,
+            session,
+            peers,
+            role,
+        }) = rx.recv().await
+        else {
+            panic!("room did not answer the join");
+        };
+        let Some(ServerReply::SyncStep1(state_vector)) = rx.recv().await else {
+            panic!("room did not open the handshake");
+        };
+        let state_vector = StateVector::decode_v1(&state_vector).unwrap();
+
+        Client {
+            id,
+            session,
+            role,
+            peers,
+            state_vector,
+            rx,
+        }
+    }
+
+    async fn leave(room: &RoomHandle, client: &Client, resumable: bool) {
+        let cmd = RoomCmd::Leave {
+            peer_id: client.id,
+            session: client.session.clone(),
+            resumable,
+        };
+        room.cmd_tx.send(cmd).await.unwrap();
+    }
+
+    async fn update(room: &RoomHandle, peer_id: u64, bytes: Vec<u8>, version: UpdateVersion) {
+        let cmd = RoomCmd::ClientUpdate {
+            peer_id,
+            bytes,
+            version,
+        };
+        room.cmd_tx.send(cmd).await.unwrap();
+    }
+
+    /// Appends an action to `client` and returns the v1 update it made.
+    fn push(client: &Doc, text: &str) -> Vec<u8> {
+        let before = client.transact().state_vector();
+        let mut txn = client.transact_mut();
+        let blocks = txn.get_or_insert_array(ydoc::BLOCKS);
+        ydoc::push_block(&mut txn, &blocks, &Element::new(Kind::Action, text));
+        drop(txn);
+
+        let update = client.transact().encode_state_as_update_v1(&before);
+        update
+    }
+
+    /// Sets the awareness state of `client_id` on behalf of `peer_id`.
+    async fn set_awareness(room: &RoomHandle, peer_id: u64, client_id: u64, json: &str) {
+        let entry = AwarenessUpdateEntry {
+            clock: 1,
+            json: json.into(),
+        };
+        let update = AwarenessUpdate {
+            clients: [(client_id, entry)].into(),
+        };
+        let cmd = RoomCmd::ClientAwareness {
+            peer_id,
+            bytes: update.encode_v1(),
+        };
+        room.cmd_tx.send(cmd).await.unwrap();
+    }
+
+    async fn stats(room: &RoomHandle) -> Vec<ClientStats> {
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        room.cmd_tx.send(RoomCmd::Stats { tx }).await.unwrap();
+        rx.await.unwrap()
+    }
+
+    /// The room's document, read through a snapshot.
+    async fn content(room: &RoomHandle) -> Vec<Element> {
+        let snapshot = request_snapshot(room, None).await.unwrap();
+        read(&snapshot).elements
+    }
+
+    fn read(update: &[u8]) -> Screenplay {
+        let doc = Doc::new();
+        apply_update(&doc, update, UpdateVersion::V1).unwrap();
+        let screenplay = ydoc::read(&doc.transact()).unwrap();
+        screenplay
+    }
+
+    fn actions(texts: &[&str]) -> Vec<Element> {
+        texts
+            .iter()
+            .map(|text| Element::new(Kind::Action, *text))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn applies_updates_and_relays_them_to_the_other_peers() {
+        let state = test_state(&[]);
+        let room = state.room("doc");
+        let mut a = join(&room).await;
+        let mut b = join(&room).await;
+        assert!(matches!(a.recv().await, ServerReply::PeerJoined(_)));
+
+        let client = Doc::new();
+        let first = push(&client, "She waits.");
+        update(&room, a.id, first.clone(), UpdateVersion::V1).await;
+        assert!(matches!(b.recv().await, ServerReply::Update(bytes) if bytes == first));
+        assert_eq!(content(&room).await, actions(&["She waits."]));
+
+        // v2 updates are applied too and relayed as v1.
+        let second = push(&client, "Then leaves.");
+        let v2 = Update::decode_v1(&second).unwrap().encode_v2();
+        update(&room, a.id, v2, UpdateVersion::V2).await;
+        let ServerReply::Update(relayed) = b.recv().await else {
+            panic!("v2 update was not relayed");
+        };
+        assert!(Update::decode_v1(&relayed).is_ok());
+        assert_eq!(
+            content(&room).await,
+            actions(&["She waits.", "Then leaves."])
+        );
+
+        // Garbage is refused, and only its sender hears about it.
+        update(&room, a.id, vec![0xff; 8], UpdateVersion::V1).await;
+        assert!(matches!(
+            a.recv().await,
+            ServerReply::Error(ServerError::InvalidUpdate(_))
+        ));
+        assert_eq!(
+            content(&room).await,
+            actions(&["She waits.", "Then leaves."])
+        );
+        assert!(a.rx.try_recv().is_err());
+        assert!(b.rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn syncs_a_reconnecting_peer_both_ways() {
+        let state = test_state(&[]);
+        let room = state.room("doc");
+        let mut a = join(&room).await;
+
+        let writer = Doc::new();
+        let first = push(&writer, "She waits.");
+        update(&room, a.id, first.clone(), UpdateVersion::V1).await;
+
+        // A peer that saw the first edit goes offline and makes one of
+        // its own while the room gets another.
+        let offline = Doc::new();
+        apply_update(&offline, &first, UpdateVersion::V1).unwrap();
+        push(&offline, "She leaves.");
+        update(&room, a.id, push(&writer, "He stays."), UpdateVersion::V1).await;
+
+        let mut b = join(&room).await;
+        assert!(matches!(a.recv().await, ServerReply::PeerJoined(_)));
+
+        // The room's SyncStep1 tells the peer what to send back...
+        let missing = offline
+            .transact()
+            .encode_state_as_update_v1(&b.state_vector);
+        update(&room, b.id, missing, UpdateVersion::V1).await;
+        assert!(matches!(a.recv().await, ServerReply::Update(_)));
+
+        // ...and the peer's tells the room what it has to send.
+        let state_vector = offline.transact().state_vector().encode_v1();
+        let cmd = RoomCmd::SyncStep1 {
+            peer_id: b.id,
+            state_vector,
+        };
+        room.cmd_tx.send(cmd).await.unwrap();
+        let ServerReply::SyncStep2(diff) = b.recv().await else {
+            panic!("SyncStep1 was not answered with SyncStep2");
+        };
+        let snapshot = request_snapshot(&room, None).await.unwrap();
+        assert!(diff.len() < snapshot.len(), "sent the whole document");
+
+        apply_update(&offline, &diff, UpdateVersion::V1).unwrap();
+        let synced = ydoc::read(&offline.transact()).unwrap().elements;
+        assert_eq!(synced, content(&room).await);
+        assert_eq!(synced.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn answers_a_bad_state_vector_and_a_sync_request() {
+        let state = test_state(&[]);
+        let room = state.room("doc");
+        let mut a = join(&room).await;
+
+        let cmd = RoomCmd::SyncStep1 {
+            peer_id: a.id,
+            state_vector: vec![0xff; 8],
+        };
+        room.cmd_tx.send(cmd).await.unwrap();
+        assert!(matches!(
+            a.recv().await,
+            ServerReply::Error(ServerError::InvalidStateVector(_))
+        ));
+
+        let cmd = RoomCmd::RequestSync { peer_id: a.id };
+        room.cmd_tx.send(cmd).await.unwrap();
+        assert!(matches!(a.recv().await, ServerReply::SyncStep1(_)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn hibernates_once_idle_and_respawns_from_storage() {
+        let state = test_state(&["--room-idle-secs", "60"]);
+        let room = state.room("doc");
+        let a = join(&room).await;
+        update(
+            &room,
+            a.id,
+            push(&Doc::new(), "She waits."),
+            UpdateVersion::V1,
+        )
+        .await;
+        leave(&room, &a, false).await;
+        drop(room);
+
+        tokio::time::sleep(Duration::from_secs(59)).await;
+        assert!(state.rooms.contains_key("doc"));
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        assert!(!state.rooms.contains_key("doc"));
+
+        let room = state.room("doc");
+        assert_eq!(content(&room).await, actions(&["She waits."]));
+
+        // A connection that has not joined yet keeps the room running.
+        tokio::time::sleep(Duration::from_secs(120)).await;
+        assert!(state.rooms.get("doc").is_some_and(|r| r.is_same(&room)));
+    }
+
+    #[tokio::test]
+    async fn tells_the_others_who_joined_and_left() {
+        let state = test_state(&[]);
+        let room = state.room("doc");
+        let mut a = join(&room).await;
+        let brick = Joiner {
+            name: Some("  Brick ".to_string()),
+            ..anonymous()
+        };
+        let b = join_as(&room, brick, 64).await;
+
+        let ServerReply::PeerJoined(info) = a.recv().await else {
+            panic!("the others were not told about the new peer");
+        };
+        assert_eq!((info.id, info.name.as_str()), (b.id, "Brick"));
+        assert!(info.color.starts_with('#'));
+        assert_eq!(b.peers.iter().map(|p| p.id).collect::<Vec<_>>(), [a.id]);
+
+        leave(&room, &b, false).await;
+        assert!(matches!(a.recv().await, ServerReply::PeerLeft { id } if id == b.id));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn resyncs_a_lagging_peer_once_it_drains() {
+        let state = test_state(&[]);
+        let room = state.room("doc");
+        let a = join(&room).await;
+        let mut b = join_as(&room, anonymous(), 4).await;
+
+        let writer = Doc::new();
+        for line in 0..6 {
+            let bytes = push(&writer, &format!("Line {line}."));
+            update(&room, a.id, bytes, UpdateVersion::V1).await;
+        }
+        let lagging = stats(&room).await.into_iter().find(|c| c.id == b.id);
+        assert!(lagging.is_some_and(|c| c.lagging));
+
+        // The peer reads what fit in its queue, then is sent the rest.
+        let reader = Doc::new();
+        for _ in 0..4 {
+            let ServerReply::Update(bytes) = b.recv().await else {
+                panic!("expected a queued update");
+            };
+            apply_update(&reader, &bytes, UpdateVersion::V1).unwrap();
+        }
+        let ServerReply::Join { id, session, .. } = b.recv().await else {
+            panic!("lagging peer was not resynced");
+        };
+        assert_eq!((id, session), (b.id, b.session.clone()));
+        let ServerReply::SyncStep2(diff) = b.recv().await else {
+            panic!("resync carried no document changes");
+        };
+        apply_update(&reader, &diff, UpdateVersion::V1).unwrap();
+
+        let synced = ydoc::read(&reader.transact()).unwrap().elements;
+        assert_eq!(synced.len(), 6);
+        assert_eq!(synced, content(&room).await);
+        assert!(stats(&room).await.iter().all(|c| !c.lagging));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn disconnects_a_peer_that_stops_reading() {
+        let state = test_state(&[]);
+        let room = state.room("doc");
+        let mut a = join(&room).await;
+        let mut b = join_as(&room, anonymous(), 2).await;
+        assert!(matches!(a.recv().await, ServerReply::PeerJoined(_)));
+
+        let writer = Doc::new();
+        for line in 0..3 {
+            let bytes = push(&writer, &format!("Line {line}."));
+            update(&room, a.id, bytes, UpdateVersion::V1).await;
+        }
+
+        tokio::time::sleep(MAX_LAG + 2 * RESYNC_INTERVAL).await;
+        assert!(matches!(a.recv().await, ServerReply::PeerLeft { id } if id == b.id));
+        while let Some(reply) = b.rx.recv().await {
+            assert!(matches!(reply, ServerReply::Update(_)), "{reply:?}");
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn drops_peers_that_stop_answering_pings() {
+        let state = test_state(&[]);
+        let room = state.room("doc");
+        let mut a = join(&room).await;
+        let mut b = join(&room).await;
+        assert!(matches!(a.recv().await, ServerReply::PeerJoined(_)));
+
+        for _ in 0..4 {
+            tokio::time::sleep(PING_INTERVAL).await;
+            let cmd = RoomCmd::Heartbeat { peer_id: a.id };
+            room.cmd_tx.send(cmd).await.unwrap();
+        }
+
+        assert!(matches!(a.recv().await, ServerReply::PeerLeft { id } if id == b.id));
+        assert!(b.rx.recv().await.is_none());
+        let ids: Vec<_> = stats(&room).await.iter().map(|c| c.id).collect();
+        assert_eq!(ids, [a.id]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn resumes_a_session_within_the_grace_window() {
+        let state = test_state(&[]);
+        let room = state.room("doc");
+        let mut a = join(&room).await;
+        let b = join(&room).await;
+        assert!(matches!(a.recv().await, ServerReply::PeerJoined(_)));
+        set_awareness(&room, b.id, 7, r#"{"name":"B"}"#).await;
+        assert!(matches!(a.recv().await, ServerReply::Awareness(_)));
+
+        leave(&room, &b, true).await;
+        tokio::time::sleep(SESSION_GRACE - Duration::from_secs(5)).await;
+        let away = stats(&room).await.into_iter().find(|c| c.id == b.id);
+        assert!(away.is_some_and(|c| c.away));
+
+        // Only the same holder can resume it.
+        let share = Joiner {
+            grant: Grant::Share {
+                token: "link".to_string(),
+                role: Role::Editor,
+            },
+            session: Some(b.session.clone()),
+            ..anonymous()
+        };
+        let stranger = join_as(&room, share, 64).await;
+        assert_ne!(stranger.id, b.id);
+        assert!(matches!(a.recv().await, ServerReply::PeerJoined(p) if p.id == stranger.id));
+        leave(&room, &stranger, false).await;
+        assert!(matches!(a.recv().await, ServerReply::PeerLeft { .. }));
+
+        let rejoin = Joiner {
+            session: Some(b.session.clone()),
+            ..anonymous()
+        };
+        let mut resumed = join_as(&room, rejoin, 64).await;
+        assert_eq!(resumed.id, b.id);
+        assert_ne!(resumed.session, b.session);
+        let ServerReply::Awareness(current) = resumed.recv().await else {
+            panic!("resumed peer lost its awareness state");
+        };
+        assert!(AwarenessUpdate::decode_v1(&current)
+            .unwrap()
+            .clients
+            .contains_key(&7));
+
+        // The others never saw it leave or come back.
+        let _ = request_snapshot(&room, None).await;
+        assert!(a.rx.try_recv().is_err());
+        assert!(stats(&room).await.iter().all(|c| !c.away));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn lets_a_session_go_after_the_grace_window() {
+        let state = test_state(&[]);
+        let room = state.room("doc");
+        let mut a = join(&room).await;
+        let b = join(&room).await;
+        assert!(matches!(a.recv().await, ServerReply::PeerJoined(_)));
+
+        leave(&room, &b, true).await;
+        for _ in 0..3 {
+            tokio::time::sleep(PING_INTERVAL).await;
+            let cmd = RoomCmd::Heartbeat { peer_id: a.id };
+            room.cmd_tx.send(cmd).await.unwrap();
+        }
+        assert!(matches!(a.recv().await, ServerReply::PeerLeft { id } if id == b.id));
+
+        let rejoin = Joiner {
+            session: Some(b.session.clone()),
+            ..anonymous()
+        };
+        let late = join_as(&room, rejoin, 64).await;
+        assert_ne!(late.id, b.id);
+        assert!(matches!(a.recv().await, ServerReply::PeerJoined(p) if p.id == late.id));
+    }
+
+    #[tokio::test]
+    async fn shutdown_saves_and_closes_every_room() {
+        let state = test_state(&[]);
+        let one = state.room("one");
+        let two = state.room("two");
+        let mut a = join(&one).await;
+        let mut b = join(&two).await;
+        update(
+            &one,
+            a.id,
+            push(&Doc::new(), "She waits."),
+            UpdateVersion::V1,
+        )
+        .await;
+        update(
+            &two,
+            b.id,
+            push(&Doc::new(), "He stays."),
+            UpdateVersion::V1,
+        )
+        .await;
+
+        assert!(state.shutdown(Duration::from_secs(5)).await);
+        for client in [&mut a, &mut b] {
+            assert!(matches!(
+                client.recv().await,
+                ServerReply::Closing { reason } if reason == "server restarting"
+            ));
+            assert!(client.rx.recv().await.is_none());
+        }
+
+        for (doc_id, text) in [("one", "She waits."), ("two", "He stays.")] {
+            let stored = state.store.load(doc_id).unwrap().unwrap();
+            assert!(stored.updates.is_empty());
+            let snapshot = stored.snapshot.unwrap();
+            assert_eq!(read(&snapshot).elements, actions(&[text]));
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn shutdown_gives_up_on_a_room_that_does_not_answer() {
+        let state = test_state(&[]);
+        let (cmd_tx, _cmd_rx) = mpsc::channel(1);
+        state
+            .rooms
+            .insert("stuck".to_string(), RoomHandle::new(cmd_tx));
+
+        assert!(!state.shutdown(Duration::from_secs(5)).await);
+        assert!(state
+            .shutting_down
+            .load(std::sync::atomic::Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn holds_peers_to_their_role() {
+        let state = test_state(&[]);
+        let mut acl = Acl {
+            members: [
+                ("ada".to_string(), Role::Owner),
+                ("bob".to_string(), Role::Viewer),
+            ]
+            .into(),
+            default_role: None,
+        };
+        state.store.save_acl("doc", &acl).unwrap();
+        let room = state.room("doc");
+
+        // Peers the ACL does not name are turned away.
+        let (tx, mut rx) = mpsc::channel(4);
+        let join = RoomCmd::Join {
+            peer_id: 1,
+            grant: Grant::Acl(None),
+            name: None,
+            session: None,
+            tx,
+        };
+        room.cmd_tx.send(join).await.unwrap();
+        assert!(rx.recv().await.is_none());
+
+        let mut owner = join_as(&room, user("ada"), 64).await;
+        let mut viewer = join_as(&room, user("bob"), 64).await;
+        assert_eq!((owner.role, viewer.role), (Role::Owner, Role::Viewer));
+        assert!(matches!(owner.recv().await, ServerReply::PeerJoined(_)));
+
+        update(
+            &room,
+            viewer.id,
+            push(&Doc::new(), "Scribble."),
+            UpdateVersion::V1,
+        )
+        .await;
+        assert!(matches!(
+            viewer.recv().await,
+            ServerReply::Error(ServerError::ReadOnly(Role::Viewer))
+        ));
+        // An empty SyncStep2 is how a viewer answers the handshake.
+        let empty = Doc::new()
+            .transact()
+            .encode_state_as_update_v1(&StateVector::default());
+        update(&room, viewer.id, empty, UpdateVersion::V1).await;
+        assert!(content(&room).await.is_empty());
+        assert!(owner.rx.try_recv().is_err());
+        assert!(viewer.rx.try_recv().is_err());
+
+        acl.members.insert("bob".to_string(), Role::Editor);
+        room.cmd_tx
+            .send(RoomCmd::SetAcl { acl: acl.clone() })
+            .await
+            .unwrap();
+        assert!(matches!(
+            viewer.recv().await,
+            ServerReply::RoleChanged(Role::Editor)
+        ));
+        update(
+            &room,
+            viewer.id,
+            push(&Doc::new(), "Edit."),
+            UpdateVersion::V1,
+        )
+        .await;
+        assert!(matches!(owner.recv().await, ServerReply::Update(_)));
+
+        acl.members.remove("bob");
+        room.cmd_tx.send(RoomCmd::SetAcl { acl }).await.unwrap();
+        assert!(matches!(owner.recv().await, ServerReply::PeerLeft { id } if id == viewer.id));
+        assert!(viewer.rx.recv().await.is_none());
+    }
 }
@@ -0,0 +1,292 @@
+//! Keeps client updates from breaking the screenplay layout.
+//!
+//! A yrs update only makes sense against the document it lands in, so
+//! updates are applied first and checked after. One that leaves the
+//! document outside the layout of [`shared::screenplay::ydoc`] is undone,
+//! and the update together with its undo is relayed to every peer, the
+//! sender included, so they all end up where they started.
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+use yrs::{
+    types::{Delta, Event},
+    undo::Options,
+    updates::{decoder::Decode, encoder::Encode},
+    Any, DeepObservable, Doc, Out, ReadTxn, Transact, TransactionMut, UndoManager, Update,
+};
+
+use crate::state::UpdateVersion;
+
+use shared::{
+    screenplay::ydoc::{self, LayoutError},
+    server::ServerError,
+};
+
+/// Origin of the transactions client updates are applied in.
+const CLIENT_ORIGIN: &str = "client";
+
+pub(crate) enum Applied {
+    /// The update was applied; relay it, re-encoded as v1.
+    Update(Vec<u8>),
+    /// The update carried no changes.
+    Empty,
+    /// The update broke the layout and was undone. `update` holds both
+    /// the update and its undo.
+    RolledBack { update: Vec<u8>, error: LayoutError },
+}
+
+pub(crate) struct Schema {
+    undo: UndoManager,
+    /// Set when a transaction changes more than the characters of texts.
+    /// Typing cannot break the layout, so only these need checking.
+    reshaped: Arc<AtomicBool>,
+    /// Whether the document followed the layout after the last update.
+    /// A document that was already broken when loaded is not held to it
+    /// until it has been repaired, so that it can still be edited.
+    valid: bool,
+}
+
+impl Schema {
+    pub(crate) fn new(doc_id: &str, doc: &Doc) -> Self {
+        let blocks = doc.get_or_insert_array(ydoc::BLOCKS);
+        let title_page = doc.get_or_insert_array(ydoc::TITLE_PAGE);
+
+        // Every update is its own undo step, and the step is dropped as
+        // soon as the update passes.
+        let options = Options {
+            capture_timeout_millis: 0,
+            ..Options::default()
+        };
+        let mut undo = UndoManager::with_scope_and_options(doc, &blocks, options);
+        undo.expand_scope(&title_page);
+        undo.include_origin(CLIENT_ORIGIN);
+
+        let reshaped = Arc::new(AtomicBool::new(false));
+        for root in [&blocks, &title_page] {
+            let reshaped = reshaped.clone();
+            root.observe_deep_with("schema", move |txn, events| {
+                if events.iter().any(|e| !is_typing(txn, e)) {
+                    reshaped.store(true, Ordering::Relaxed);
+                }
+            });
+        }
+
+        let valid = match ydoc::read(&doc.transact()) {
+            Ok(_) => true,
+            Err(e) => {
+                tracing::warn!(%doc_id, "document does not follow the screenplay layout: {}", e);
+                false
+            }
+        };
+
+        Self {
+            undo,
+            reshaped,
+            valid,
+        }
+    }
+
+    /// Applies a client update to `doc`, undoing it if it breaks the
+    /// layout.
+    pub(crate) fn apply(
+        &mut self,
+        doc: &Doc,
+        bytes: &[u8],
+        version: UpdateVersion,
+    ) -> Result<Applied, ServerError> {
+        let Some((update, relay)) = decode_update(bytes, version)? else {
+            return Ok(Applied::Empty);
+        };
+
+        let before = doc.transact().state_vector();
+        self.reshaped.store(false, Ordering::Relaxed);
+        let applied = doc
+            .transact_mut_with(CLIENT_ORIGIN)
+            .apply_update(update)
+            .map_err(|e| ServerError::InvalidUpdate(e.to_string()));
+
+        let checked = match applied {
+            Ok(()) if self.valid && !self.reshaped.load(Ordering::Relaxed) => Ok(Ok(())),
+            Ok(()) => Ok(ydoc::read(&doc.transact()).map(drop)),
+            Err(e) => Err(e),
+        };
+
+        let applied = match checked {
+            Ok(Ok(())) => {
+                self.valid = true;
+                Ok(Applied::Update(relay))
+            }
+            Ok(Err(_)) if !self.valid => Ok(Applied::Update(relay)),
+            Ok(Err(error)) => {
+                self.undo.undo_blocking();
+                let update = doc.transact().encode_state_as_update_v1(&before);
+                Ok(Applied::RolledBack { update, error })
+            }
+            Err(e) => Err(e),
+        };
+        self.undo.clear();
+
+        applied
+    }
+}
+
+/// Whether `event` only inserts or removes characters in a text. Texts
+/// can also have values and shared types embedded in them, which the
+/// layout does not allow.
+fn is_typing(txn: &TransactionMut, event: &Event) -> bool {
+    let Event::Text(text) = event else {
+        return false;
+    };
+
+    text.delta(txn).iter().all(|delta| match delta {
+        Delta::Inserted(value, _) => matches!(value, Out::Any(Any::String(_))),
+        Delta::Deleted(_) | Delta::Retain(..) => true,
+    })
+}
+
+/// Decodes `bytes` as a yrs update, along with the update encoded as v1
+/// so it can be relayed to the other peers. Returns `None` if the update
+/// carries no changes.
+pub(crate) fn decode_update(
+    bytes: &[u8],
+    version: UpdateVersion,
+) -> Result<Option<(Update, Vec<u8>)>, ServerError> {
+    let update = match version {
+        UpdateVersion::V1 => Update::decode_v1(bytes),
+        UpdateVersion::V2 => Update::decode_v2(bytes),
+    }
+    .map_err(|e| ServerError::InvalidUpdate(e.to_string()))?;
+
+    if update.is_empty() {
+        return Ok(None);
+    }
+
+    let relay = match version {
+        UpdateVersion::V1 => bytes.to_vec(),
+        UpdateVersion::V2 => update.encode_v1(),
+    };
+
+    Ok(Some((update, relay)))
+}
+
+#[cfg(test)]
+mod tests {
+    use shared::screenplay::{Element, Screenplay, ScreenplayElementKind as Kind};
+    use yrs::{Array, ArrayRef, Map, MapPrelim, MapRef, Text, TextRef, WriteTxn};
+
+    use super::*;
+
+    /// A room document holding one action block, and a client in sync
+    /// with it.
+    fn setup() -> (Doc, Schema, Doc) {
+        let doc = Doc::new();
+        let screenplay = Screenplay {
+            elements: vec![Element::new(Kind::Action, "She waits.")],
+            ..Screenplay::default()
+        };
+        ydoc::write(&mut doc.transact_mut(), &screenplay);
+        let schema = Schema::new("test", &doc);
+
+        let client = Doc::new();
+        sync(&doc, &client);
+        (doc, schema, client)
+    }
+
+    fn sync(from: &Doc, to: &Doc) {
+        let update = from
+            .transact()
+            .encode_state_as_update_v1(&to.transact().state_vector());
+        to.transact_mut()
+            .apply_update(Update::decode_v1(&update).unwrap())
+            .unwrap();
+    }
+
+    /// Runs `edit` on `client` and returns the update it made.
+    fn edit(client: &Doc, edit: impl FnOnce(&mut yrs::TransactionMut, ArrayRef)) -> Vec<u8> {
+        let before = client.transact().state_vector();
+        let mut txn = client.transact_mut();
+        let blocks = txn.get_or_insert_array(ydoc::BLOCKS);
+        edit(&mut txn, blocks);
+        drop(txn);
+        client.transact().encode_state_as_update_v1(&before)
+    }
+
+    fn first_text(txn: &impl ReadTxn, blocks: &ArrayRef) -> TextRef {
+        let block: MapRef = blocks.get(txn, 0).unwrap().cast().unwrap();
+        block.get(txn, "text").unwrap().cast().unwrap()
+    }
+
+    fn apply(schema: &mut Schema, doc: &Doc, update: &[u8]) -> Applied {
+        schema.apply(doc, update, UpdateVersion::V1).unwrap()
+    }
+
+    #[test]
+    fn passes_a_text_edit() {
+        let (doc, mut schema, client) = setup();
+        let update = edit(&client, |txn, blocks| {
+            first_text(txn, &blocks).push(txn, " And waits.");
+        });
+
+        assert!(matches!(
+            apply(&mut schema, &doc, &update),
+            Applied::Update(_)
+        ));
+        let read = ydoc::read(&doc.transact()).unwrap();
+        assert_eq!(read.elements[0].text, "She waits. And waits.");
+    }
+
+    #[test]
+    fn rolls_back_a_bad_block() {
+        let (doc, mut schema, client) = setup();
+        let before = ydoc::read(&doc.transact()).unwrap();
+        let update = edit(&client, |txn, blocks| {
+            blocks.push_back(txn, MapPrelim::from([("kind", "montage")]));
+        });
+
+        let Applied::RolledBack { update, error } = apply(&mut schema, &doc, &update) else {
+            panic!("bad block was kept");
+        };
+        assert!(error.to_string().contains("montage"), "{error}");
+        assert_eq!(ydoc::read(&doc.transact()).unwrap(), before);
+
+        // The relayed undo brings the sender back in line too.
+        client
+            .transact_mut()
+            .apply_update(Update::decode_v1(&update).unwrap())
+            .unwrap();
+        assert_eq!(ydoc::read(&client.transact()).unwrap(), before);
+
+        // Later edits are still checked and applied.
+        let update = edit(&client, |txn, blocks| {
+            first_text(txn, &blocks).push(txn, "!");
+        });
+        assert!(matches!(
+            apply(&mut schema, &doc, &update),
+            Applied::Update(_)
+        ));
+    }
+
+    #[test]
+    fn rolls_back_an_embed_in_a_block_text() {
+        let (doc, mut schema, client) = setup();
+        let update = edit(&client, |txn, blocks| {
+            first_text(txn, &blocks).insert_embed(txn, 0, MapPrelim::default());
+        });
+
+        assert!(matches!(
+            apply(&mut schema, &doc, &update),
+            Applied::RolledBack { .. }
+        ));
+        let read = ydoc::read(&doc.transact()).unwrap();
+        assert_eq!(read.elements[0].text, "She waits.");
+    }
+
+    #[test]
+    fn ignores_an_empty_update() {
+        let (doc, mut schema, client) = setup();
+        let update = edit(&client, |_, _| {});
+        assert!(matches!(apply(&mut schema, &doc, &update), Applied::Empty));
+    }
+}
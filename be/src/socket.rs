@@ -1,105 +1,368 @@
 use axum::extract::ws::{Message, WebSocket};
 use futures::{SinkExt, StreamExt};
-use tokio::sync::{mpsc, oneshot};
+use std::time::Duration;
 
-use crate::state::{into_message, RoomCmd};
+use tokio::{
+    sync::{mpsc, oneshot},
+    time::Instant,
+};
 
-use shared::server::{ServerReply, ServerRequest};
+use crate::{
+    limits::Limiter,
+    state::{into_message, Grant, RoomCmd, RoomHandle, UpdateVersion, PING_INTERVAL},
+    stats,
+};
 
-#[tracing::instrument(skip(state, socket))]
-pub async fn handle_connect(state: crate::state::AppState, doc_id: String, socket: WebSocket) {
+use shared::server::{ServerError, ServerReply, ServerRequest};
+
+/// How long a client being disconnected by the server gets to receive
+/// the replies explaining why.
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Who a new connection joins its room as.
+#[derive(Debug)]
+pub(crate) struct Joiner {
+    pub(crate) peer_id: u64,
+    pub(crate) grant: Grant,
+    pub(crate) name: Option<String>,
+    /// Session token to resume, from an earlier `Join` reply.
+    pub(crate) session: Option<String>,
+}
+
+#[tracing::instrument(skip(state, joiner, socket), fields(peer_id = joiner.peer_id))]
+pub(crate) async fn handle_connect(
+    state: crate::state::AppState,
+    doc_id: String,
+    joiner: Joiner,
+    socket: WebSocket,
+) {
     tracing::info!(%doc_id, "new websocket connection");
-    let handle = state
-        .rooms
-        .entry(doc_id.clone())
-        .or_insert_with(|| crate::room::spawn_room(doc_id.clone()))
-        .clone();
+    let handle = state.room(&doc_id);
 
     let (mut sink, mut stream) = socket.split();
 
-    let (server_tx, mut server_rx) = mpsc::channel::<ServerReply>(64);
-    let peer_id = rand::random::<u64>();
+    // Replies from the room and replies produced by this handler travel on
+    // separate channels: the room closes its channel to disconnect us.
+    let (room_tx, mut room_rx) = mpsc::channel::<ServerReply>(64);
+    let (server_tx, mut server_rx) = mpsc::channel::<ServerReply>(16);
 
-    if let Err(e) = handle
-        .cmd_tx
-        .send(RoomCmd::Join {
-            peer_id,
-            tx: server_tx.clone(),
-        })
-        .await
-    {
-        tracing::error!("failed to join room: {}", e);
+    let Some((peer_id, session, joined)) = join_room(&handle, joiner, room_tx, &mut room_rx).await
+    else {
+        return;
+    };
+    let joined = into_message(joined);
+    stats::add(&stats::BYTES_OUT, payload_len(&joined));
+    if sink.send(joined).await.is_err() {
+        let _ = handle
+            .cmd_tx
+            .send(RoomCmd::Leave {
+                peer_id,
+                session,
+                resumable: true,
+            })
+            .await;
         return;
     }
 
-    let sink_task = tokio::spawn(async move {
-        while let Some(msg) = server_rx.recv().await {
-            if sink.send(into_message(msg)).await.is_err() {
-                break;
+    let mut sink_task = tokio::spawn(async move {
+        let mut ping = tokio::time::interval_at(Instant::now() + PING_INTERVAL, PING_INTERVAL);
+        loop {
+            let msg = tokio::select! {
+                // Our own replies go first so an error explaining a
+                // disconnect is sent before the connection closes.
+                biased;
+                Some(reply) = server_rx.recv() => into_message(reply),
+                reply = room_rx.recv() => match reply {
+                    Some(reply) => into_message(reply),
+                    None => break,
+                },
+                _ = ping.tick() => Message::Ping(Default::default()),
+            };
+
+            stats::add(&stats::BYTES_OUT, payload_len(&msg));
+            if sink.send(msg).await.is_err() {
+                return;
             }
         }
+
+        let _ = sink.send(Message::Close(None)).await;
     });
 
-    let (tx, rx) = oneshot::channel();
-    let _ = handle.cmd_tx.send(RoomCmd::Snapshot { peer_id, tx }).await;
-    if let Ok(snapshot) = rx.await {
-        let _ = server_tx.send(ServerReply::Snapshot(snapshot)).await;
-    }
+    let mut limiter = Limiter::new(&state.config);
+    let mut resumable = true;
+    let mut flush = false;
+    loop {
+        let msg = tokio::select! {
+            msg = stream.next() => match msg {
+                Some(Ok(msg)) => msg,
+                _ => break,
+            },
+            _ = &mut sink_task => {
+                tracing::info!(%doc_id, %peer_id, "room closed the connection");
+                break;
+            }
+        };
 
-    while let Some(Ok(msg)) = stream.next().await {
-        match msg {
-            Message::Binary(bytes) if !bytes.is_empty() => {
-                let r: ServerRequest = serde_json::from_slice(&bytes).unwrap();
+        let frame = match msg {
+            Message::Binary(bytes) if !bytes.is_empty() => bytes,
+            Message::Text(text) if !text.is_empty() => text.into(),
+            Message::Close(_) => break,
+            Message::Ping(_) => {
+                let _ = handle.cmd_tx.send(RoomCmd::Heartbeat { peer_id }).await;
+                let _ = server_tx.send(ServerReply::PingPong).await;
+                continue;
+            }
+            Message::Pong(_) => {
+                let _ = handle.cmd_tx.send(RoomCmd::Heartbeat { peer_id }).await;
+                continue;
+            }
+            _ => continue,
+        };
+        stats::add(&stats::BYTES_IN, frame.len());
 
-                match r {
-                    _ => {
-                        // handle them all
-                    }
+        let checked = match serde_json::from_slice::<ServerRequest>(&frame) {
+            Ok(request) => match update_len(&request) {
+                Some(len) => {
+                    let document_update = is_document_update(&request);
+                    admit(&mut limiter, &handle, peer_id, len, document_update)
+                        .await
+                        .map(|()| request)
                 }
+                None => Ok(request),
+            },
+            Err(e) => {
+                limiter.strike();
+                Err(ServerError::MalformedRequest(e.to_string()))
+            }
+        };
+        let request = match checked {
+            Ok(request) => request,
+            Err(e) => {
+                tracing::warn!(%doc_id, %peer_id, "refused request: {}", e);
+                let _ = server_tx.send(ServerReply::Error(e)).await;
+                if limiter.exhausted() {
+                    tracing::warn!(%doc_id, %peer_id, "disconnecting client after repeated violations");
+                    stats::incr(&stats::ABUSE_DISCONNECTS);
+                    let _ = server_tx
+                        .send(ServerReply::Error(ServerError::TooManyViolations))
+                        .await;
+                    resumable = false;
+                    flush = true;
+                    break;
+                }
+                continue;
+            }
+        };
 
-                // match tag {
-                //     TAG_UPDATE => {
-                //         tracing::info!("Received UPDATE message");
-                //         let _ = handle
-                //             .cmd_tx
-                //             .send(RoomCmd::ClientUpdate {
-                //                 peer_id,
-                //                 bytes: payload.to_vec(),
-                //             })
-                //             .await;
-                //     }
-                //     TAG_AWARENESS => {
-                //         tracing::info!("Received AWARENESS message");
-                //         let _ = handle
-                //             .cmd_tx
-                //             .send(RoomCmd::ClientAwareness {
-                //                 peer_id,
-                //                 bytes: payload.to_vec(),
-                //             })
-                //             .await;
-                //     }
-                //     TAG_SNAPSHOT_REQ => {
-                //         tracing::info!("Received SNAPSHOT_REQ message");
-                //         let (tx, rx) = oneshot::channel();
-                //         let _ = handle.cmd_tx.send(RoomCmd::Snapshot { peer_id, tx }).await;
-                //         if let Ok(snapshot) = rx.await {
-                //             let _ = server_tx.send(ServerReply::Snapshot(snapshot)).await;
-                //         }
-                //     }
-                //     other => {
-                //         tracing::warn!("unknown tag: {}", other);
-                //     }
-                // }
+        match request {
+            ServerRequest::Update(bytes) => {
+                tracing::debug!(%doc_id, %peer_id, "received update");
+                let _ = handle
+                    .cmd_tx
+                    .send(RoomCmd::ClientUpdate {
+                        peer_id,
+                        bytes,
+                        version: UpdateVersion::V1,
+                    })
+                    .await;
             }
-            Message::Close(_) => break,
-            Message::Ping(_) => {
+            ServerRequest::UpdateV2(bytes) => {
+                tracing::debug!(%doc_id, %peer_id, "received v2 update");
+                let _ = handle
+                    .cmd_tx
+                    .send(RoomCmd::ClientUpdate {
+                        peer_id,
+                        bytes,
+                        version: UpdateVersion::V2,
+                    })
+                    .await;
+            }
+            ServerRequest::SyncStep1(state_vector) => {
+                tracing::debug!(%doc_id, %peer_id, "received sync step 1");
+                let _ = handle
+                    .cmd_tx
+                    .send(RoomCmd::SyncStep1 {
+                        peer_id,
+                        state_vector,
+                    })
+                    .await;
+            }
+            ServerRequest::SyncStep2(bytes) => {
+                tracing::debug!(%doc_id, %peer_id, "received sync step 2");
+                let _ = handle
+                    .cmd_tx
+                    .send(RoomCmd::ClientUpdate {
+                        peer_id,
+                        bytes,
+                        version: UpdateVersion::V1,
+                    })
+                    .await;
+            }
+            ServerRequest::Awareness(bytes) => {
+                tracing::debug!(%doc_id, %peer_id, "received awareness");
+                let _ = handle
+                    .cmd_tx
+                    .send(RoomCmd::ClientAwareness { peer_id, bytes })
+                    .await;
+            }
+            ServerRequest::Snapshot => {
+                tracing::debug!(%doc_id, %peer_id, "received snapshot request");
+                if let Some(snapshot) = request_snapshot(&handle, Some(peer_id)).await {
+                    let _ = server_tx.send(ServerReply::Snapshot(snapshot)).await;
+                }
+            }
+            ServerRequest::Ping => {
+                let _ = handle.cmd_tx.send(RoomCmd::Heartbeat { peer_id }).await;
                 let _ = server_tx.send(ServerReply::PingPong).await;
             }
-            _ => {}
+            ServerRequest::Leave => {
+                resumable = false;
+                break;
+            }
         }
     }
 
-    let _ = handle.cmd_tx.send(RoomCmd::Leave { peer_id }).await;
+    let _ = handle
+        .cmd_tx
+        .send(RoomCmd::Leave {
+            peer_id,
+            session,
+            resumable,
+        })
+        .await;
     tracing::info!(%doc_id, %peer_id, "websocket disconnected");
+    if flush {
+        // The sink finishes once the room drops the peer.
+        let _ = tokio::time::timeout(FLUSH_TIMEOUT, &mut sink_task).await;
+    }
     sink_task.abort();
 }
+
+/// Whether `request` carries changes to the document.
+fn is_document_update(request: &ServerRequest) -> bool {
+    matches!(
+        request,
+        ServerRequest::Update(_) | ServerRequest::UpdateV2(_) | ServerRequest::SyncStep2(_)
+    )
+}
+
+/// Size of the update carried by a request subject to the update limits.
+fn update_len(request: &ServerRequest) -> Option<usize> {
+    match request {
+        ServerRequest::Update(bytes)
+        | ServerRequest::UpdateV2(bytes)
+        | ServerRequest::SyncStep2(bytes)
+        | ServerRequest::Awareness(bytes) => Some(bytes.len()),
+        ServerRequest::SyncStep1(_)
+        | ServerRequest::Snapshot
+        | ServerRequest::Ping
+        | ServerRequest::Leave => None,
+    }
+}
+
+/// Holds a `len`-byte update from `peer_id` to the update limits, for
+/// either transport. Document updates past the rate wait for it;
+/// awareness updates past it are refused. Every refusal is a strike.
+///
+/// The client keeps a refused document update and builds on it, so the
+/// room is asked to sync with the client for whatever it lacks.
+pub(crate) async fn admit(
+    limiter: &mut Limiter,
+    handle: &RoomHandle,
+    peer_id: u64,
+    len: usize,
+    document_update: bool,
+) -> Result<(), ServerError> {
+    let admitted = if document_update {
+        limiter.admit(len).await
+    } else {
+        limiter.check(len)
+    };
+
+    if admitted.is_err() {
+        limiter.strike();
+        if document_update {
+            let _ = handle.cmd_tx.send(RoomCmd::RequestSync { peer_id }).await;
+        }
+    }
+    admitted
+}
+
+/// Size of the data carried by a WebSocket message, for the traffic
+/// counters.
+pub(crate) fn payload_len(msg: &Message) -> usize {
+    match msg {
+        Message::Text(text) => text.len(),
+        Message::Binary(bytes) | Message::Ping(bytes) | Message::Pong(bytes) => bytes.len(),
+        Message::Close(_) => 0,
+    }
+}
+
+/// Joins the room as `joiner`.
+///
+/// Returns the peer id and session the room assigned along with its
+/// `ServerReply::Join`, or `None` if the room went away or turned the
+/// peer away.
+pub(crate) async fn join_room(
+    handle: &RoomHandle,
+    joiner: Joiner,
+    tx: mpsc::Sender<ServerReply>,
+    rx: &mut mpsc::Receiver<ServerReply>,
+) -> Option<(u64, String, ServerReply)> {
+    let join = RoomCmd::Join {
+        peer_id: joiner.peer_id,
+        grant: joiner.grant,
+        name: joiner.name,
+        session: joiner.session,
+        tx,
+    };
+    if let Err(e) = handle.cmd_tx.send(join).await {
+        tracing::error!("failed to join room: {}", e);
+        return None;
+    }
+
+    let reply = rx.recv().await?;
+    let ServerReply::Join { id, session, .. } = &reply else {
+        tracing::error!("room answered join with {:?}", reply);
+        return None;
+    };
+
+    Some((*id, session.clone(), reply))
+}
+
+/// Whether the running room for `doc_id` would let a connection with
+/// `grant` resume `session`. Rooms that are not running hold no sessions.
+pub(crate) async fn holds_session(
+    state: &crate::state::AppState,
+    doc_id: &str,
+    session: &str,
+    grant: &Grant,
+) -> bool {
+    let Some(cmd_tx) = state.rooms.get(doc_id).map(|r| r.cmd_tx.clone()) else {
+        return false;
+    };
+
+    let (tx, rx) = oneshot::channel();
+    let query = RoomCmd::HoldsSession {
+        session: session.to_string(),
+        grant: grant.clone(),
+        tx,
+    };
+    if cmd_tx.send(query).await.is_err() {
+        return false;
+    }
+
+    rx.await.unwrap_or(false)
+}
+
+/// Asks the room for a snapshot of its document on behalf of `peer_id`.
+pub(crate) async fn request_snapshot(handle: &RoomHandle, peer_id: Option<u64>) -> Option<Vec<u8>> {
+    let (tx, rx) = oneshot::channel();
+    handle
+        .cmd_tx
+        .send(RoomCmd::Snapshot { peer_id, tx })
+        .await
+        .ok()?;
+
+    rx.await.ok()
+}
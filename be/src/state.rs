@@ -1,48 +1,350 @@
-use std::sync::Arc;
+use std::{
+    ops::Deref,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
 use axum::extract::ws::Message;
 use dashmap::DashMap;
 use tokio::sync::{mpsc, oneshot};
 
-use shared::server::ServerReply;
+use crate::{auth::Auth, config::Config, store::SharedStore};
+
+use shared::{
+    api::{Acl, ClientStats, Role},
+    server::ServerReply,
+};
+
+/// How often connections ping their client. Peers the room has not heard
+/// from in a few intervals are considered dead.
+pub(crate) const PING_INTERVAL: Duration = Duration::from_secs(15);
 
 #[derive(Clone)]
 pub(crate) struct AppState {
     pub(crate) rooms: Arc<DashMap<String, RoomHandle>>,
+    pub(crate) store: SharedStore,
+    pub(crate) config: Arc<Config>,
+    /// Verifies connection tokens; `None` when authentication is off.
+    pub(crate) auth: Option<Arc<Auth>>,
+    /// Set once the server has started shutting down; no new connections
+    /// are accepted after that.
+    pub(crate) shutting_down: Arc<AtomicBool>,
+}
+
+impl AppState {
+    /// Connects to the room for `doc_id`, spawning it (and loading its
+    /// document from storage) if it is not running. The room will not
+    /// hibernate while the returned guard is alive.
+    pub(crate) fn room(&self, doc_id: &str) -> RoomGuard {
+        // The connection is counted while the map entry is locked, so a
+        // room deciding to hibernate either sees it or is already gone.
+        let entry = self
+            .rooms
+            .entry(doc_id.to_string())
+            .or_insert_with(|| crate::room::spawn_room(doc_id.to_string(), self.clone()));
+        entry.connections.fetch_add(1, Ordering::SeqCst);
+
+        RoomGuard(entry.clone())
+    }
+
+    /// Removes the room for `doc_id` from the map if `handle` is still the
+    /// running room, whoever is connected to it. Used by a room that failed
+    /// to start; the next connection spawns a fresh one.
+    pub(crate) fn remove_room(&self, doc_id: &str, handle: &RoomHandle) {
+        self.rooms
+            .remove_if(doc_id, |_, current| current.is_same(handle));
+    }
+
+    /// Closes the room for `doc_id`, if one is running, telling its peers
+    /// `reason`. Returns once the room has persisted its document; the
+    /// next connection spawns a fresh room.
+    pub(crate) async fn close_room(&self, doc_id: &str, reason: &str) {
+        let Some((_, handle)) = self.rooms.remove(doc_id) else {
+            return;
+        };
+
+        let (ack, done) = oneshot::channel();
+        let reason = reason.to_string();
+        if handle
+            .cmd_tx
+            .send(RoomCmd::Shutdown { reason, ack })
+            .await
+            .is_ok()
+        {
+            let _ = done.await;
+        }
+    }
+
+    /// Removes the room for `doc_id` from the map if `handle` is still the
+    /// running room and nobody is connected to it. Returns whether it was
+    /// removed.
+    pub(crate) fn evict_idle(&self, doc_id: &str, handle: &RoomHandle) -> bool {
+        self.rooms
+            .remove_if(doc_id, |_, current| {
+                current.is_same(handle) && current.connections.load(Ordering::SeqCst) == 0
+            })
+            .is_some()
+    }
+
+    /// Asks every running room to disconnect its peers and persist its
+    /// document, waiting up to `deadline` for all of them to finish.
+    /// Returns whether they all did.
+    pub(crate) async fn shutdown(&self, deadline: Duration) -> bool {
+        self.shutting_down.store(true, Ordering::SeqCst);
+
+        let handles: Vec<_> = self
+            .rooms
+            .iter()
+            .map(|entry| (entry.key().clone(), entry.value().clone()))
+            .collect();
+        tracing::info!(rooms = handles.len(), "shutting down rooms");
+
+        let closed = handles.into_iter().map(|(doc_id, handle)| async move {
+            let (ack, done) = oneshot::channel();
+            let reason = "server restarting".to_string();
+            if handle
+                .cmd_tx
+                .send(RoomCmd::Shutdown { reason, ack })
+                .await
+                .is_err()
+            {
+                // The room already exited on its own.
+                return;
+            }
+            if done.await.is_err() {
+                tracing::warn!(%doc_id, "room exited without acknowledging shutdown");
+            }
+        });
+
+        tokio::time::timeout(deadline, futures::future::join_all(closed))
+            .await
+            .is_ok()
+    }
+}
+
+#[cfg(test)]
+impl AppState {
+    /// A server with no rooms running and an empty in-memory store.
+    pub(crate) fn for_tests(config: Config) -> Self {
+        Self {
+            rooms: Arc::new(DashMap::new()),
+            store: Arc::new(crate::store::MemStore::default()),
+            auth: config
+                .auth_secret
+                .as_deref()
+                .map(|secret| Arc::new(Auth::new(secret))),
+            config: Arc::new(config),
+            shutting_down: Arc::new(AtomicBool::new(false)),
+        }
+    }
 }
 
 #[derive(Clone)]
 pub(crate) struct RoomHandle {
     pub(crate) cmd_tx: mpsc::Sender<RoomCmd>,
+    /// Number of live `RoomGuard`s for this room.
+    connections: Arc<AtomicUsize>,
+    /// Number of peers in the room, published by the room for metrics.
+    peers: Arc<AtomicUsize>,
 }
 
 impl RoomHandle {
     pub(crate) fn new(cmd_tx: mpsc::Sender<RoomCmd>) -> Self {
-        Self { cmd_tx }
+        Self {
+            cmd_tx,
+            connections: Arc::new(AtomicUsize::new(0)),
+            peers: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    pub(crate) fn set_peer_count(&self, count: usize) {
+        self.peers.store(count, Ordering::Relaxed);
+    }
+
+    pub(crate) fn peer_count(&self) -> usize {
+        self.peers.load(Ordering::Relaxed)
+    }
+
+    /// Commands waiting in the room's queue.
+    pub(crate) fn queue_depth(&self) -> usize {
+        self.cmd_tx.max_capacity() - self.cmd_tx.capacity()
+    }
+
+    /// Whether both handles refer to the same room task.
+    pub(crate) fn is_same(&self, other: &RoomHandle) -> bool {
+        Arc::ptr_eq(&self.connections, &other.connections)
+    }
+}
+
+/// A connection to a room, handed out by `AppState::room`. Keeps the
+/// room from hibernating until it is dropped.
+pub(crate) struct RoomGuard(RoomHandle);
+
+impl Deref for RoomGuard {
+    type Target = RoomHandle;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl Drop for RoomGuard {
+    fn drop(&mut self) {
+        self.0.connections.fetch_sub(1, Ordering::SeqCst);
     }
 }
 
 #[derive(Debug)]
 pub(crate) enum RoomCmd {
+    /// Joins as the peer holding `session` if
+    /// [`Peers::resume`](crate::peers::Peers::resume) finds one for
+    /// `grant`, or as a new peer with id `peer_id` otherwise. The first
+    /// reply on `tx` is always `ServerReply::Join` with the id actually
+    /// assigned; if `grant` gives no access, `tx` is dropped instead.
     Join {
         peer_id: u64,
+        grant: Grant,
+        name: Option<String>,
+        session: Option<String>,
         tx: mpsc::Sender<ServerReply>,
     },
+    /// The connection using `session` closed. A `resumable` peer is held
+    /// for a grace window instead of leaving right away.
     Leave {
         peer_id: u64,
+        session: String,
+        resumable: bool,
     },
     ClientUpdate {
         peer_id: u64,
         bytes: Vec<u8>,
+        version: UpdateVersion,
     },
     ClientAwareness {
         peer_id: u64,
         bytes: Vec<u8>,
     },
-    Snapshot {
+    AwarenessQuery {
         peer_id: u64,
+    },
+    SyncStep1 {
+        peer_id: u64,
+        state_vector: Vec<u8>,
+    },
+    /// Encodes the whole document as a v1 update, for a peer or, with no
+    /// `peer_id`, for the REST API.
+    Snapshot {
+        peer_id: Option<u64>,
         tx: oneshot::Sender<Vec<u8>>,
     },
+    /// The peer answered a ping; it has no other effect.
+    Heartbeat {
+        peer_id: u64,
+    },
+    /// A document update from `peer_id` was refused before it reached the
+    /// room. The room answers with a SyncStep1 so the peer re-sends what
+    /// the room is missing.
+    RequestSync {
+        peer_id: u64,
+    },
+    /// Reports whether a connection with `grant` presenting `session`
+    /// would resume a peer rather than join as a new one.
+    HoldsSession {
+        session: String,
+        grant: Grant,
+        tx: oneshot::Sender<bool>,
+    },
+    /// Reports the room's peers for the stats API.
+    Stats {
+        tx: oneshot::Sender<Vec<ClientStats>>,
+    },
+    /// The document's ACL was replaced. Peers get their new roles, and
+    /// those it no longer lets in are disconnected.
+    SetAcl {
+        acl: Acl,
+    },
+    /// The share link `token` was revoked; peers that joined with it are
+    /// disconnected.
+    RevokeShare {
+        token: String,
+    },
+    /// Closes the room, telling its peers `reason`. `ack` is sent once
+    /// the document has been persisted.
+    Shutdown {
+        reason: String,
+        ack: oneshot::Sender<()>,
+    },
+}
+
+impl RoomCmd {
+    /// The peer that sent the command, for commands that prove it is
+    /// still alive.
+    pub(crate) fn peer_id(&self) -> Option<u64> {
+        match self {
+            RoomCmd::Join { .. }
+            | RoomCmd::Leave { .. }
+            | RoomCmd::HoldsSession { .. }
+            | RoomCmd::Stats { .. }
+            | RoomCmd::SetAcl { .. }
+            | RoomCmd::RevokeShare { .. }
+            | RoomCmd::Shutdown { .. } => None,
+            RoomCmd::ClientUpdate { peer_id, .. }
+            | RoomCmd::ClientAwareness { peer_id, .. }
+            | RoomCmd::AwarenessQuery { peer_id }
+            | RoomCmd::SyncStep1 { peer_id, .. }
+            | RoomCmd::RequestSync { peer_id }
+            | RoomCmd::Heartbeat { peer_id } => Some(*peer_id),
+            RoomCmd::Snapshot { peer_id, .. } => *peer_id,
+        }
+    }
+}
+
+/// How a peer got into a room, which decides its role.
+#[derive(Debug, Clone)]
+pub(crate) enum Grant {
+    /// Through the document's ACL, as an authenticated user or, with no
+    /// user, anonymously.
+    Acl(Option<String>),
+    /// Through a share link.
+    Share { token: String, role: Role },
+}
+
+impl Grant {
+    /// The role this grant gives under `acl`, or `None` for no access.
+    pub(crate) fn role(&self, acl: &Acl) -> Option<Role> {
+        match self {
+            Grant::Acl(user) => acl.role(user.as_deref()),
+            Grant::Share { role, .. } => Some(*role),
+        }
+    }
+
+    /// The authenticated user behind the grant, if any.
+    pub(crate) fn user(&self) -> Option<&str> {
+        match self {
+            Grant::Acl(user) => user.as_deref(),
+            Grant::Share { .. } => None,
+        }
+    }
+
+    /// Whether both grants were presented by the same holder: the same
+    /// user, the same share link, or both anonymous.
+    pub(crate) fn same_holder(&self, other: &Grant) -> bool {
+        match (self, other) {
+            (Grant::Acl(a), Grant::Acl(b)) => a == b,
+            (Grant::Share { token: a, .. }, Grant::Share { token: b, .. }) => a == b,
+            _ => false,
+        }
+    }
+}
+
+/// Encoding of the yrs update carried by `RoomCmd::ClientUpdate`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum UpdateVersion {
+    V1,
+    V2,
 }
 
 pub fn into_message(r: ServerReply) -> Message {
@@ -0,0 +1,123 @@
+//! Process-wide counters for things worth keeping an eye on.
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Instant,
+};
+
+/// Replies that could not be queued because a peer's channel was full.
+pub(crate) static DROPPED_SENDS: AtomicU64 = AtomicU64::new(0);
+/// Lagging peers brought back in sync with a state-vector diff.
+pub(crate) static RESYNCS: AtomicU64 = AtomicU64::new(0);
+/// Peers disconnected for lagging for too long.
+pub(crate) static SLOW_DISCONNECTS: AtomicU64 = AtomicU64::new(0);
+/// Peers disconnected for not answering heartbeats.
+pub(crate) static DEAD_DISCONNECTS: AtomicU64 = AtomicU64::new(0);
+/// Clients disconnected for having too many messages refused.
+pub(crate) static ABUSE_DISCONNECTS: AtomicU64 = AtomicU64::new(0);
+/// Client updates applied to a document.
+pub(crate) static UPDATES_APPLIED: AtomicU64 = AtomicU64::new(0);
+/// Client updates refused because they could not be decoded or applied.
+pub(crate) static UPDATES_REJECTED: AtomicU64 = AtomicU64::new(0);
+/// Payload bytes received from clients over WebSockets.
+pub(crate) static BYTES_IN: AtomicU64 = AtomicU64::new(0);
+/// Payload bytes sent to clients over WebSockets.
+pub(crate) static BYTES_OUT: AtomicU64 = AtomicU64::new(0);
+
+/// Time spent encoding full document snapshots.
+pub(crate) static SNAPSHOT_ENCODE: Histogram = Histogram::new();
+
+pub(crate) fn incr(counter: &AtomicU64) {
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+pub(crate) fn add(counter: &AtomicU64, n: usize) {
+    counter.fetch_add(n as u64, Ordering::Relaxed);
+}
+
+/// Runs `encode`, recording how long it took in `SNAPSHOT_ENCODE`.
+pub(crate) fn time_snapshot<T>(encode: impl FnOnce() -> T) -> T {
+    let start = Instant::now();
+    let snapshot = encode();
+    SNAPSHOT_ENCODE.observe(start.elapsed().as_secs_f64());
+    snapshot
+}
+
+/// Upper bounds, in seconds, of the `Histogram` buckets.
+pub(crate) const BUCKETS: [f64; 10] = [
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 1.0,
+];
+
+/// A fixed-bucket latency histogram in the shape Prometheus expects.
+pub(crate) struct Histogram {
+    /// Observations at or below each of `BUCKETS`, not cumulative.
+    buckets: [AtomicU64; BUCKETS.len()],
+    count: AtomicU64,
+    /// Sum of all observations, in microseconds.
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    const fn new() -> Self {
+        Self {
+            buckets: [const { AtomicU64::new(0) }; BUCKETS.len()],
+            count: AtomicU64::new(0),
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn observe(&self, secs: f64) {
+        if let Some(i) = BUCKETS.iter().position(|bound| secs <= *bound) {
+            self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add((secs * 1e6) as u64, Ordering::Relaxed);
+    }
+
+    /// Cumulative counts for each of `BUCKETS`, the total count and the
+    /// sum in seconds.
+    pub(crate) fn read(&self) -> ([u64; BUCKETS.len()], u64, f64) {
+        let mut cumulative = [0; BUCKETS.len()];
+        let mut total = 0;
+        for (i, bucket) in self.buckets.iter().enumerate() {
+            total += bucket.load(Ordering::Relaxed);
+            cumulative[i] = total;
+        }
+
+        (
+            cumulative,
+            self.count.load(Ordering::Relaxed),
+            self.sum_micros.load(Ordering::Relaxed) as f64 / 1e6,
+        )
+    }
+}
+
+/// A point-in-time copy of the counters, served on `/stats`.
+#[derive(Debug, serde::Serialize)]
+pub(crate) struct Stats {
+    pub(crate) dropped_sends: u64,
+    pub(crate) resyncs: u64,
+    pub(crate) slow_disconnects: u64,
+    pub(crate) dead_disconnects: u64,
+    pub(crate) abuse_disconnects: u64,
+    pub(crate) updates_applied: u64,
+    pub(crate) updates_rejected: u64,
+    pub(crate) bytes_in: u64,
+    pub(crate) bytes_out: u64,
+}
+
+impl Stats {
+    pub(crate) fn read() -> Self {
+        Self {
+            dropped_sends: DROPPED_SENDS.load(Ordering::Relaxed),
+            resyncs: RESYNCS.load(Ordering::Relaxed),
+            slow_disconnects: SLOW_DISCONNECTS.load(Ordering::Relaxed),
+            dead_disconnects: DEAD_DISCONNECTS.load(Ordering::Relaxed),
+            abuse_disconnects: ABUSE_DISCONNECTS.load(Ordering::Relaxed),
+            updates_applied: UPDATES_APPLIED.load(Ordering::Relaxed),
+            updates_rejected: UPDATES_REJECTED.load(Ordering::Relaxed),
+            bytes_in: BYTES_IN.load(Ordering::Relaxed),
+            bytes_out: BYTES_OUT.load(Ordering::Relaxed),
+        }
+    }
+}
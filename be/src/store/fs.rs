@@ -0,0 +1,361 @@
+use std::{
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use dashmap::DashMap;
+
+use shared::api::{Acl, ScreenplayMeta, ShareLink};
+
+use super::{DocumentStore, StoreError, StoredDoc};
+
+/// Size of the `seq` and `len` header in front of every log entry.
+const ENTRY_HEADER_LEN: usize = 12;
+
+/// Stores each document as a `<doc_id>.ydoc` snapshot file and a
+/// `<doc_id>.ylog` update log in a directory, with its metadata and ACL
+/// as JSON in `<doc_id>.meta` and `<doc_id>.acl`. Share links are JSON
+/// files named after their token in a `shares` subdirectory.
+///
+/// Log entries are a little-endian `u64` sequence number and `u32`
+/// length followed by the update bytes.
+pub(crate) struct FsStore {
+    root: PathBuf,
+    /// Serializes appends and compactions of the same document.
+    locks: DashMap<String, Arc<Mutex<()>>>,
+    /// Serializes share link redemptions.
+    shares: Mutex<()>,
+}
+
+impl FsStore {
+    /// Opens the store rooted at `root`, creating the directory if needed.
+    pub(crate) fn open(root: impl AsRef<Path>) -> Result<Self, StoreError> {
+        let root = root.as_ref().to_path_buf();
+        fs::create_dir_all(root.join("shares"))?;
+
+        Ok(Self {
+            root,
+            locks: DashMap::new(),
+            shares: Mutex::new(()),
+        })
+    }
+
+    fn path(&self, doc_id: &str, extension: &str) -> PathBuf {
+        self.root
+            .join(format!("{}.{}", file_stem(doc_id), extension))
+    }
+
+    fn share_path(&self, token: &str) -> PathBuf {
+        self.root
+            .join("shares")
+            .join(format!("{}.json", file_stem(token)))
+    }
+
+    fn lock(&self, doc_id: &str) -> Arc<Mutex<()>> {
+        self.locks.entry(doc_id.to_string()).or_default().clone()
+    }
+}
+
+impl DocumentStore for FsStore {
+    fn load(&self, doc_id: &str) -> Result<Option<StoredDoc>, StoreError> {
+        let lock = self.lock(doc_id);
+        let _guard = lock.lock().unwrap_or_else(|e| e.into_inner());
+
+        let snapshot = read_optional(&self.path(doc_id, "ydoc"))?;
+        let log = read_optional(&self.path(doc_id, "ylog"))?;
+        if snapshot.is_none() && log.is_none() {
+            return Ok(None);
+        }
+
+        let mut updates = Vec::new();
+        if let Some(log) = log {
+            let (entries, complete) = parse_log(&log);
+            if complete < log.len() {
+                // Cut the torn entry off now: an append after it would
+                // be read as part of it and lost on the next load.
+                tracing::warn!(%doc_id, "truncating torn entry at the end of the update log");
+                fs::OpenOptions::new()
+                    .write(true)
+                    .open(self.path(doc_id, "ylog"))?
+                    .set_len(complete as u64)?;
+            }
+            updates = entries;
+        }
+
+        Ok(Some(StoredDoc { snapshot, updates }))
+    }
+
+    fn append(&self, doc_id: &str, seq: u64, update: &[u8]) -> Result<(), StoreError> {
+        let lock = self.lock(doc_id);
+        let _guard = lock.lock().unwrap_or_else(|e| e.into_inner());
+
+        let mut entry = Vec::with_capacity(ENTRY_HEADER_LEN + update.len());
+        write_entry(&mut entry, seq, update);
+
+        let mut log = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.path(doc_id, "ylog"))?;
+        log.write_all(&entry)?;
+
+        Ok(())
+    }
+
+    fn compact(&self, doc_id: &str, state: &[u8], seq: u64) -> Result<(), StoreError> {
+        let lock = self.lock(doc_id);
+        let _guard = lock.lock().unwrap_or_else(|e| e.into_inner());
+
+        // The snapshot goes first: if we crash before the log is rewritten
+        // the old entries are replayed on top of it, which is harmless
+        // because applying a yrs update twice is a no-op.
+        write_atomic(&self.path(doc_id, "ydoc"), state)?;
+
+        let log_path = self.path(doc_id, "ylog");
+        let Some(log) = read_optional(&log_path)? else {
+            return Ok(());
+        };
+
+        let mut kept = Vec::new();
+        for (entry_seq, update) in parse_log(&log).0 {
+            if entry_seq > seq {
+                write_entry(&mut kept, entry_seq, &update);
+            }
+        }
+        write_atomic(&log_path, &kept)?;
+
+        Ok(())
+    }
+
+    fn list_meta(&self) -> Result<Vec<ScreenplayMeta>, StoreError> {
+        let mut metas = Vec::new();
+        for entry in fs::read_dir(&self.root)? {
+            let path = entry?.path();
+            if path.extension().is_none_or(|ext| ext != "meta") {
+                continue;
+            }
+
+            match parse_json(&fs::read(&path)?) {
+                Ok(meta) => metas.push(meta),
+                Err(e) => tracing::warn!(path = %path.display(), "skipping metadata: {}", e),
+            }
+        }
+
+        Ok(metas)
+    }
+
+    fn load_meta(&self, doc_id: &str) -> Result<Option<ScreenplayMeta>, StoreError> {
+        read_optional(&self.path(doc_id, "meta"))?
+            .map(|bytes| parse_json(&bytes))
+            .transpose()
+    }
+
+    fn save_meta(&self, meta: &ScreenplayMeta) -> Result<(), StoreError> {
+        let bytes = serde_json::to_vec(meta).map_err(|e| StoreError::Corrupt(e.to_string()))?;
+        write_atomic(&self.path(&meta.id, "meta"), &bytes)
+    }
+
+    fn load_acl(&self, doc_id: &str) -> Result<Option<Acl>, StoreError> {
+        read_optional(&self.path(doc_id, "acl"))?
+            .map(|bytes| parse_json(&bytes))
+            .transpose()
+    }
+
+    fn save_acl(&self, doc_id: &str, acl: &Acl) -> Result<(), StoreError> {
+        let bytes = serde_json::to_vec(acl).map_err(|e| StoreError::Corrupt(e.to_string()))?;
+        write_atomic(&self.path(doc_id, "acl"), &bytes)
+    }
+
+    fn save_share(&self, link: &ShareLink) -> Result<(), StoreError> {
+        let bytes = serde_json::to_vec(link).map_err(|e| StoreError::Corrupt(e.to_string()))?;
+        write_atomic(&self.share_path(&link.token), &bytes)
+    }
+
+    fn load_share(&self, token: &str) -> Result<Option<ShareLink>, StoreError> {
+        read_optional(&self.share_path(token))?
+            .map(|bytes| parse_json(&bytes))
+            .transpose()
+    }
+
+    fn list_shares(&self, doc_id: &str) -> Result<Vec<ShareLink>, StoreError> {
+        let mut links = Vec::new();
+        for entry in fs::read_dir(self.root.join("shares"))? {
+            let path = entry?.path();
+            if path.extension().is_none_or(|ext| ext != "json") {
+                continue;
+            }
+
+            match parse_json::<ShareLink>(&fs::read(&path)?) {
+                Ok(link) if link.doc_id == doc_id => links.push(link),
+                Ok(_) => {}
+                Err(e) => tracing::warn!(path = %path.display(), "skipping share link: {}", e),
+            }
+        }
+
+        Ok(links)
+    }
+
+    fn delete_share(&self, token: &str) -> Result<bool, StoreError> {
+        let _guard = self.shares.lock().unwrap_or_else(|e| e.into_inner());
+        match fs::remove_file(self.share_path(token)) {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn redeem_share(&self, token: &str, now: u64) -> Result<Option<ShareLink>, StoreError> {
+        let _guard = self.shares.lock().unwrap_or_else(|e| e.into_inner());
+        let Some(mut link) = self.load_share(token)? else {
+            return Ok(None);
+        };
+        if !link.is_valid(now) {
+            return Ok(None);
+        }
+
+        link.uses += 1;
+        self.save_share(&link)?;
+
+        Ok(Some(link))
+    }
+}
+
+fn parse_json<T: serde::de::DeserializeOwned>(bytes: &[u8]) -> Result<T, StoreError> {
+    serde_json::from_slice(bytes).map_err(|e| StoreError::Corrupt(e.to_string()))
+}
+
+fn read_optional(path: &Path) -> Result<Option<Vec<u8>>, StoreError> {
+    match fs::read(path) {
+        Ok(bytes) => Ok(Some(bytes)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Writes to a temporary file first so a crash mid-write never leaves
+/// a truncated file behind. The file is synced before it replaces the
+/// old one and the directory after, so the rename cannot reach the disk
+/// ahead of the contents. Every write gets a temporary file of its own.
+fn write_atomic(path: &Path, bytes: &[u8]) -> Result<(), StoreError> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(format!(".{:016x}.tmp", rand::random::<u64>()));
+    let tmp = PathBuf::from(tmp);
+
+    let written = (|| {
+        let mut file = fs::File::create(&tmp)?;
+        file.write_all(bytes)?;
+        file.sync_all()?;
+        fs::rename(&tmp, path)
+    })();
+    if let Err(e) = written {
+        let _ = fs::remove_file(&tmp);
+        return Err(e.into());
+    }
+
+    if let Some(dir) = path.parent() {
+        fs::File::open(dir)?.sync_all()?;
+    }
+
+    Ok(())
+}
+
+fn write_entry(buf: &mut Vec<u8>, seq: u64, update: &[u8]) {
+    buf.extend_from_slice(&seq.to_le_bytes());
+    buf.extend_from_slice(&(update.len() as u32).to_le_bytes());
+    buf.extend_from_slice(update);
+}
+
+/// Reads every complete entry of a log, along with the length of the
+/// prefix they fill. Anything after it is a torn entry left by a crash
+/// mid-append.
+fn parse_log(mut log: &[u8]) -> (Vec<(u64, Vec<u8>)>, usize) {
+    let mut entries = Vec::new();
+    let mut complete = 0;
+    while log.len() >= ENTRY_HEADER_LEN {
+        let (header, rest) = log.split_at(ENTRY_HEADER_LEN);
+        let seq = u64::from_le_bytes(header[..8].try_into().unwrap());
+        let len = u32::from_le_bytes(header[8..].try_into().unwrap()) as usize;
+        if rest.len() < len {
+            break;
+        }
+
+        let (update, rest) = rest.split_at(len);
+        entries.push((seq, update.to_vec()));
+        complete += ENTRY_HEADER_LEN + len;
+        log = rest;
+    }
+
+    (entries, complete)
+}
+
+/// Escapes `doc_id` so it is safe to use as a file name. Anything other
+/// than ASCII alphanumerics, `-` and `_` is written as `%XX`.
+fn file_stem(doc_id: &str) -> String {
+    let mut stem = String::with_capacity(doc_id.len());
+    for b in doc_id.bytes() {
+        if b.is_ascii_alphanumeric() || b == b'-' || b == b'_' {
+            stem.push(b as char);
+        } else {
+            stem.push_str(&format!("%{b:02X}"));
+        }
+    }
+
+    stem
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::TempPath;
+
+    #[test]
+    fn recovers_from_a_torn_log() {
+        let root = TempPath::new("data");
+        let store = FsStore::open(&*root).unwrap();
+        store.append("doc", 1, b"one").unwrap();
+
+        // A crash mid-append leaves half an entry behind.
+        let mut torn = Vec::new();
+        write_entry(&mut torn, 2, b"lost in the crash");
+        let mut log = fs::OpenOptions::new()
+            .append(true)
+            .open(store.path("doc", "ylog"))
+            .unwrap();
+        log.write_all(&torn[..torn.len() - 4]).unwrap();
+
+        let doc = store.load("doc").unwrap().unwrap();
+        assert_eq!(doc.updates, [(1, b"one".to_vec())]);
+
+        store.append("doc", 2, b"two").unwrap();
+        let doc = store.load("doc").unwrap().unwrap();
+        assert_eq!(doc.updates, [(1, b"one".to_vec()), (2, b"two".to_vec())]);
+    }
+
+    #[test]
+    fn concurrent_writes_keep_their_own_temporary_files() {
+        let root = TempPath::new("data");
+        fs::create_dir_all(&*root).unwrap();
+        let path = root.join("doc.ydoc");
+
+        std::thread::scope(|scope| {
+            for i in 0..8u8 {
+                let path = &path;
+                scope.spawn(move || {
+                    for _ in 0..50 {
+                        write_atomic(path, &[i; 4096]).unwrap();
+                    }
+                });
+            }
+        });
+
+        let written = fs::read(&path).unwrap();
+        assert_eq!(written.len(), 4096);
+        assert!(written.iter().all(|b| *b == written[0]));
+        assert_eq!(
+            fs::read_dir(&*root).unwrap().count(),
+            1,
+            "temporary files left"
+        );
+    }
+}
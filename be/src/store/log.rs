@@ -0,0 +1,157 @@
+use tokio::task::JoinHandle;
+use yrs::{Doc, ReadTxn, StateVector, Transact};
+
+use super::{SharedStore, StoreError, StoredDoc};
+
+/// Compact once this many updates have been logged since the last snapshot.
+const COMPACT_AFTER_UPDATES: usize = 256;
+/// Compact once the updates logged since the last snapshot reach this size.
+const COMPACT_AFTER_BYTES: usize = 1 << 20;
+
+/// A room's handle on the update log of its document.
+///
+/// Every applied update is appended to the log, which keeps the write
+/// path cheap. When the log passes a size or count threshold the state
+/// of the document is written as a new snapshot in the background and
+/// the entries it covers are dropped.
+pub(crate) struct UpdateLog {
+    store: SharedStore,
+    doc_id: String,
+    /// Sequence number of the last appended update.
+    seq: u64,
+    /// Updates and bytes logged since the last compaction was started.
+    pending: usize,
+    pending_bytes: usize,
+    /// Size of the last snapshot taken or loaded.
+    snapshot_bytes: usize,
+    compaction: Option<JoinHandle<()>>,
+}
+
+impl UpdateLog {
+    /// Reads the stored state of `doc_id`, returning the log positioned
+    /// after its last entry along with whatever was stored.
+    ///
+    /// Fails if the store could not be read. The caller must not append
+    /// to or compact a document it failed to load: the new entries would
+    /// overwrite the ones it could not read.
+    pub(crate) async fn load(
+        store: SharedStore,
+        doc_id: &str,
+    ) -> Result<(Self, Option<StoredDoc>), StoreError> {
+        let loaded = {
+            let store = store.clone();
+            let doc_id = doc_id.to_string();
+            tokio::task::spawn_blocking(move || store.load(&doc_id)).await
+        };
+
+        let stored = match loaded {
+            Ok(loaded) => loaded?,
+            Err(e) => return Err(StoreError::Io(std::io::Error::other(e))),
+        };
+
+        let updates = stored
+            .as_ref()
+            .map(|s| s.updates.as_slice())
+            .unwrap_or_default();
+        let log = Self {
+            store,
+            doc_id: doc_id.to_string(),
+            seq: updates.last().map(|(seq, _)| *seq).unwrap_or_default(),
+            pending: updates.len(),
+            pending_bytes: updates.iter().map(|(_, u)| u.len()).sum(),
+            snapshot_bytes: stored
+                .as_ref()
+                .and_then(|s| s.snapshot.as_ref())
+                .map_or(0, Vec::len),
+            compaction: None,
+        };
+
+        Ok((log, stored))
+    }
+
+    /// Appends an applied update to the log.
+    pub(crate) async fn append(&mut self, update: &[u8]) {
+        self.seq += 1;
+        self.pending += 1;
+        self.pending_bytes += update.len();
+
+        let appended = {
+            let store = self.store.clone();
+            let doc_id = self.doc_id.clone();
+            let seq = self.seq;
+            let update = update.to_vec();
+            tokio::task::spawn_blocking(move || store.append(&doc_id, seq, &update)).await
+        };
+
+        match appended {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => tracing::error!(doc_id = %self.doc_id, "failed to append update: {}", e),
+            Err(e) => tracing::error!(doc_id = %self.doc_id, "update append task failed: {}", e),
+        }
+    }
+
+    /// Roughly how large the document is encoded: its last snapshot plus
+    /// every update logged since. Updates that overwrite each other make
+    /// this an overestimate until the next compaction.
+    pub(crate) fn size(&self) -> usize {
+        self.snapshot_bytes + self.pending_bytes
+    }
+
+    /// Starts a background compaction if the log has grown past its
+    /// thresholds and no compaction is already running.
+    pub(crate) fn maybe_compact(&mut self, doc: &Doc) {
+        if self.pending < COMPACT_AFTER_UPDATES && self.pending_bytes < COMPACT_AFTER_BYTES {
+            return;
+        }
+
+        if self.compaction.as_ref().is_some_and(|c| !c.is_finished()) {
+            return;
+        }
+
+        tracing::info!(doc_id = %self.doc_id, seq = self.seq, "compacting update log");
+        let (store, doc_id, state, seq) = self.prepare_compaction(doc);
+        self.compaction = Some(tokio::task::spawn_blocking(move || {
+            if let Err(e) = store.compact(&doc_id, &state, seq) {
+                tracing::error!(%doc_id, "failed to compact update log: {}", e);
+            }
+        }));
+    }
+
+    /// Snapshots the document and empties the log, waiting for any
+    /// background compaction to finish first. Does nothing if no update
+    /// has been logged since the last compaction.
+    pub(crate) async fn compact(&mut self, doc: &Doc) {
+        if let Some(compaction) = self.compaction.take() {
+            let _ = compaction.await;
+        }
+
+        if self.pending == 0 {
+            return;
+        }
+
+        let (store, doc_id, state, seq) = self.prepare_compaction(doc);
+        let compacted = {
+            let doc_id = doc_id.clone();
+            tokio::task::spawn_blocking(move || store.compact(&doc_id, &state, seq)).await
+        };
+
+        match compacted {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => tracing::error!(%doc_id, "failed to compact update log: {}", e),
+            Err(e) => tracing::error!(%doc_id, "update log compaction task failed: {}", e),
+        }
+    }
+
+    fn prepare_compaction(&mut self, doc: &Doc) -> (SharedStore, String, Vec<u8>, u64) {
+        let state = crate::stats::time_snapshot(|| {
+            doc.transact()
+                .encode_state_as_update_v1(&StateVector::default())
+        });
+
+        self.pending = 0;
+        self.pending_bytes = 0;
+        self.snapshot_bytes = state.len();
+
+        (self.store.clone(), self.doc_id.clone(), state, self.seq)
+    }
+}
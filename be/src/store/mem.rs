@@ -0,0 +1,102 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use shared::api::{Acl, ScreenplayMeta, ShareLink};
+
+use super::{DocumentStore, StoreError, StoredDoc};
+
+/// Keeps everything in memory, for tests that drive rooms and the API
+/// without touching the disk.
+#[derive(Default)]
+pub(crate) struct MemStore {
+    docs: Mutex<HashMap<String, StoredDoc>>,
+    meta: Mutex<HashMap<String, ScreenplayMeta>>,
+    acls: Mutex<HashMap<String, Acl>>,
+    shares: Mutex<HashMap<String, ShareLink>>,
+}
+
+impl DocumentStore for MemStore {
+    fn load(&self, doc_id: &str) -> Result<Option<StoredDoc>, StoreError> {
+        Ok(self.docs.lock().unwrap().get(doc_id).map(|doc| StoredDoc {
+            snapshot: doc.snapshot.clone(),
+            updates: doc.updates.clone(),
+        }))
+    }
+
+    fn append(&self, doc_id: &str, seq: u64, update: &[u8]) -> Result<(), StoreError> {
+        let mut docs = self.docs.lock().unwrap();
+        let doc = docs.entry(doc_id.to_string()).or_default();
+        doc.updates.push((seq, update.to_vec()));
+        Ok(())
+    }
+
+    fn compact(&self, doc_id: &str, state: &[u8], seq: u64) -> Result<(), StoreError> {
+        let mut docs = self.docs.lock().unwrap();
+        let doc = docs.entry(doc_id.to_string()).or_default();
+        doc.snapshot = Some(state.to_vec());
+        doc.updates.retain(|(s, _)| *s > seq);
+        Ok(())
+    }
+
+    fn list_meta(&self) -> Result<Vec<ScreenplayMeta>, StoreError> {
+        Ok(self.meta.lock().unwrap().values().cloned().collect())
+    }
+
+    fn load_meta(&self, doc_id: &str) -> Result<Option<ScreenplayMeta>, StoreError> {
+        Ok(self.meta.lock().unwrap().get(doc_id).cloned())
+    }
+
+    fn save_meta(&self, meta: &ScreenplayMeta) -> Result<(), StoreError> {
+        self.meta
+            .lock()
+            .unwrap()
+            .insert(meta.id.clone(), meta.clone());
+        Ok(())
+    }
+
+    fn load_acl(&self, doc_id: &str) -> Result<Option<Acl>, StoreError> {
+        Ok(self.acls.lock().unwrap().get(doc_id).cloned())
+    }
+
+    fn save_acl(&self, doc_id: &str, acl: &Acl) -> Result<(), StoreError> {
+        self.acls
+            .lock()
+            .unwrap()
+            .insert(doc_id.to_string(), acl.clone());
+        Ok(())
+    }
+
+    fn save_share(&self, link: &ShareLink) -> Result<(), StoreError> {
+        self.shares
+            .lock()
+            .unwrap()
+            .insert(link.token.clone(), link.clone());
+        Ok(())
+    }
+
+    fn load_share(&self, token: &str) -> Result<Option<ShareLink>, StoreError> {
+        Ok(self.shares.lock().unwrap().get(token).cloned())
+    }
+
+    fn list_shares(&self, doc_id: &str) -> Result<Vec<ShareLink>, StoreError> {
+        let shares = self.shares.lock().unwrap();
+        Ok(shares
+            .values()
+            .filter(|link| link.doc_id == doc_id)
+            .cloned()
+            .collect())
+    }
+
+    fn delete_share(&self, token: &str) -> Result<bool, StoreError> {
+        Ok(self.shares.lock().unwrap().remove(token).is_some())
+    }
+
+    fn redeem_share(&self, token: &str, now: u64) -> Result<Option<ShareLink>, StoreError> {
+        let mut shares = self.shares.lock().unwrap();
+        let Some(link) = shares.get_mut(token).filter(|link| link.is_valid(now)) else {
+            return Ok(None);
+        };
+
+        link.uses += 1;
+        Ok(Some(link.clone()))
+    }
+}
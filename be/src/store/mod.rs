@@ -0,0 +1,266 @@
+//! Persistent document storage.
+//!
+//! Rooms load their document from a [`DocumentStore`] when they are
+//! spawned and append every applied update to a per-document log, so
+//! screenplays survive a server restart. Once the log grows past a
+//! threshold the room compacts it into a single snapshot. The filesystem
+//! backend is always available; the SQLite backend is enabled with the
+//! `sqlite` cargo feature.
+//!
+//! Screenplays created through the REST API also have a small metadata
+//! record (title, timestamps, deletion) kept next to the document, and
+//! any document can have an ACL saying who may open and edit it, and
+//! share links handing out access to people without an account.
+#[cfg(test)]
+use std::ops::Deref;
+use std::{path::Path, str::FromStr, sync::Arc};
+
+mod fs;
+mod log;
+#[cfg(test)]
+mod mem;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+
+use shared::api::{Acl, ScreenplayMeta, ShareLink};
+
+pub(crate) use fs::FsStore;
+pub(crate) use log::UpdateLog;
+#[cfg(test)]
+pub(crate) use mem::MemStore;
+#[cfg(feature = "sqlite")]
+pub(crate) use sqlite::SqliteStore;
+
+/// Storage for every document, keyed by doc id. A document is a snapshot
+/// plus an append-only log of the updates applied since it was taken.
+///
+/// Implementations are blocking; the room actor calls them from
+/// `tokio::task::spawn_blocking`. `append` and `compact` may run
+/// concurrently for the same document.
+pub(crate) trait DocumentStore: Send + Sync + 'static {
+    /// Returns the snapshot and logged updates of `doc_id`, or `None` if
+    /// nothing has ever been stored for it.
+    fn load(&self, doc_id: &str) -> Result<Option<StoredDoc>, StoreError>;
+
+    /// Appends `update`, a yrs v1 update, to the log of `doc_id` under
+    /// the sequence number `seq`.
+    fn append(&self, doc_id: &str, seq: u64, update: &[u8]) -> Result<(), StoreError>;
+
+    /// Replaces the snapshot of `doc_id` with `state`, a yrs v1 update,
+    /// and drops every logged update with a sequence number up to and
+    /// including `seq`.
+    fn compact(&self, doc_id: &str, state: &[u8], seq: u64) -> Result<(), StoreError>;
+
+    /// Returns the metadata of every screenplay, deleted ones included.
+    fn list_meta(&self) -> Result<Vec<ScreenplayMeta>, StoreError>;
+
+    /// Returns the metadata of `doc_id`, or `None` if it was never
+    /// created through the API.
+    fn load_meta(&self, doc_id: &str) -> Result<Option<ScreenplayMeta>, StoreError>;
+
+    /// Creates or replaces the metadata of `meta.id`.
+    fn save_meta(&self, meta: &ScreenplayMeta) -> Result<(), StoreError>;
+
+    /// Returns the ACL of `doc_id`, or `None` if it never had one.
+    fn load_acl(&self, doc_id: &str) -> Result<Option<Acl>, StoreError>;
+
+    /// Creates or replaces the ACL of `doc_id`.
+    fn save_acl(&self, doc_id: &str, acl: &Acl) -> Result<(), StoreError>;
+
+    /// Stores a new share link.
+    fn save_share(&self, link: &ShareLink) -> Result<(), StoreError>;
+
+    /// Returns the share link `token`, valid or not.
+    fn load_share(&self, token: &str) -> Result<Option<ShareLink>, StoreError>;
+
+    /// Returns every share link of `doc_id`, expired ones included.
+    fn list_shares(&self, doc_id: &str) -> Result<Vec<ShareLink>, StoreError>;
+
+    /// Deletes the share link `token`. Returns whether it existed.
+    fn delete_share(&self, token: &str) -> Result<bool, StoreError>;
+
+    /// Counts a use of the share link `token` and returns it, or `None`
+    /// if there is no such link or it is no longer valid at `now`. Two
+    /// concurrent calls never both take a link's last use.
+    fn redeem_share(&self, token: &str, now: u64) -> Result<Option<ShareLink>, StoreError>;
+}
+
+/// A document as it was read back from a [`DocumentStore`].
+#[derive(Debug, Default)]
+pub(crate) struct StoredDoc {
+    /// The last compacted state, as a yrs v1 update.
+    pub(crate) snapshot: Option<Vec<u8>>,
+    /// Updates logged after the snapshot, in sequence order.
+    pub(crate) updates: Vec<(u64, Vec<u8>)>,
+}
+
+pub(crate) type SharedStore = Arc<dyn DocumentStore>;
+
+/// The storage backends compiled into this binary.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Backend {
+    Fs,
+    #[cfg(feature = "sqlite")]
+    Sqlite,
+}
+
+impl Backend {
+    /// Opens this backend at `path`: a directory for `Fs`, a database
+    /// file for `Sqlite`.
+    pub(crate) fn open(self, path: impl AsRef<Path>) -> Result<SharedStore, StoreError> {
+        Ok(match self {
+            Backend::Fs => Arc::new(FsStore::open(path)?),
+            #[cfg(feature = "sqlite")]
+            Backend::Sqlite => Arc::new(SqliteStore::open(path)?),
+        })
+    }
+
+    /// Where the backend keeps its data when no path is configured.
+    pub(crate) fn default_path(self) -> &'static str {
+        match self {
+            Backend::Fs => "data",
+            #[cfg(feature = "sqlite")]
+            Backend::Sqlite => "prosia.db",
+        }
+    }
+}
+
+impl FromStr for Backend {
+    type Err = StoreError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fs" => Ok(Backend::Fs),
+            #[cfg(feature = "sqlite")]
+            "sqlite" => Ok(Backend::Sqlite),
+            other => Err(StoreError::UnknownBackend(other.to_string())),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) enum StoreError {
+    UnknownBackend(String),
+    Io(std::io::Error),
+    /// Stored data could not be decoded.
+    Corrupt(String),
+    #[cfg(feature = "sqlite")]
+    Sqlite(rusqlite::Error),
+}
+
+impl core::fmt::Display for StoreError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            StoreError::UnknownBackend(name) => write!(f, "unknown storage backend: {name}"),
+            StoreError::Io(e) => write!(f, "io error: {e}"),
+            StoreError::Corrupt(e) => write!(f, "corrupt data: {e}"),
+            #[cfg(feature = "sqlite")]
+            StoreError::Sqlite(e) => write!(f, "sqlite error: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for StoreError {}
+
+impl From<std::io::Error> for StoreError {
+    fn from(e: std::io::Error) -> Self {
+        StoreError::Io(e)
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl From<rusqlite::Error> for StoreError {
+    fn from(e: rusqlite::Error) -> Self {
+        StoreError::Sqlite(e)
+    }
+}
+
+/// A scratch path under the system temp directory, removed on drop.
+#[cfg(test)]
+pub(crate) struct TempPath(std::path::PathBuf);
+
+#[cfg(test)]
+impl TempPath {
+    pub(crate) fn new(name: &str) -> Self {
+        static NEXT: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+        let n = NEXT.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        let dir = std::env::temp_dir().join(format!("prosia-{}-{n}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        TempPath(dir.join(name))
+    }
+}
+
+#[cfg(test)]
+impl Deref for TempPath {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        if let Some(dir) = self.0.parent() {
+            let _ = std::fs::remove_dir_all(dir);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn updates(doc: &StoredDoc) -> Vec<(u64, &[u8])> {
+        doc.updates
+            .iter()
+            .map(|(seq, u)| (*seq, u.as_slice()))
+            .collect()
+    }
+
+    fn assert_round_trip(store: &dyn DocumentStore) {
+        assert!(store.load("doc").unwrap().is_none());
+
+        store.append("doc", 1, b"one").unwrap();
+        store.append("doc", 2, b"two").unwrap();
+        store.append("doc", 3, b"three").unwrap();
+        let doc = store.load("doc").unwrap().unwrap();
+        assert_eq!(doc.snapshot, None);
+        assert_eq!(
+            updates(&doc),
+            [(1, &b"one"[..]), (2, b"two"), (3, b"three")]
+        );
+
+        store.compact("doc", b"snapshot", 2).unwrap();
+        store.append("doc", 4, b"four").unwrap();
+        let doc = store.load("doc").unwrap().unwrap();
+        assert_eq!(doc.snapshot.as_deref(), Some(&b"snapshot"[..]));
+        assert_eq!(updates(&doc), [(3, &b"three"[..]), (4, b"four")]);
+
+        store.compact("doc", b"final", 4).unwrap();
+        let doc = store.load("doc").unwrap().unwrap();
+        assert_eq!(doc.snapshot.as_deref(), Some(&b"final"[..]));
+        assert!(doc.updates.is_empty());
+
+        assert!(store.load("other").unwrap().is_none());
+    }
+
+    #[test]
+    fn mem_store_round_trips() {
+        assert_round_trip(&MemStore::default());
+    }
+
+    #[test]
+    fn fs_store_round_trips() {
+        let path = TempPath::new("data");
+        assert_round_trip(&FsStore::open(&*path).unwrap());
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn sqlite_store_round_trips() {
+        let path = TempPath::new("prosia.db");
+        assert_round_trip(&SqliteStore::open(&*path).unwrap());
+    }
+}
@@ -0,0 +1,287 @@
+use std::{path::Path, sync::Mutex};
+
+use rusqlite::{params, Connection, OptionalExtension};
+
+use shared::api::{Acl, Role, ScreenplayMeta, ShareLink};
+
+use super::{DocumentStore, StoreError, StoredDoc};
+
+/// Stores documents in an embedded SQLite database: snapshots in the
+/// `documents` table, the update log in `updates`, metadata in
+/// `screenplays`, ACLs, as JSON, in `acls` and share links in `shares`.
+pub(crate) struct SqliteStore {
+    conn: Mutex<Connection>,
+}
+
+impl SqliteStore {
+    /// Opens (or creates) the database at `path`.
+    pub(crate) fn open(path: impl AsRef<Path>) -> Result<Self, StoreError> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(
+            "PRAGMA journal_mode = WAL;
+            CREATE TABLE IF NOT EXISTS documents (
+                doc_id TEXT PRIMARY KEY,
+                state BLOB NOT NULL,
+                updated_at INTEGER NOT NULL DEFAULT (unixepoch())
+            );
+            CREATE TABLE IF NOT EXISTS updates (
+                doc_id TEXT NOT NULL,
+                seq INTEGER NOT NULL,
+                data BLOB NOT NULL,
+                PRIMARY KEY (doc_id, seq)
+            );
+            CREATE TABLE IF NOT EXISTS screenplays (
+                doc_id TEXT PRIMARY KEY,
+                title TEXT NOT NULL,
+                created_at INTEGER NOT NULL,
+                updated_at INTEGER NOT NULL,
+                deleted_at INTEGER
+            );
+            CREATE TABLE IF NOT EXISTS acls (
+                doc_id TEXT PRIMARY KEY,
+                acl TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS shares (
+                token TEXT PRIMARY KEY,
+                doc_id TEXT NOT NULL,
+                role TEXT NOT NULL,
+                created_at INTEGER NOT NULL,
+                expires_at INTEGER NOT NULL,
+                max_uses INTEGER,
+                uses INTEGER NOT NULL DEFAULT 0
+            );
+            CREATE INDEX IF NOT EXISTS shares_doc_id ON shares (doc_id);",
+        )?;
+
+        Ok(Self {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    fn conn(&self) -> std::sync::MutexGuard<'_, Connection> {
+        // A panic while holding the lock cannot leave the connection in
+        // a state worse than SQLite's own transaction handling.
+        self.conn.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl DocumentStore for SqliteStore {
+    fn load(&self, doc_id: &str) -> Result<Option<StoredDoc>, StoreError> {
+        let conn = self.conn();
+        let snapshot: Option<Vec<u8>> = conn
+            .query_row(
+                "SELECT state FROM documents WHERE doc_id = ?1",
+                params![doc_id],
+                |row| row.get(0),
+            )
+            .optional()?;
+
+        let mut stmt =
+            conn.prepare("SELECT seq, data FROM updates WHERE doc_id = ?1 ORDER BY seq")?;
+        let updates = stmt
+            .query_map(params![doc_id], |row| {
+                Ok((row.get::<_, i64>(0)? as u64, row.get(1)?))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        if snapshot.is_none() && updates.is_empty() {
+            return Ok(None);
+        }
+
+        Ok(Some(StoredDoc { snapshot, updates }))
+    }
+
+    fn append(&self, doc_id: &str, seq: u64, update: &[u8]) -> Result<(), StoreError> {
+        self.conn().execute(
+            "INSERT OR REPLACE INTO updates (doc_id, seq, data) VALUES (?1, ?2, ?3)",
+            params![doc_id, seq as i64, update],
+        )?;
+
+        Ok(())
+    }
+
+    fn compact(&self, doc_id: &str, state: &[u8], seq: u64) -> Result<(), StoreError> {
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        tx.execute(
+            "INSERT INTO documents (doc_id, state) VALUES (?1, ?2)
+             ON CONFLICT(doc_id) DO UPDATE SET state = excluded.state, updated_at = unixepoch()",
+            params![doc_id, state],
+        )?;
+        tx.execute(
+            "DELETE FROM updates WHERE doc_id = ?1 AND seq <= ?2",
+            params![doc_id, seq as i64],
+        )?;
+        tx.commit()?;
+
+        Ok(())
+    }
+
+    fn list_meta(&self) -> Result<Vec<ScreenplayMeta>, StoreError> {
+        let conn = self.conn();
+        let mut stmt = conn
+            .prepare("SELECT doc_id, title, created_at, updated_at, deleted_at FROM screenplays")?;
+        let metas = stmt
+            .query_map([], meta_from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(metas)
+    }
+
+    fn load_meta(&self, doc_id: &str) -> Result<Option<ScreenplayMeta>, StoreError> {
+        let meta = self
+            .conn()
+            .query_row(
+                "SELECT doc_id, title, created_at, updated_at, deleted_at
+                 FROM screenplays WHERE doc_id = ?1",
+                params![doc_id],
+                meta_from_row,
+            )
+            .optional()?;
+
+        Ok(meta)
+    }
+
+    fn save_meta(&self, meta: &ScreenplayMeta) -> Result<(), StoreError> {
+        self.conn().execute(
+            "INSERT OR REPLACE INTO screenplays (doc_id, title, created_at, updated_at, deleted_at)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                meta.id,
+                meta.title,
+                meta.created_at as i64,
+                meta.updated_at as i64,
+                meta.deleted_at.map(|t| t as i64),
+            ],
+        )?;
+
+        Ok(())
+    }
+
+    fn load_acl(&self, doc_id: &str) -> Result<Option<Acl>, StoreError> {
+        let acl: Option<String> = self
+            .conn()
+            .query_row(
+                "SELECT acl FROM acls WHERE doc_id = ?1",
+                params![doc_id],
+                |row| row.get(0),
+            )
+            .optional()?;
+
+        acl.map(|acl| serde_json::from_str(&acl).map_err(|e| StoreError::Corrupt(e.to_string())))
+            .transpose()
+    }
+
+    fn save_acl(&self, doc_id: &str, acl: &Acl) -> Result<(), StoreError> {
+        let acl = serde_json::to_string(acl).map_err(|e| StoreError::Corrupt(e.to_string()))?;
+        self.conn().execute(
+            "INSERT OR REPLACE INTO acls (doc_id, acl) VALUES (?1, ?2)",
+            params![doc_id, acl],
+        )?;
+
+        Ok(())
+    }
+
+    fn save_share(&self, link: &ShareLink) -> Result<(), StoreError> {
+        self.conn().execute(
+            "INSERT OR REPLACE INTO shares
+             (token, doc_id, role, created_at, expires_at, max_uses, uses)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                link.token,
+                link.doc_id,
+                link.role.to_string(),
+                link.created_at as i64,
+                link.expires_at as i64,
+                link.max_uses,
+                link.uses,
+            ],
+        )?;
+
+        Ok(())
+    }
+
+    fn load_share(&self, token: &str) -> Result<Option<ShareLink>, StoreError> {
+        let link = self
+            .conn()
+            .query_row(
+                "SELECT token, doc_id, role, created_at, expires_at, max_uses, uses
+                 FROM shares WHERE token = ?1",
+                params![token],
+                share_from_row,
+            )
+            .optional()?;
+
+        Ok(link)
+    }
+
+    fn list_shares(&self, doc_id: &str) -> Result<Vec<ShareLink>, StoreError> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare(
+            "SELECT token, doc_id, role, created_at, expires_at, max_uses, uses
+             FROM shares WHERE doc_id = ?1",
+        )?;
+        let links = stmt
+            .query_map(params![doc_id], share_from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(links)
+    }
+
+    fn delete_share(&self, token: &str) -> Result<bool, StoreError> {
+        let deleted = self
+            .conn()
+            .execute("DELETE FROM shares WHERE token = ?1", params![token])?;
+
+        Ok(deleted > 0)
+    }
+
+    fn redeem_share(&self, token: &str, now: u64) -> Result<Option<ShareLink>, StoreError> {
+        let redeemed = self.conn().execute(
+            "UPDATE shares SET uses = uses + 1
+             WHERE token = ?1 AND expires_at > ?2 AND (max_uses IS NULL OR uses < max_uses)",
+            params![token, now as i64],
+        )?;
+        if redeemed == 0 {
+            return Ok(None);
+        }
+
+        self.load_share(token)
+    }
+}
+
+fn share_from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<ShareLink> {
+    let role: String = row.get(2)?;
+    let role = match role.as_str() {
+        "viewer" => Role::Viewer,
+        "commenter" => Role::Commenter,
+        "editor" => Role::Editor,
+        other => {
+            return Err(rusqlite::Error::FromSqlConversionFailure(
+                2,
+                rusqlite::types::Type::Text,
+                format!("unknown share role {other:?}").into(),
+            ))
+        }
+    };
+
+    Ok(ShareLink {
+        token: row.get(0)?,
+        doc_id: row.get(1)?,
+        role,
+        created_at: row.get::<_, i64>(3)? as u64,
+        expires_at: row.get::<_, i64>(4)? as u64,
+        max_uses: row.get(5)?,
+        uses: row.get(6)?,
+    })
+}
+
+fn meta_from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<ScreenplayMeta> {
+    Ok(ScreenplayMeta {
+        id: row.get(0)?,
+        title: row.get(1)?,
+        created_at: row.get::<_, i64>(2)? as u64,
+        updated_at: row.get::<_, i64>(3)? as u64,
+        deleted_at: row.get::<_, Option<i64>>(4)?.map(|t| t as u64),
+    })
+}
@@ -0,0 +1,196 @@
+//! y-websocket wire compatibility.
+//!
+//! Off-the-shelf Yjs clients (y-websocket, y-prosemirror) speak a binary
+//! protocol of varint-framed sync and awareness messages instead of the
+//! JSON-tagged `ServerRequest`/`ServerReply` format. This module translates
+//! between the two so those clients can join the same room actors.
+use axum::extract::ws::{Message, WebSocket};
+use futures::{SinkExt, StreamExt};
+use tokio::{sync::mpsc, time::Instant};
+use yrs::{
+    sync::{AwarenessUpdate, Message as YMessage, MessageReader, SyncMessage},
+    updates::{
+        decoder::{Decode, DecoderV1},
+        encoder::Encode,
+    },
+    StateVector,
+};
+
+use crate::{
+    limits::Limiter,
+    socket::{admit, join_room, payload_len, Joiner},
+    state::{RoomCmd, UpdateVersion, PING_INTERVAL},
+    stats,
+};
+
+use shared::server::ServerReply;
+
+#[tracing::instrument(skip(state, joiner, socket), fields(peer_id = joiner.peer_id))]
+pub(crate) async fn handle_connect(
+    state: crate::state::AppState,
+    doc_id: String,
+    mut joiner: Joiner,
+    socket: WebSocket,
+) {
+    tracing::info!(%doc_id, "new y-websocket connection");
+    let handle = state.room(&doc_id);
+
+    let (mut sink, mut stream) = socket.split();
+
+    let (server_tx, mut server_rx) = mpsc::channel::<ServerReply>(64);
+
+    // The protocol has nowhere to carry a session token, so y-websocket
+    // peers always join fresh and cannot resume.
+    joiner.session = None;
+    let Some((peer_id, session, _)) = join_room(&handle, joiner, server_tx, &mut server_rx).await
+    else {
+        return;
+    };
+
+    let mut sink_task = tokio::spawn(async move {
+        let mut ping = tokio::time::interval_at(Instant::now() + PING_INTERVAL, PING_INTERVAL);
+        loop {
+            let msg = tokio::select! {
+                reply = server_rx.recv() => match reply.map(into_ymessage) {
+                    Some(Some(msg)) => Message::Binary(msg.encode_v1().into()),
+                    Some(None) => continue,
+                    None => break,
+                },
+                _ = ping.tick() => Message::Ping(Default::default()),
+            };
+
+            stats::add(&stats::BYTES_OUT, payload_len(&msg));
+            if sink.send(msg).await.is_err() {
+                return;
+            }
+        }
+
+        // The room dropped us.
+        let _ = sink.send(Message::Close(None)).await;
+    });
+
+    let mut limiter = Limiter::new(&state.config);
+    loop {
+        let msg = tokio::select! {
+            msg = stream.next() => match msg {
+                Some(Ok(msg)) => msg,
+                _ => break,
+            },
+            _ = &mut sink_task => {
+                tracing::info!(%doc_id, %peer_id, "room closed the connection");
+                break;
+            }
+        };
+
+        let frame = match msg {
+            Message::Binary(bytes) if !bytes.is_empty() => bytes,
+            Message::Close(_) => break,
+            Message::Ping(_) | Message::Pong(_) => {
+                let _ = handle.cmd_tx.send(RoomCmd::Heartbeat { peer_id }).await;
+                continue;
+            }
+            _ => continue,
+        };
+        stats::add(&stats::BYTES_IN, frame.len());
+
+        let mut decoder = DecoderV1::from(frame.as_ref());
+        for msg in MessageReader::new(&mut decoder) {
+            let cmd = match msg {
+                Ok(YMessage::Sync(SyncMessage::SyncStep1(sv))) => RoomCmd::SyncStep1 {
+                    peer_id,
+                    state_vector: sv.encode_v1(),
+                },
+                Ok(YMessage::Sync(SyncMessage::SyncStep2(bytes)))
+                | Ok(YMessage::Sync(SyncMessage::Update(bytes))) => RoomCmd::ClientUpdate {
+                    peer_id,
+                    bytes,
+                    version: UpdateVersion::V1,
+                },
+                Ok(YMessage::Awareness(update)) => RoomCmd::ClientAwareness {
+                    peer_id,
+                    bytes: update.encode_v1(),
+                },
+                Ok(YMessage::AwarenessQuery) => RoomCmd::AwarenessQuery { peer_id },
+                Ok(other) => {
+                    tracing::debug!(%doc_id, %peer_id, "ignoring y-websocket message: {:?}", other);
+                    continue;
+                }
+                Err(e) => {
+                    // The protocol has no error message, so all we can do
+                    // is drop the rest of the frame.
+                    tracing::warn!(%doc_id, %peer_id, "malformed y-websocket frame: {}", e);
+                    limiter.strike();
+                    break;
+                }
+            };
+
+            let admitted = match &cmd {
+                RoomCmd::ClientUpdate { bytes, .. } => {
+                    admit(&mut limiter, &handle, peer_id, bytes.len(), true).await
+                }
+                RoomCmd::ClientAwareness { bytes, .. } => {
+                    admit(&mut limiter, &handle, peer_id, bytes.len(), false).await
+                }
+                _ => Ok(()),
+            };
+            if let Err(e) = admitted {
+                tracing::warn!(%doc_id, %peer_id, "refused y-websocket message: {}", e);
+                continue;
+            }
+
+            let _ = handle.cmd_tx.send(cmd).await;
+        }
+
+        if limiter.exhausted() {
+            tracing::warn!(%doc_id, %peer_id, "disconnecting client after repeated violations");
+            stats::incr(&stats::ABUSE_DISCONNECTS);
+            break;
+        }
+    }
+
+    let _ = handle
+        .cmd_tx
+        .send(RoomCmd::Leave {
+            peer_id,
+            session,
+            resumable: false,
+        })
+        .await;
+    tracing::info!(%doc_id, %peer_id, "y-websocket disconnected");
+    sink_task.abort();
+}
+
+/// Converts a room reply into its y-websocket equivalent. Replies that
+/// have no counterpart in the protocol are dropped.
+fn into_ymessage(reply: ServerReply) -> Option<YMessage> {
+    match reply {
+        ServerReply::Update(bytes) => Some(YMessage::Sync(SyncMessage::Update(bytes))),
+        ServerReply::SyncStep1(sv) => match StateVector::decode_v1(&sv) {
+            Ok(sv) => Some(YMessage::Sync(SyncMessage::SyncStep1(sv))),
+            Err(e) => {
+                tracing::error!("failed to decode room state vector: {}", e);
+                None
+            }
+        },
+        ServerReply::SyncStep2(bytes) | ServerReply::Snapshot(bytes) => {
+            Some(YMessage::Sync(SyncMessage::SyncStep2(bytes)))
+        }
+        ServerReply::Awareness(bytes) => match AwarenessUpdate::decode_v1(&bytes) {
+            Ok(update) => Some(YMessage::Awareness(update)),
+            Err(e) => {
+                tracing::error!("failed to decode room awareness update: {}", e);
+                None
+            }
+        },
+        ServerReply::Error(e) => {
+            tracing::debug!("dropping error reply for y-websocket peer: {}", e);
+            None
+        }
+        ServerReply::Join { .. }
+        | ServerReply::PeerJoined(_)
+        | ServerReply::PeerLeft { .. }
+        | ServerReply::PingPong
+        | ServerReply::RoleChanged(_)
+        | ServerReply::Closing { .. } => None,
+    }
+}
@@ -1,46 +1,34 @@
 use leptos::prelude::*;
 
-use super::ScreenplayElementKind;
-
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
-pub struct ScreenPlayElement {
-    pub kind: ScreenplayElementKind,
-    pub content: (ReadSignal<String>, WriteSignal<String>),
-}
+use shared::screenplay::Element;
 
-impl ScreenPlayElement {
-    pub fn new(kind: ScreenplayElementKind) -> Self {
-        let content = signal(String::new());
-
-        Self { kind, content }
-    }
-
-    pub fn with_content(self, content: &str) -> Self {
-        self.content.1.set(content.into());
-        self
-    }
-}
+use super::ScreenplayElementKind;
 
+/// Renders one element of the shared screenplay model as an editable
+/// paragraph, styled by its kind.
 #[component]
 pub fn ElementComponent(
-    element: ScreenPlayElement,
+    element: Element,
     set_active_format: WriteSignal<ScreenplayElementKind>,
 ) -> impl IntoView {
-    let rows = move || element.content.0.get().lines().count().max(1) + 1;
+    let Element { kind, text, .. } = element;
+    let text = RwSignal::new(text);
+    let rows = move || text.get().lines().count().max(1) + 1;
+    let class = format!("element-textarea element-{}", kind.as_str());
 
     view! {
         <fieldset class="fieldset">
             <textarea
-                class="element-textarea"
+                class=class
                 placeholder="Start something magical..."
                 autocomplete="off"
                 autofocus="off"
                 rows=rows
-                prop:value=element.content.0
+                prop:value=move || text.get()
                 on:input:target=move |ev| {
-                    element.content.1.set(ev.target().value());
+                    text.set(ev.target().value());
                 }
-                on:click=move |_| set_active_format.set(element.kind.clone())
+                on:click=move |_| set_active_format.set(kind.clone())
             ></textarea>
         </fieldset>
     }
@@ -2,7 +2,9 @@
 //!
 //! These represent the block/paragraph-level elements recognized by common
 //! screenplay formats (Final Draft, Fountain, etc.). They’re intentionally
-//! tool-agnostic and can be mapped to specific render rules.
+//! tool-agnostic and can be mapped to specific render rules. The model
+//! itself lives in `shared::screenplay`; this module adds the editor's
+//! presentation of it.
 //!
 use icondata::{
     BiCommentDetailRegular, BiHeadingRegular, BsLightning, BsPersonArmsUp, LuParentheses,
@@ -13,40 +15,7 @@ use crate::components::icon::IntoIcon;
 
 pub mod element;
 
-/// Paragraph-level element kinds in a screenplay.
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
-#[non_exhaustive]
-pub enum ScreenplayElementKind {
-    /// Any typing that doesn’t fit another category
-    General,
-    /// Scene heading (aka slugline): e.g., INT. OFFICE - DAY
-    SceneHeading,
-    /// Action/description blocks
-    Action,
-    /// Character cue preceding dialogue
-    Character,
-    /// Parenthetical (wryly), between character and dialogue
-    Parenthetical,
-    /// Dialogue text
-    Dialogue,
-    /// Transition blocks (e.g., CUT TO:)
-    Transition,
-}
-
-impl core::fmt::Display for ScreenplayElementKind {
-    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
-        let s = match self {
-            ScreenplayElementKind::General => "General",
-            ScreenplayElementKind::SceneHeading => "SceneHeading",
-            ScreenplayElementKind::Action => "Action",
-            ScreenplayElementKind::Character => "Character",
-            ScreenplayElementKind::Parenthetical => "Parenthetical",
-            ScreenplayElementKind::Dialogue => "Dialogue",
-            ScreenplayElementKind::Transition => "Transition",
-        };
-        f.write_str(s)
-    }
-}
+pub use shared::screenplay::{ParseElementError, ScreenplayElementKind};
 
 impl IntoIcon for ScreenplayElementKind {
     fn into_icon(&self) -> icondata_core::Icon {
@@ -57,16 +26,7 @@ impl IntoIcon for ScreenplayElementKind {
             ScreenplayElementKind::Parenthetical => LuParentheses,
             ScreenplayElementKind::Dialogue => BiCommentDetailRegular,
             ScreenplayElementKind::Transition => MdiTransitDetour,
-            ScreenplayElementKind::General => BiCommentDetailRegular,
+            _ => BiCommentDetailRegular,
         }
     }
 }
-
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub struct ParseElementError;
-
-impl core::fmt::Display for ParseElementError {
-    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
-        f.write_str("invalid screenplay element")
-    }
-}
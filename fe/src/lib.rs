@@ -15,10 +15,7 @@ use uuid::Uuid;
 
 use shared::server::{ServerReply, ServerRequest};
 
-use crate::format::{
-    element::{ElementComponent, ScreenPlayElement},
-    ScreenplayElementKind,
-};
+use crate::format::{element::ElementComponent, ScreenplayElementKind};
 
 mod socket;
 
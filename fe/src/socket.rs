@@ -31,13 +31,38 @@ impl WebsocketContext {
                 ServerReply::Snapshot(payload) => {
                     log::info!("Received SNAPSHOT message with {} bytes", payload.len());
                 }
-                ServerReply::Join { id, peers } => {
+                ServerReply::SyncStep1(payload) => {
+                    log::info!("Received SYNC_STEP_1 message with {} bytes", payload.len());
+                }
+                ServerReply::SyncStep2(payload) => {
+                    log::info!("Received SYNC_STEP_2 message with {} bytes", payload.len());
+                }
+                ServerReply::Join { id, peers, .. } => {
                     log::info!(
                         "Received JOIN message with id: {} and {} peers",
                         id,
                         peers.len()
                     );
                 }
+                ServerReply::PeerJoined(peer) => {
+                    log::info!(
+                        "Received PEER_JOINED message for {} ({})",
+                        peer.name,
+                        peer.id
+                    );
+                }
+                ServerReply::PeerLeft { id } => {
+                    log::info!("Received PEER_LEFT message for {}", id);
+                }
+                ServerReply::RoleChanged(role) => {
+                    log::info!("Received ROLE_CHANGED message: {:?}", role);
+                }
+                ServerReply::Closing { reason } => {
+                    log::info!("Received CLOSING message: {}", reason);
+                }
+                ServerReply::Error(e) => {
+                    log::warn!("Received ERROR message: {}", e);
+                }
             },
             None => log::info!("No WebSocket message received"),
         });
@@ -0,0 +1,171 @@
+/// ScreenplayMeta describes a screenplay managed through the REST API.
+/// Times are seconds since the Unix epoch.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct ScreenplayMeta {
+    /// The document id, also used as the room name on `/ws/{id}`.
+    pub id: String,
+    pub title: String,
+    pub created_at: u64,
+    /// Last time the metadata (not the content) changed.
+    pub updated_at: u64,
+    /// Set when the screenplay was deleted. Deleted screenplays are kept
+    /// but hidden from listings and cannot be opened.
+    pub deleted_at: Option<u64>,
+}
+
+/// Body of `POST /api/screenplays` and `POST /api/screenplays/{id}/duplicate`.
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct NewScreenplay {
+    /// Defaults to "Untitled", or "Copy of ..." when duplicating.
+    pub title: Option<String>,
+}
+
+/// Body of `PATCH /api/screenplays/{id}`.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct RenameScreenplay {
+    pub title: String,
+}
+
+/// A screenplay and its content, as returned by `GET /api/screenplays/{id}`.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Screenplay {
+    pub meta: ScreenplayMeta,
+    pub content: crate::screenplay::Screenplay,
+}
+
+/// Response of `GET /api/health`.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Health {
+    /// "ok", or "shutting_down" once the server stopped taking connections.
+    pub status: String,
+    /// Rooms currently loaded in memory.
+    pub rooms: usize,
+}
+
+/// The peers of one running room, as returned by `GET /api/rooms`.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct RoomStats {
+    pub doc_id: String,
+    pub clients: Vec<ClientStats>,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct ClientStats {
+    pub id: u64,
+    pub name: String,
+    /// Seconds since the peer's current connection opened; 0 while away.
+    pub connected_secs: u64,
+    /// Seconds since the room last heard from the peer.
+    pub idle_secs: u64,
+    /// The peer's connection dropped and the room is holding its session.
+    pub away: bool,
+    /// Replies to the peer are being dropped until it catches up.
+    pub lagging: bool,
+}
+
+/// What a user may do with a screenplay. Roles are ordered: each one can
+/// do everything the roles before it can.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, serde::Serialize, serde::Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// Can read the script and see who else is in the room.
+    Viewer,
+    /// Can read and, once comments exist, comment.
+    Commenter,
+    /// Can change the script.
+    Editor,
+    /// Can change the script and decide who else may access it.
+    Owner,
+}
+
+impl Role {
+    /// Whether the role may change the document.
+    pub fn can_edit(self) -> bool {
+        self >= Role::Editor
+    }
+}
+
+impl core::fmt::Display for Role {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let name = match self {
+            Role::Viewer => "viewer",
+            Role::Commenter => "commenter",
+            Role::Editor => "editor",
+            Role::Owner => "owner",
+        };
+        write!(f, "{name}")
+    }
+}
+
+/// Who may access a screenplay, as returned and accepted by
+/// `/api/screenplays/{id}/acl`.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Acl {
+    /// Roles of individual users, by user id (the `sub` of their token).
+    #[serde(default)]
+    pub members: std::collections::BTreeMap<String, Role>,
+    /// Role of everyone else, including every peer when authentication
+    /// is off. `None` keeps them out.
+    pub default_role: Option<Role>,
+}
+
+impl Acl {
+    /// An ACL with `user` as its only owner and nobody else allowed in.
+    pub fn owned_by(user: &str) -> Self {
+        Self {
+            members: [(user.to_string(), Role::Owner)].into(),
+            default_role: None,
+        }
+    }
+
+    /// The role of `user`, or of an anonymous peer when `None`.
+    pub fn role(&self, user: Option<&str>) -> Option<Role> {
+        user.and_then(|user| self.members.get(user).copied())
+            .or(self.default_role)
+    }
+}
+
+/// Documents without an ACL, such as those created before ACLs existed,
+/// stay open to everyone.
+impl Default for Acl {
+    fn default() -> Self {
+        Self {
+            members: Default::default(),
+            default_role: Some(Role::Editor),
+        }
+    }
+}
+
+/// A link giving whoever holds `token` access to one screenplay without
+/// an account, as returned by `/api/screenplays/{id}/shares`.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct ShareLink {
+    /// Passed as `?share=` when connecting to `/ws/{doc_id}`.
+    pub token: String,
+    pub doc_id: String,
+    /// The role the link grants. Never `Owner`.
+    pub role: Role,
+    pub created_at: u64,
+    pub expires_at: u64,
+    /// How many connections the link may open, or `None` for no limit.
+    pub max_uses: Option<u32>,
+    pub uses: u32,
+}
+
+impl ShareLink {
+    /// Whether the link can still be used at time `now`.
+    pub fn is_valid(&self, now: u64) -> bool {
+        now < self.expires_at && self.max_uses.is_none_or(|max| self.uses < max)
+    }
+}
+
+/// Body of `POST /api/screenplays/{id}/shares`.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct NewShareLink {
+    pub role: Role,
+    /// Lifetime of the link in seconds. Defaults to a week.
+    pub expires_in_secs: Option<u64>,
+    pub max_uses: Option<u32>,
+}
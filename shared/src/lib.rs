@@ -1 +1,3 @@
+pub mod api;
+pub mod screenplay;
 pub mod server;
@@ -0,0 +1,614 @@
+//! [Fountain](https://fountain.io), the plain-text screenplay markup.
+//!
+//! [`parse`] reads a script into a [`Screenplay`] and [`write`] writes
+//! one back out. Emphasis (`*`, `_`) and notes inside a paragraph are
+//! kept in its text as written; the boneyard (`/* */`) is dropped.
+//!
+//! Fountain has no way to write some text as it is, so [`write`] escapes
+//! it with a backslash, which [`parse`] drops again: `\/*` outside the
+//! boneyard, `\]]` inside a note, `\(` opening a dialogue line that
+//! would otherwise read as a parenthetical, `\#` opening a section title
+//! or closing a scene heading, `\.` opening a forced scene heading,
+//! `\^` closing a character cue and `\<` closing a transition. Empty lines
+//! inside action, dialogue, notes and title page values are written as
+//! two spaces.
+use super::{Attributes, Element, Screenplay, ScreenplayElementKind, TitleField, TitlePage};
+
+/// Characters that force an element when they start a line.
+const FORCING: [char; 8] = ['!', '@', '#', '=', '.', '~', '>', '['];
+
+/// Words that open a scene heading.
+const SCENE_PREFIXES: [&str; 6] = ["INT", "EXT", "EST", "INT./EXT", "INT/EXT", "I/E"];
+
+/// Parses a Fountain script. Every input is a valid script: anything
+/// that is not recognised as another element is action.
+pub fn parse(input: &str) -> Screenplay {
+    let input = input
+        .trim_start_matches('\u{feff}')
+        .replace("\r\n", "\n")
+        .replace('\r', "\n");
+    let input = strip_boneyard(&input);
+    let lines: Vec<&str> = input.split('\n').collect();
+
+    let (title_page, body) = title_page(&lines);
+    Screenplay {
+        title_page,
+        elements: Parser::new(body).parse(),
+    }
+}
+
+/// Writes a screenplay as a Fountain script. Elements whose text would
+/// read as something else are forced, so parsing the script gives the
+/// screenplay back.
+pub fn write(screenplay: &Screenplay) -> String {
+    use ScreenplayElementKind as Kind;
+
+    let mut out = String::new();
+    for field in &screenplay.title_page.0 {
+        if field.value.contains('\n') {
+            out.push_str(&format!("{}:\n", field.key));
+            for line in field.value.split('\n') {
+                if is_blank(line) {
+                    out.push_str("  \n");
+                } else {
+                    out.push_str(&format!("    {}\n", line.trim()));
+                }
+            }
+        } else {
+            out.push_str(format!("{}: {}", field.key, field.value).trim_end());
+        }
+        if !out.ends_with('\n') {
+            out.push('\n');
+        }
+    }
+
+    let mut previous: Option<&ScreenplayElementKind> = None;
+    for element in &screenplay.elements {
+        // Parentheticals and dialogue stay with their cue; everything
+        // else is a paragraph of its own.
+        let continues = matches!(
+            (previous, &element.kind),
+            (
+                Some(Kind::Character | Kind::Parenthetical | Kind::Dialogue),
+                Kind::Parenthetical | Kind::Dialogue
+            )
+        );
+        if !continues && !out.is_empty() {
+            out.push('\n');
+        }
+
+        // Without a title page, the first paragraph must not read as one.
+        let opens_script = out.is_empty();
+        out.push_str(&write_element(element, opens_script));
+        out.push('\n');
+        previous = Some(&element.kind);
+    }
+
+    // Nothing written opens a boneyard, so every `/*` is from the text.
+    out.replace("/*", "\\/*")
+}
+
+/// The lines of one element, without the blank line around it.
+fn write_element(element: &Element, opens_script: bool) -> String {
+    use ScreenplayElementKind as Kind;
+
+    let text = element.text.as_str();
+    let attrs = &element.attrs;
+    // Without a title page, the first line must not read as one.
+    let title_like =
+        opens_script && title_key(text.split('\n').next().unwrap_or_default()).is_some();
+    match element.kind {
+        Kind::SceneHeading => {
+            let text = escape_suffix(text, '#');
+            let heading = if is_scene_heading(&text) && !title_like {
+                text
+            } else {
+                format!(".{}", escape_prefix(&text, '.'))
+            };
+            match &attrs.scene_number {
+                Some(number) => format!("{heading} #{number}#"),
+                None => heading,
+            }
+        }
+        Kind::Character => {
+            let forced = !is_cue(text) || text.starts_with(FORCING) || title_like;
+            let text = escape_suffix(text, '^');
+            let cue = if forced { format!("@{text}") } else { text };
+            if attrs.dual { format!("{cue} ^") } else { cue }
+        }
+        Kind::Parenthetical => {
+            if is_parenthetical(text) {
+                text.to_string()
+            } else {
+                format!("({text})")
+            }
+        }
+        Kind::Dialogue => text
+            .split('\n')
+            .map(|line| match line.trim() {
+                _ if is_blank(line) => "  ".to_string(),
+                line if is_parenthetical(line.trim_start_matches('\\')) => format!("\\{line}"),
+                line => line.to_string(),
+            })
+            .collect::<Vec<_>>()
+            .join("\n"),
+        Kind::Transition => {
+            let plain = is_transition(text)
+                && !is_scene_heading(text)
+                && !text.starts_with(FORCING)
+                && !title_like;
+            if plain {
+                text.to_string()
+            } else {
+                format!(">{}", escape_suffix(text, '<'))
+            }
+        }
+        Kind::Lyrics => text
+            .split('\n')
+            .map(|line| format!("~{line}"))
+            .collect::<Vec<_>>()
+            .join("\n"),
+        Kind::Section => format!(
+            "{} {}",
+            "#".repeat(attrs.level.unwrap_or(1).into()),
+            escape_prefix(text, '#')
+        ),
+        Kind::Synopsis => text
+            .split('\n')
+            .map(|line| format!("= {line}"))
+            .collect::<Vec<_>>()
+            .join("\n"),
+        Kind::Note => {
+            let note = text
+                .split('\n')
+                .map(|line| if is_blank(line) { "  " } else { line })
+                .collect::<Vec<_>>()
+                .join("\n")
+                .replace("]]", "\\]]");
+            // A backslash just before the closing `]]` would escape it.
+            let pad = if note.ends_with('\\') { " " } else { "" };
+            format!("[[{note}{pad}]]")
+        }
+        Kind::PageBreak => "===".to_string(),
+        _ if attrs.centered => format!("> {text} <"),
+        _ => write_action(text, title_like),
+    }
+}
+
+/// Escapes `text` with a backslash if it starts with `c`, or with
+/// backslashes and then `c`.
+fn escape_prefix(text: &str, c: char) -> String {
+    if text.trim_start_matches('\\').starts_with(c) {
+        format!("\\{text}")
+    } else {
+        text.to_string()
+    }
+}
+
+/// Escapes a `c` ending `text` with a backslash.
+fn escape_suffix(text: &str, c: char) -> String {
+    match text.strip_suffix(c) {
+        Some(rest) => format!("{rest}\\{c}"),
+        None => text.to_string(),
+    }
+}
+
+/// Drops the backslash [`escape_prefix`] adds.
+fn unescape_prefix(text: &str, c: char) -> &str {
+    match text.strip_prefix('\\') {
+        Some(rest) if rest.trim_start_matches('\\').starts_with(c) => rest,
+        _ => text,
+    }
+}
+
+/// Drops the backslash [`escape_suffix`] adds.
+fn unescape_suffix(text: &str, c: char) -> String {
+    match text
+        .strip_suffix(c)
+        .and_then(|rest| rest.strip_suffix('\\'))
+    {
+        Some(rest) => format!("{rest}{c}"),
+        None => text.to_string(),
+    }
+}
+
+/// Writes action as it is, or forced with `!` if it would otherwise be
+/// read as another element, or as a title page.
+fn write_action(text: &str, title_like: bool) -> String {
+    let written = text
+        .split('\n')
+        .map(|line| if is_blank(line) { "  " } else { line })
+        .collect::<Vec<_>>()
+        .join("\n");
+    let lines: Vec<&str> = written.split('\n').collect();
+    let reread = Parser::new(&lines).parse();
+    let plain = matches!(
+        reread.as_slice(),
+        [Element { kind: ScreenplayElementKind::Action, text: reread, attrs }]
+            if reread == text && attrs.is_empty()
+    ) && !title_like;
+
+    if plain {
+        written
+    } else {
+        format!("!{written}")
+    }
+}
+
+/// Drops the boneyard, keeping an escaped `\/*` as a plain `/*`.
+fn strip_boneyard(input: &str) -> String {
+    let mut out = String::with_capacity(input.len());
+    let mut rest = input;
+    while let Some(start) = rest.find("/*") {
+        if let Some(before) = rest[..start].strip_suffix('\\') {
+            out.push_str(before);
+            out.push_str("/*");
+            rest = &rest[start + 2..];
+            continue;
+        }
+        out.push_str(&rest[..start]);
+        rest = match rest[start + 2..].find("*/") {
+            Some(end) => &rest[start + 2 + end + 2..],
+            None => "",
+        };
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Splits off the title page, which is the run of `Key: value` lines at
+/// the very start of the script, if any. Values can also be given as
+/// indented lines under a bare `Key:`.
+fn title_page<'a>(lines: &'a [&'a str]) -> (TitlePage, &'a [&'a str]) {
+    if lines.first().and_then(|l| title_key(l)).is_none() {
+        return (TitlePage::default(), lines);
+    }
+
+    // Each key with the lines of its value.
+    let mut fields: Vec<(&str, Vec<&str>)> = Vec::new();
+    let mut end = lines.len();
+    for (i, line) in lines.iter().enumerate() {
+        if is_blank(line) && *line != "  " {
+            end = i;
+            break;
+        }
+
+        if let Some((key, value)) = title_key(line) {
+            let value = if value.is_empty() {
+                vec![]
+            } else {
+                vec![value]
+            };
+            fields.push((key, value));
+        } else if let Some((_, value)) = fields.last_mut() {
+            value.push(line.trim());
+        }
+    }
+
+    let fields = fields
+        .into_iter()
+        .map(|(key, value)| TitleField {
+            key: key.to_string(),
+            value: value.join("\n"),
+        })
+        .collect();
+    (TitlePage(fields), &lines[end.min(lines.len())..])
+}
+
+/// The key and inline value of a `Key: value` line.
+fn title_key(line: &str) -> Option<(&str, &str)> {
+    let (key, value) = line.split_once(':')?;
+    let valid = !key.is_empty()
+        && !key.starts_with(char::is_whitespace)
+        && key
+            .chars()
+            .all(|c| c.is_alphanumeric() || matches!(c, ' ' | '-' | '_'));
+
+    valid.then(|| (key.trim(), value.trim()))
+}
+
+fn is_blank(line: &str) -> bool {
+    line.trim().is_empty()
+}
+
+struct Parser<'a> {
+    lines: &'a [&'a str],
+    pos: usize,
+    elements: Vec<Element>,
+}
+
+impl<'a> Parser<'a> {
+    fn new(lines: &'a [&'a str]) -> Self {
+        Self {
+            lines,
+            pos: 0,
+            elements: Vec::new(),
+        }
+    }
+
+    fn parse(mut self) -> Vec<Element> {
+        while self.pos < self.lines.len() {
+            if is_blank(self.lines[self.pos]) {
+                self.pos += 1;
+                continue;
+            }
+            self.paragraph();
+        }
+        self.elements
+    }
+
+    fn blank_at(&self, index: usize) -> bool {
+        self.lines.get(index).is_none_or(|l| is_blank(l))
+    }
+
+    fn push(&mut self, kind: ScreenplayElementKind, text: impl Into<String>) {
+        self.elements.push(Element::new(kind, text));
+    }
+
+    /// Reads the element starting at the current line.
+    fn paragraph(&mut self) {
+        use ScreenplayElementKind as Kind;
+
+        let line = self.lines[self.pos];
+        let trimmed = line.trim();
+        let blank_before = self.pos == 0 || self.blank_at(self.pos - 1);
+        let blank_after = self.blank_at(self.pos + 1);
+        let speech_after = !blank_after || self.lines.get(self.pos + 1) == Some(&"  ");
+
+        if is_page_break(trimmed) {
+            self.push(Kind::PageBreak, "");
+        } else if let Some(title) = trimmed.strip_prefix('#') {
+            let level = 1 + title.chars().take_while(|c| *c == '#').count();
+            let title = unescape_prefix(title.trim_start_matches('#').trim(), '#');
+            self.elements
+                .push(Element::new(Kind::Section, title).with_attrs(Attributes {
+                    level: Some(level.min(u8::MAX.into()) as u8),
+                    ..Attributes::default()
+                }));
+        } else if trimmed.starts_with('=') {
+            self.synopsis();
+            return;
+        } else if trimmed.starts_with("[[") && self.note() {
+            return;
+        } else if trimmed.starts_with('~') {
+            self.lyrics();
+            return;
+        } else if trimmed.starts_with('!') {
+            self.action();
+            return;
+        } else if let Some(heading) = forced_heading(trimmed) {
+            self.scene_heading(heading);
+        } else if blank_before && blank_after && is_scene_heading(trimmed) {
+            self.scene_heading(trimmed);
+        } else if let Some(text) = centered(trimmed) {
+            self.elements
+                .push(Element::new(Kind::Action, text).with_attrs(Attributes {
+                    centered: true,
+                    ..Attributes::default()
+                }));
+        } else if let Some(transition) = trimmed.strip_prefix('>') {
+            self.push(Kind::Transition, unescape_suffix(transition.trim(), '<'));
+        } else if blank_before && blank_after && is_transition(trimmed) {
+            self.push(Kind::Transition, trimmed);
+        } else if blank_before && speech_after && (trimmed.starts_with('@') || is_cue(trimmed)) {
+            self.dialogue();
+            return;
+        } else {
+            self.action();
+            return;
+        }
+
+        self.pos += 1;
+    }
+
+    fn scene_heading(&mut self, heading: &str) {
+        let (text, number) = scene_number(heading);
+        let text = unescape_suffix(text, '#');
+        self.elements.push(
+            Element::new(ScreenplayElementKind::SceneHeading, text).with_attrs(Attributes {
+                scene_number: number.map(str::to_string),
+                ..Attributes::default()
+            }),
+        );
+    }
+
+    /// Reads a note standing as a paragraph of its own. Returns false if
+    /// the paragraph holds more than the note.
+    fn note(&mut self) -> bool {
+        let mut text = String::new();
+        for (i, line) in self.lines.iter().enumerate().skip(self.pos) {
+            if i > self.pos && is_blank(line) && *line != "  " {
+                return false;
+            }
+
+            let line = line.trim();
+            let line = if i == self.pos { &line[2..] } else { line };
+            if let Some(close) = note_close(line) {
+                if close + 2 != line.len() || !self.blank_at(i + 1) {
+                    return false;
+                }
+                if !text.is_empty() {
+                    text.push('\n');
+                }
+                text.push_str(&line[..close]);
+                let note = text.trim().replace("\\]]", "]]");
+                self.push(ScreenplayElementKind::Note, note);
+                self.pos = i + 1;
+                return true;
+            }
+
+            if !text.is_empty() {
+                text.push('\n');
+            }
+            text.push_str(line);
+        }
+
+        false
+    }
+
+    /// Reads consecutive `~` lines as one lyrics element.
+    fn lyrics(&mut self) {
+        let mut lines = Vec::new();
+        while let Some(lyric) = self
+            .lines
+            .get(self.pos)
+            .and_then(|l| l.trim().strip_prefix('~'))
+        {
+            lines.push(lyric.trim());
+            self.pos += 1;
+        }
+        self.push(ScreenplayElementKind::Lyrics, lines.join("\n"));
+    }
+
+    /// Reads consecutive `=` lines as one synopsis.
+    fn synopsis(&mut self) {
+        let mut lines = Vec::new();
+        while let Some(line) = self
+            .lines
+            .get(self.pos)
+            .map(|l| l.trim())
+            .filter(|l| !is_page_break(l))
+            .and_then(|l| l.strip_prefix('='))
+        {
+            lines.push(line.trim());
+            self.pos += 1;
+        }
+        self.push(ScreenplayElementKind::Synopsis, lines.join("\n"));
+    }
+
+    /// Reads the rest of the paragraph as action, keeping its line
+    /// breaks and indentation.
+    fn action(&mut self) {
+        let mut lines = Vec::new();
+        while let Some(line) = self.lines.get(self.pos) {
+            // Two spaces keep an empty line inside the action.
+            if is_blank(line) && *line != "  " {
+                break;
+            }
+            let line = line.trim_end();
+            let line = if lines.is_empty() {
+                line.trim_start().strip_prefix('!').unwrap_or(line)
+            } else {
+                line
+            };
+            lines.push(line);
+            self.pos += 1;
+        }
+        self.push(ScreenplayElementKind::Action, lines.join("\n"));
+    }
+
+    /// Reads a character cue and the parentheticals and dialogue under it.
+    fn dialogue(&mut self) {
+        let cue = self.lines[self.pos].trim();
+        let cue = cue.strip_prefix('@').unwrap_or(cue);
+        let (cue, dual) = match cue.strip_suffix('^').filter(|c| !c.ends_with('\\')) {
+            Some(cue) => (cue.trim_end(), true),
+            None => (cue, false),
+        };
+        let cue = unescape_suffix(cue, '^');
+        self.elements.push(
+            Element::new(ScreenplayElementKind::Character, cue).with_attrs(Attributes {
+                dual,
+                ..Attributes::default()
+            }),
+        );
+        self.pos += 1;
+
+        let mut speech: Vec<&str> = Vec::new();
+        while let Some(line) = self.lines.get(self.pos) {
+            // Two spaces keep an empty line inside the speech.
+            if is_blank(line) && *line != "  " {
+                break;
+            }
+            self.pos += 1;
+
+            let line = line.trim();
+            if is_parenthetical(line) {
+                self.flush_dialogue(&mut speech);
+                self.push(ScreenplayElementKind::Parenthetical, line);
+            } else if let Some(escaped) = line
+                .strip_prefix('\\')
+                .filter(|l| is_parenthetical(l.trim_start_matches('\\')))
+            {
+                speech.push(escaped);
+            } else {
+                speech.push(line);
+            }
+        }
+        self.flush_dialogue(&mut speech);
+    }
+
+    fn flush_dialogue(&mut self, speech: &mut Vec<&str>) {
+        if !speech.is_empty() {
+            self.push(ScreenplayElementKind::Dialogue, speech.join("\n"));
+            speech.clear();
+        }
+    }
+}
+
+fn is_page_break(line: &str) -> bool {
+    line.len() >= 3 && line.chars().all(|c| c == '=')
+}
+
+/// Where the `]]` closing a note is in a line, skipping escaped ones.
+fn note_close(line: &str) -> Option<usize> {
+    line.match_indices("]]")
+        .map(|(i, _)| i)
+        .find(|i| !line[..*i].ends_with('\\'))
+}
+
+fn is_parenthetical(line: &str) -> bool {
+    line.starts_with('(') && line.ends_with(')')
+}
+
+/// The heading of a line forced to be one with a leading `.`.
+fn forced_heading(line: &str) -> Option<&str> {
+    let heading = line.strip_prefix('.')?;
+    (!heading.is_empty() && !heading.starts_with('.')).then(|| unescape_prefix(heading.trim(), '.'))
+}
+
+fn is_scene_heading(line: &str) -> bool {
+    let upper = line.to_uppercase();
+    SCENE_PREFIXES.iter().any(|prefix| {
+        upper
+            .strip_prefix(prefix)
+            .is_some_and(|rest| rest.starts_with(['.', ' ']))
+    })
+}
+
+/// Splits a trailing scene number, as in `INT. HOUSE - DAY #12A#`, off a
+/// scene heading.
+fn scene_number(heading: &str) -> (&str, Option<&str>) {
+    let Some(rest) = heading.strip_suffix('#').filter(|r| !r.ends_with('\\')) else {
+        return (heading, None);
+    };
+    match rest.rfind('#') {
+        Some(open) if open > 0 && !rest[open + 1..].is_empty() => {
+            (rest[..open].trim_end(), Some(&rest[open + 1..]))
+        }
+        _ => (heading, None),
+    }
+}
+
+/// The text of a `> centered <` line.
+fn centered(line: &str) -> Option<&str> {
+    let text = line.strip_prefix('>')?.strip_suffix('<')?;
+    (!text.ends_with('\\')).then(|| text.trim())
+}
+
+fn is_transition(line: &str) -> bool {
+    line.ends_with("TO:") && is_upper(line)
+}
+
+/// Whether a line reads as a character cue: the name, which may be
+/// followed by an extension such as `(V.O.)` or a `^`, in capitals.
+fn is_cue(line: &str) -> bool {
+    let line = line.strip_suffix('^').unwrap_or(line);
+    let name = line.split('(').next().unwrap_or_default();
+    is_upper(name)
+}
+
+/// Whether a line has letters and all of them are capitals.
+fn is_upper(line: &str) -> bool {
+    line.chars().any(char::is_alphabetic) && !line.chars().any(char::is_lowercase)
+}
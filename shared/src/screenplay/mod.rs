@@ -0,0 +1,266 @@
+//! The screenplay document model.
+//!
+//! A screenplay is a title page followed by a flat list of elements
+//! (Hollywood-style paragraph categories). The model is tool-agnostic:
+//! the editor renders it, the server validates and exports it, and
+//! [`ydoc`] maps it to and from the yrs document peers edit together.
+pub mod fountain;
+pub mod ydoc;
+
+/// Paragraph-level element kinds in a screenplay.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum ScreenplayElementKind {
+    /// Any typing that doesn’t fit another category
+    General,
+    /// Scene heading (aka slugline): e.g., INT. OFFICE - DAY
+    SceneHeading,
+    /// Action/description blocks
+    Action,
+    /// Character cue preceding dialogue
+    Character,
+    /// Parenthetical (wryly), between character and dialogue
+    Parenthetical,
+    /// Dialogue text
+    Dialogue,
+    /// Transition blocks (e.g., CUT TO:)
+    Transition,
+    /// Song lyrics, set in italics
+    Lyrics,
+    /// Outline heading that groups scenes into acts or sequences; not printed
+    Section,
+    /// One-line summary of a section or scene; not printed
+    Synopsis,
+    /// Writer's note; not printed
+    Note,
+    /// Forced page break
+    PageBreak,
+}
+
+impl ScreenplayElementKind {
+    /// Every kind, in the order the editor offers them.
+    pub const ALL: [ScreenplayElementKind; 12] = [
+        ScreenplayElementKind::General,
+        ScreenplayElementKind::SceneHeading,
+        ScreenplayElementKind::Action,
+        ScreenplayElementKind::Character,
+        ScreenplayElementKind::Parenthetical,
+        ScreenplayElementKind::Dialogue,
+        ScreenplayElementKind::Transition,
+        ScreenplayElementKind::Lyrics,
+        ScreenplayElementKind::Section,
+        ScreenplayElementKind::Synopsis,
+        ScreenplayElementKind::Note,
+        ScreenplayElementKind::PageBreak,
+    ];
+
+    /// The name the kind is stored under, as in serde and the yrs layout.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ScreenplayElementKind::General => "general",
+            ScreenplayElementKind::SceneHeading => "scene_heading",
+            ScreenplayElementKind::Action => "action",
+            ScreenplayElementKind::Character => "character",
+            ScreenplayElementKind::Parenthetical => "parenthetical",
+            ScreenplayElementKind::Dialogue => "dialogue",
+            ScreenplayElementKind::Transition => "transition",
+            ScreenplayElementKind::Lyrics => "lyrics",
+            ScreenplayElementKind::Section => "section",
+            ScreenplayElementKind::Synopsis => "synopsis",
+            ScreenplayElementKind::Note => "note",
+            ScreenplayElementKind::PageBreak => "page_break",
+        }
+    }
+
+    /// The kind shown under `name` by its `Display` implementation.
+    pub fn from_display_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|kind| kind.to_string() == name)
+    }
+}
+
+impl core::fmt::Display for ScreenplayElementKind {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let s = match self {
+            ScreenplayElementKind::General => "General",
+            ScreenplayElementKind::SceneHeading => "SceneHeading",
+            ScreenplayElementKind::Action => "Action",
+            ScreenplayElementKind::Character => "Character",
+            ScreenplayElementKind::Parenthetical => "Parenthetical",
+            ScreenplayElementKind::Dialogue => "Dialogue",
+            ScreenplayElementKind::Transition => "Transition",
+            ScreenplayElementKind::Lyrics => "Lyrics",
+            ScreenplayElementKind::Section => "Section",
+            ScreenplayElementKind::Synopsis => "Synopsis",
+            ScreenplayElementKind::Note => "Note",
+            ScreenplayElementKind::PageBreak => "PageBreak",
+        };
+        f.write_str(s)
+    }
+}
+
+/// Parses the stored name returned by [`ScreenplayElementKind::as_str`].
+/// Display names are read with
+/// [`ScreenplayElementKind::from_display_name`].
+impl core::str::FromStr for ScreenplayElementKind {
+    type Err = ParseElementError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|kind| kind.as_str() == s)
+            .ok_or(ParseElementError)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParseElementError;
+
+impl core::fmt::Display for ParseElementError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str("invalid screenplay element")
+    }
+}
+
+/// Stable identity of a scene. It is kept on the scene heading and
+/// survives edits to the heading's text, so notes, reports and links
+/// can refer to the scene. [`ydoc`] assigns one to every scene heading
+/// it adds to a document.
+#[derive(
+    Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, serde::Serialize, serde::Deserialize,
+)]
+#[serde(transparent)]
+pub struct SceneId(pub String);
+
+impl core::fmt::Display for SceneId {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Optional properties of an element. Which ones make sense depends on
+/// the kind; the rest stay at their defaults.
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct Attributes {
+    /// Set on scene headings.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scene_id: Option<SceneId>,
+    /// Scene number printed in the margin of a scene heading, e.g. "12A".
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scene_number: Option<String>,
+    /// The character cue (and the dialogue under it) is set beside the
+    /// previous one, for two characters speaking at once.
+    #[serde(skip_serializing_if = "core::ops::Not::not")]
+    pub dual: bool,
+    /// The text is centered on the page.
+    #[serde(skip_serializing_if = "core::ops::Not::not")]
+    pub centered: bool,
+    /// Nesting depth of a section, from 1 for the outermost.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub level: Option<u8>,
+}
+
+impl Attributes {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// One paragraph of a screenplay.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Element {
+    pub kind: ScreenplayElementKind,
+    pub text: String,
+    #[serde(default, skip_serializing_if = "Attributes::is_empty")]
+    pub attrs: Attributes,
+}
+
+impl Element {
+    pub fn new(kind: ScreenplayElementKind, text: impl Into<String>) -> Self {
+        Self {
+            kind,
+            text: text.into(),
+            attrs: Attributes::default(),
+        }
+    }
+
+    pub fn with_attrs(mut self, attrs: Attributes) -> Self {
+        self.attrs = attrs;
+        self
+    }
+}
+
+/// One `Key: value` entry of a title page, such as the title, credit,
+/// author or contact details. Values may span several lines.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct TitleField {
+    pub key: String,
+    pub value: String,
+}
+
+/// The entries of a title page, in the order they were written.
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(transparent)]
+pub struct TitlePage(pub Vec<TitleField>);
+
+impl TitlePage {
+    /// The value of the first entry named `key`, ignoring case.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|field| field.key.eq_ignore_ascii_case(key))
+            .map(|field| field.value.as_str())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+/// A whole screenplay.
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Screenplay {
+    #[serde(default, skip_serializing_if = "TitlePage::is_empty")]
+    pub title_page: TitlePage,
+    pub elements: Vec<Element>,
+}
+
+impl Screenplay {
+    /// The scenes of the screenplay, each a scene heading and the
+    /// elements up to the next one. Elements before the first heading
+    /// belong to no scene.
+    pub fn scenes(&self) -> impl Iterator<Item = Scene<'_>> {
+        let starts: Vec<usize> = self
+            .elements
+            .iter()
+            .enumerate()
+            .filter(|(_, e)| e.kind == ScreenplayElementKind::SceneHeading)
+            .map(|(i, _)| i)
+            .collect();
+        let ends: Vec<usize> = starts
+            .iter()
+            .skip(1)
+            .copied()
+            .chain(Some(self.elements.len()))
+            .collect();
+
+        starts.into_iter().zip(ends).map(|(start, end)| Scene {
+            heading: &self.elements[start],
+            body: &self.elements[start + 1..end],
+        })
+    }
+}
+
+/// A scene heading and the elements under it.
+#[derive(Debug, Clone, Copy)]
+pub struct Scene<'a> {
+    pub heading: &'a Element,
+    pub body: &'a [Element],
+}
+
+impl Scene<'_> {
+    pub fn id(&self) -> Option<&SceneId> {
+        self.heading.attrs.scene_id.as_ref()
+    }
+}
@@ -0,0 +1,357 @@
+//! The layout a [`Screenplay`] takes in a yrs document.
+//!
+//! The root array [`BLOCKS`] holds one map per element:
+//!
+//! | key     | type   | value                                     |
+//! |---------|--------|-------------------------------------------|
+//! | `kind`  | string | [`ScreenplayElementKind::as_str`]         |
+//! | `text`  | Y.Text | the element's text                        |
+//! | `attrs` | Y.Map  | [`Attributes`], omitted keys are defaults |
+//!
+//! The root array [`TITLE_PAGE`] holds one `{ key, value }` map of
+//! strings per title page entry. Peers edit the text of a block in place;
+//! adding, removing or reordering blocks edits the array.
+//!
+//! Documents written before this layout keep the whole script in a root
+//! text: [`LEGACY_TEXT`], or in the earliest rooms a root named after the
+//! doc id. [`migrate_plain_text`] moves it into blocks.
+//!
+//! The server and the REST API use this layout. The editor does not sync
+//! its document through it yet; it renders [`Element`]s on its own until
+//! it does.
+use yrs::{
+    Any, Array, ArrayRef, GetString, Map, MapPrelim, MapRef, Out, ReadTxn, Text, TextPrelim,
+    TextRef, TransactionMut, WriteTxn,
+    branch::{Branch, BranchID},
+    types::text::YChange,
+};
+
+use super::{
+    Attributes, Element, SceneId, Screenplay, ScreenplayElementKind, TitleField, TitlePage,
+};
+
+/// Root array of element blocks.
+pub const BLOCKS: &str = "blocks";
+/// Root array of title page entries.
+pub const TITLE_PAGE: &str = "title_page";
+/// Root text that held the whole script as plain text.
+pub const LEGACY_TEXT: &str = "content";
+
+const KIND: &str = "kind";
+const TEXT: &str = "text";
+const ATTRS: &str = "attrs";
+const KEY: &str = "key";
+const VALUE: &str = "value";
+
+const SCENE_ID: &str = "scene_id";
+const SCENE_NUMBER: &str = "scene_number";
+const DUAL: &str = "dual";
+const CENTERED: &str = "centered";
+const LEVEL: &str = "level";
+
+/// Replaces the whole document with `screenplay`.
+pub fn write(txn: &mut TransactionMut, screenplay: &Screenplay) {
+    let blocks = txn.get_or_insert_array(BLOCKS);
+    clear(txn, &blocks);
+    for element in &screenplay.elements {
+        push_block(txn, &blocks, element);
+    }
+
+    let title_page = txn.get_or_insert_array(TITLE_PAGE);
+    clear(txn, &title_page);
+    for field in &screenplay.title_page.0 {
+        title_page.push_back(
+            txn,
+            MapPrelim::from([
+                (KEY, Any::from(field.key.as_str())),
+                (VALUE, Any::from(field.value.as_str())),
+            ]),
+        );
+    }
+}
+
+/// Inserts `element` as a block at `index`.
+pub fn insert_block(
+    txn: &mut TransactionMut,
+    blocks: &ArrayRef,
+    index: u32,
+    element: &Element,
+) -> MapRef {
+    let block = blocks.insert(txn, index, MapPrelim::from([(KIND, element.kind.as_str())]));
+    fill_block(txn, &block, element);
+    block
+}
+
+/// Appends `element` as a block.
+pub fn push_block(txn: &mut TransactionMut, blocks: &ArrayRef, element: &Element) -> MapRef {
+    let block = blocks.push_back(txn, MapPrelim::from([(KIND, element.kind.as_str())]));
+    fill_block(txn, &block, element);
+    block
+}
+
+/// Fills in a new block. A scene heading without a scene id is given one
+/// made from the block's own yrs id, which no other block shares.
+fn fill_block(txn: &mut TransactionMut, block: &MapRef, element: &Element) {
+    block.insert(txn, TEXT, TextPrelim::new(element.text.as_str()));
+
+    let attrs = &element.attrs;
+    let mut prelim = MapPrelim::default();
+    let scene_id = match (&element.kind, &attrs.scene_id) {
+        (_, Some(SceneId(id))) => Some(id.clone()),
+        (ScreenplayElementKind::SceneHeading, None) => Some(new_scene_id(block)),
+        _ => None,
+    };
+    if let Some(id) = scene_id {
+        prelim.insert(SCENE_ID.into(), Any::from(id).into());
+    }
+    if let Some(number) = &attrs.scene_number {
+        prelim.insert(SCENE_NUMBER.into(), Any::from(number.as_str()).into());
+    }
+    if attrs.dual {
+        prelim.insert(DUAL.into(), Any::Bool(true).into());
+    }
+    if attrs.centered {
+        prelim.insert(CENTERED.into(), Any::Bool(true).into());
+    }
+    if let Some(level) = attrs.level {
+        prelim.insert(LEVEL.into(), Any::Number(level.into()).into());
+    }
+    block.insert(txn, ATTRS, prelim);
+}
+
+fn new_scene_id(block: &MapRef) -> String {
+    let branch: &Branch = block.as_ref();
+    match branch.id() {
+        BranchID::Nested(id) => format!("{}-{}", id.client, id.clock),
+        BranchID::Root(name) => name.to_string(),
+    }
+}
+
+fn clear(txn: &mut TransactionMut, array: &ArrayRef) {
+    let len = array.len(txn);
+    if len > 0 {
+        array.remove_range(txn, 0, len);
+    }
+}
+
+/// Moves a plain-text script into [`BLOCKS`], one `general` block per
+/// non-blank line, and empties the old text. The script is read from the
+/// root text named `doc_id`, where the first rooms kept it, followed by
+/// [`LEGACY_TEXT`]. Only documents with no blocks yet are migrated;
+/// returns whether anything changed.
+pub fn migrate_plain_text(txn: &mut TransactionMut, doc_id: &str) -> bool {
+    if txn.get_array(BLOCKS).is_some_and(|b| b.len(txn) > 0) {
+        return false;
+    }
+
+    let by_id = Some(doc_id).filter(|id| ![BLOCKS, TITLE_PAGE, LEGACY_TEXT].contains(id));
+    let legacy: Vec<_> = by_id
+        .into_iter()
+        .chain([LEGACY_TEXT])
+        .filter_map(|root| txn.get_text(root))
+        .collect();
+    let text: Vec<_> = legacy.iter().map(|root| root.get_string(txn)).collect();
+    if text.iter().all(String::is_empty) {
+        return false;
+    }
+
+    let blocks = txn.get_or_insert_array(BLOCKS);
+    for line in text
+        .iter()
+        .flat_map(|text| text.lines())
+        .map(str::trim_end)
+        .filter(|l| !l.is_empty())
+    {
+        push_block(
+            txn,
+            &blocks,
+            &Element::new(ScreenplayElementKind::General, line),
+        );
+    }
+    for root in legacy {
+        let len = root.len(txn);
+        root.remove_range(txn, 0, len);
+    }
+
+    true
+}
+
+/// Reads the screenplay held by a document. Documents without the root
+/// arrays read as empty; anything in them that does not follow the
+/// layout is an error.
+pub fn read<T: ReadTxn>(txn: &T) -> Result<Screenplay, LayoutError> {
+    let elements = match txn.get_array(BLOCKS) {
+        Some(blocks) => blocks
+            .iter(txn)
+            .enumerate()
+            .map(|(index, value)| match value {
+                Out::YMap(block) => read_block(txn, &block).map_err(|e| e.at(index)),
+                _ => Err(LayoutError::NotABlock(index)),
+            })
+            .collect::<Result<_, _>>()?,
+        None => Vec::new(),
+    };
+
+    let title_page = match txn.get_array(TITLE_PAGE) {
+        Some(fields) => fields
+            .iter(txn)
+            .enumerate()
+            .map(|(index, value)| {
+                read_title_field(txn, value).ok_or(LayoutError::TitleField(index))
+            })
+            .collect::<Result<_, _>>()?,
+        None => Vec::new(),
+    };
+
+    Ok(Screenplay {
+        title_page: TitlePage(title_page),
+        elements,
+    })
+}
+
+/// Reads one block of [`BLOCKS`].
+pub fn read_block<T: ReadTxn>(txn: &T, block: &MapRef) -> Result<Element, BlockError> {
+    let mut kind = None;
+    let mut text = None;
+    let mut attrs = Attributes::default();
+
+    for (key, value) in block.iter(txn) {
+        match (key, value) {
+            (KIND, Out::Any(Any::String(s))) => {
+                kind = Some(
+                    s.parse::<ScreenplayElementKind>()
+                        .map_err(|_| BlockError::UnknownKind(s.to_string()))?,
+                );
+            }
+            (TEXT, Out::YText(t)) => text = Some(read_text(txn, &t)?),
+            (ATTRS, Out::YMap(map)) => attrs = read_attrs(txn, &map)?,
+            (KIND | TEXT | ATTRS, _) => return Err(BlockError::WrongType(key.to_owned())),
+            _ => return Err(BlockError::UnknownField(key.to_owned())),
+        }
+    }
+
+    Ok(Element {
+        kind: kind.ok_or(BlockError::Missing(KIND))?,
+        text: text.ok_or(BlockError::Missing(TEXT))?,
+        attrs,
+    })
+}
+
+/// Reads the text of a block, which may only hold characters. Anything
+/// embedded in it would be invisible to `get_string`.
+fn read_text<T: ReadTxn>(txn: &T, text: &TextRef) -> Result<String, BlockError> {
+    let embedded = text
+        .diff(txn, YChange::identity)
+        .into_iter()
+        .any(|chunk| !matches!(chunk.insert, Out::Any(Any::String(_))));
+    if embedded {
+        return Err(BlockError::EmbedInText);
+    }
+
+    Ok(text.get_string(txn))
+}
+
+fn read_attrs<T: ReadTxn>(txn: &T, map: &MapRef) -> Result<Attributes, BlockError> {
+    let mut attrs = Attributes::default();
+
+    for (key, value) in map.iter(txn) {
+        match (key, value) {
+            (SCENE_ID, Out::Any(Any::String(s))) => attrs.scene_id = Some(SceneId(s.to_string())),
+            (SCENE_NUMBER, Out::Any(Any::String(s))) => attrs.scene_number = Some(s.to_string()),
+            (DUAL, Out::Any(Any::Bool(b))) => attrs.dual = b,
+            (CENTERED, Out::Any(Any::Bool(b))) => attrs.centered = b,
+            (LEVEL, Out::Any(Any::Number(n))) if n.fract() == 0.0 && (1.0..=255.0).contains(&n) => {
+                attrs.level = Some(n as u8);
+            }
+            (LEVEL, Out::Any(Any::BigInt(n))) if (1..=255).contains(&n) => {
+                attrs.level = Some(n as u8)
+            }
+            (SCENE_ID | SCENE_NUMBER | DUAL | CENTERED | LEVEL, _) => {
+                return Err(BlockError::WrongType(format!("{ATTRS}.{key}")));
+            }
+            _ => return Err(BlockError::UnknownAttribute(key.to_owned())),
+        }
+    }
+
+    Ok(attrs)
+}
+
+fn read_title_field<T: ReadTxn>(txn: &T, value: Out) -> Option<TitleField> {
+    let Out::YMap(map) = value else {
+        return None;
+    };
+    if map.len(txn) != 2 {
+        return None;
+    }
+
+    let string = |key| match map.get(txn, key) {
+        Some(Out::Any(Any::String(s))) => Some(s.to_string()),
+        _ => None,
+    };
+
+    Some(TitleField {
+        key: string(KEY)?,
+        value: string(VALUE)?,
+    })
+}
+
+/// A block that does not follow the layout.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlockError {
+    /// A required field is absent.
+    Missing(&'static str),
+    /// `kind` names no [`ScreenplayElementKind`].
+    UnknownKind(String),
+    /// The block has a field the layout does not define.
+    UnknownField(String),
+    /// `attrs` has a key [`Attributes`] does not define.
+    UnknownAttribute(String),
+    /// A field or attribute holds a value of the wrong type, such as a
+    /// nested block where a string belongs.
+    WrongType(String),
+    /// `text` holds an embedded value or shared type between its
+    /// characters.
+    EmbedInText,
+}
+
+impl BlockError {
+    fn at(self, index: usize) -> LayoutError {
+        LayoutError::Block { index, error: self }
+    }
+}
+
+impl core::fmt::Display for BlockError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            BlockError::Missing(field) => write!(f, "missing field `{field}`"),
+            BlockError::UnknownKind(kind) => write!(f, "unknown element kind `{kind}`"),
+            BlockError::UnknownField(field) => write!(f, "unknown field `{field}`"),
+            BlockError::UnknownAttribute(key) => write!(f, "unknown attribute `{key}`"),
+            BlockError::WrongType(field) => write!(f, "`{field}` has the wrong type"),
+            BlockError::EmbedInText => write!(f, "`{TEXT}` holds an embedded value"),
+        }
+    }
+}
+
+/// A document that does not follow the layout.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LayoutError {
+    /// An entry of [`BLOCKS`] is not a map.
+    NotABlock(usize),
+    /// An entry of [`BLOCKS`] is a malformed block.
+    Block { index: usize, error: BlockError },
+    /// An entry of [`TITLE_PAGE`] is not a `{ key, value }` map of strings.
+    TitleField(usize),
+}
+
+impl core::fmt::Display for LayoutError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            LayoutError::NotABlock(index) => write!(f, "block {index} is not a map"),
+            LayoutError::Block { index, error } => write!(f, "block {index}: {error}"),
+            LayoutError::TitleField(index) => write!(f, "title page entry {index} is malformed"),
+        }
+    }
+}
+
+impl std::error::Error for LayoutError {}
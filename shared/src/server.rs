@@ -1,6 +1,20 @@
+use crate::api::Role;
+
+/// ServerRequest represents a message sent from a client to the
+/// server. Requests arrive as JSON-encoded WebSocket frames and are
+/// routed to the room the client is connected to.
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 #[serde(tag = "type", content = "data")]
-pub enum ServerRequest {}
+pub enum ServerRequest {
+    Update(Vec<u8>),    // yrs v1 document update payload
+    UpdateV2(Vec<u8>),  // yrs v2 document update payload
+    SyncStep1(Vec<u8>), // client state vector, answered with SyncStep2
+    SyncStep2(Vec<u8>), // update the server is missing, per its SyncStep1
+    Awareness(Vec<u8>), // yrs v1 awareness update payload
+    Snapshot,           // request the full document state as an update
+    Ping,
+    Leave,
+}
 
 /// ServerReply represents the output of the server processing
 /// a client message. The reply is converted into a WebSocket Message
@@ -9,8 +23,97 @@ pub enum ServerRequest {}
 #[serde(tag = "type", content = "data")]
 pub enum ServerReply {
     Update(Vec<u8>), // TAG_UPDATE + payload
-    Join { id: u64, peers: Vec<u64> },
+    Join {
+        id: u64,
+        /// Token to pass as `?session=` when reconnecting, to come back
+        /// as the same peer.
+        session: String,
+        peers: Vec<PeerInfo>,
+        /// What the peer may do in the room.
+        role: Role,
+    },
+    PeerJoined(PeerInfo),
+    PeerLeft {
+        id: u64,
+    },
     Awareness(Vec<u8>), // TAG_AWARENESS + payload
     Snapshot(Vec<u8>),  // TAG_SNAPSHOT + payload
+    SyncStep1(Vec<u8>), // server state vector, sent on join
+    SyncStep2(Vec<u8>), // update the client is missing, per its SyncStep1
     PingPong,
+    /// The document's ACL changed and the peer now has `role`.
+    RoleChanged(Role),
+    /// The room is closing the connection, as `reason` says: the server
+    /// is restarting, in which case the client should reconnect after a
+    /// moment, or the screenplay was deleted.
+    Closing {
+        reason: String,
+    },
+    Error(ServerError),
+}
+
+/// PeerInfo describes a collaborator connected to a room.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct PeerInfo {
+    pub id: u64,
+    /// The authenticated user behind the connection, shared by all of
+    /// their tabs. `None` for anonymous peers.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    /// Display name shown in the collaborator list.
+    pub name: String,
+    /// CSS colour used for the peer's cursor and avatar.
+    pub color: String,
+}
+
+/// ServerError describes why the server refused to process
+/// a client message. It is sent only to the client that caused it.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "kind", content = "detail")]
+pub enum ServerError {
+    /// The frame could not be decoded as a `ServerRequest`.
+    MalformedRequest(String),
+    /// The update payload could not be decoded or applied to the document.
+    InvalidUpdate(String),
+    /// The update broke the screenplay layout and was undone.
+    SchemaViolation(String),
+    /// The state vector sent with SyncStep1 could not be decoded.
+    InvalidStateVector(String),
+    /// The awareness payload could not be decoded or applied.
+    InvalidAwareness(String),
+    /// The peer's role does not allow changing the document.
+    ReadOnly(Role),
+    /// The update was larger than the server's limit, in bytes.
+    TooLarge(usize),
+    /// The client sent awareness updates faster than the server allows.
+    /// The update was dropped; the next one replaces it. Document updates
+    /// are delayed instead of refused.
+    RateLimited,
+    /// Applying the update would grow the document past the server's
+    /// limit, in bytes.
+    DocumentTooLarge(usize),
+    /// The client had too many messages refused and is being
+    /// disconnected.
+    TooManyViolations,
+}
+
+impl core::fmt::Display for ServerError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            ServerError::MalformedRequest(e) => write!(f, "malformed request: {e}"),
+            ServerError::InvalidUpdate(e) => write!(f, "invalid update: {e}"),
+            ServerError::SchemaViolation(e) => {
+                write!(f, "update breaks the screenplay layout and was undone: {e}")
+            }
+            ServerError::InvalidStateVector(e) => write!(f, "invalid state vector: {e}"),
+            ServerError::InvalidAwareness(e) => write!(f, "invalid awareness update: {e}"),
+            ServerError::ReadOnly(role) => write!(f, "a {role} cannot edit this document"),
+            ServerError::TooLarge(limit) => write!(f, "update exceeds the {limit} byte limit"),
+            ServerError::RateLimited => write!(f, "too many updates, slow down"),
+            ServerError::DocumentTooLarge(limit) => {
+                write!(f, "document would exceed the {limit} byte limit")
+            }
+            ServerError::TooManyViolations => write!(f, "too many refused messages"),
+        }
+    }
 }
@@ -0,0 +1,387 @@
+use shared::screenplay::{
+    Attributes, Element, Screenplay, ScreenplayElementKind as Kind, TitleField, TitlePage,
+    fountain, ydoc,
+};
+use yrs::{Doc, Transact};
+
+const SCRIPT: &str = r#"Title:
+    _**BRICK & STEEL**_
+    _**FULL RETIRED**_
+Credit: Written by
+Author: Stu Maschwitz
+Source: Story by KTM
+Draft date: 1/20/2012
+Contact:
+    Next Level Productions
+    1588 Mission Dr.
+    Solvang, CA 93463
+
+# ACT I
+
+= Steel and Brick meet again.
+
+EXT. BRICK'S PATIO - DAY #1#
+
+A gorgeous day.  The sun is shining.  But BRICK BRADDOCK, retired police detective, is sitting quietly, contemplating -- something.
+
+The SCREEN DOOR slides open and DICK STEEL, his former partner and fellow retiree, emerges with two cold beers.
+
+STEEL
+Beer's ready!
+
+BRICK
+Are they cold?
+
+STEEL
+Does a bear crap in the woods?
+
+Steel sits.  They laugh at the dumb joke.
+
+STEEL
+(beer raised)
+To retirement.
+
+BRICK
+To retirement.
+
+They drink /* long and well */ from the beers.
+
+.FLASHBACK
+
+[[Check the timeline of this flashback.]]
+
+BRICK ^
+Thirty years.
+
+STEEL ^
+Thirty years.
+
+@McCLANE
+Yippee ki-yay! I mean...
+
+...yay.
+
+~Willy Wonka! Willy Wonka! The amazing chocolatier!
+~Willy Wonka! Willy Wonka! Everybody give a cheer!
+
+!INT. NOT A HEADING
+
+CUT TO:
+
+INT. GARAGE - NIGHT
+
+    Tab-indented action keeps its indentation.
+
+> THE END <
+
+>SMASH CUT TO BLACK.
+
+===
+
+## Sequence B
+"#;
+
+/// Exporting `screenplay` and importing the result gives it back.
+fn assert_round_trip(screenplay: &Screenplay) {
+    let script = fountain::write(screenplay);
+    assert_eq!(fountain::parse(&script), *screenplay, "script:\n{script}");
+}
+
+fn action(text: &str) -> Element {
+    Element::new(Kind::Action, text)
+}
+
+fn elements(elements: Vec<Element>) -> Screenplay {
+    Screenplay {
+        title_page: TitlePage::default(),
+        elements,
+    }
+}
+
+#[test]
+fn import_export_import_is_stable() {
+    let imported = fountain::parse(SCRIPT);
+    let exported = fountain::write(&imported);
+
+    assert_eq!(fountain::parse(&exported), imported, "script:\n{exported}");
+    assert_eq!(fountain::write(&fountain::parse(&exported)), exported);
+}
+
+#[test]
+fn imports_every_element() {
+    let imported = fountain::parse(SCRIPT);
+    let kinds: Vec<_> = imported.elements.iter().map(|e| e.kind.clone()).collect();
+
+    for kind in [
+        Kind::SceneHeading,
+        Kind::Action,
+        Kind::Character,
+        Kind::Parenthetical,
+        Kind::Dialogue,
+        Kind::Transition,
+        Kind::Lyrics,
+        Kind::Section,
+        Kind::Synopsis,
+        Kind::Note,
+        Kind::PageBreak,
+    ] {
+        assert!(kinds.contains(&kind), "no {kind} in {kinds:?}");
+    }
+    assert_eq!(imported.title_page.get("author"), Some("Stu Maschwitz"));
+    assert_eq!(
+        imported.title_page.get("contact"),
+        Some("Next Level Productions\n1588 Mission Dr.\nSolvang, CA 93463")
+    );
+    assert!(
+        imported
+            .elements
+            .iter()
+            .all(|e| !e.text.contains("long and well")),
+        "boneyard was imported"
+    );
+}
+
+#[test]
+fn round_trips_title_page() {
+    assert_round_trip(&Screenplay {
+        title_page: TitlePage(vec![
+            TitleField {
+                key: "Title".into(),
+                value: "*THE SCRIPT*".into(),
+            },
+            TitleField {
+                key: "Notes".into(),
+                value: "First line\nSecond line".into(),
+            },
+            TitleField {
+                key: "Draft date".into(),
+                value: String::new(),
+            },
+        ]),
+        elements: vec![action("It begins.")],
+    });
+}
+
+#[test]
+fn forces_action_that_reads_as_another_element() {
+    for text in [
+        "INT. HOUSE - DAY",
+        "CUT TO:",
+        "# Not a section",
+        "= Not a synopsis",
+        "~Not lyrics",
+        "[[Not a note]]",
+        "===",
+        ".NOT A HEADING",
+        "> Not a transition",
+        "> Not centered <",
+        "@Not a cue",
+        "!Bang",
+        "BOB\nHello there.",
+    ] {
+        let screenplay = elements(vec![action("Before."), action(text)]);
+        assert_round_trip(&screenplay);
+    }
+
+    let script = fountain::write(&elements(vec![action("BOB\nHello there.")]));
+    assert!(
+        script.starts_with("!BOB"),
+        "cue-like action not forced: {script}"
+    );
+}
+
+#[test]
+fn forces_action_that_reads_as_a_title_page() {
+    assert_round_trip(&elements(vec![action("Note: this is action.")]));
+}
+
+#[test]
+fn forces_elements_that_would_read_as_action() {
+    assert_round_trip(&elements(vec![
+        Element::new(Kind::SceneHeading, "FLASHBACK"),
+        Element::new(Kind::Character, "McCLANE"),
+        Element::new(Kind::Dialogue, "Hello."),
+        Element::new(Kind::Transition, "FADE OUT."),
+        Element::new(Kind::Transition, "Cut to:"),
+        Element::new(Kind::Character, "#HASHTAG"),
+        Element::new(Kind::Dialogue, "Trending."),
+    ]));
+}
+
+#[test]
+fn round_trips_attributes() {
+    assert_round_trip(&elements(vec![
+        Element::new(Kind::Section, "Act Two").with_attrs(Attributes {
+            level: Some(1),
+            ..Attributes::default()
+        }),
+        Element::new(Kind::Section, "The Chase").with_attrs(Attributes {
+            level: Some(3),
+            ..Attributes::default()
+        }),
+        Element::new(Kind::SceneHeading, "EXT. ROOFTOP - NIGHT").with_attrs(Attributes {
+            scene_number: Some("12A".into()),
+            ..Attributes::default()
+        }),
+        Element::new(Kind::Character, "ALICE"),
+        Element::new(Kind::Dialogue, "Now!"),
+        Element::new(Kind::Character, "BOB").with_attrs(Attributes {
+            dual: true,
+            ..Attributes::default()
+        }),
+        Element::new(Kind::Dialogue, "Now!"),
+        action("THE END").with_attrs(Attributes {
+            centered: true,
+            ..Attributes::default()
+        }),
+    ]));
+}
+
+#[test]
+fn keeps_notes_and_drops_the_boneyard() {
+    let screenplay = elements(vec![
+        action("He waits."),
+        Element::new(Kind::Note, "Is this too slow?\nMaybe cut."),
+        action("He leaves. [[inline note]]"),
+    ]);
+    let script = fountain::write(&screenplay);
+
+    assert!(script.contains("[[Is this too slow?\nMaybe cut.]]"));
+    assert_round_trip(&screenplay);
+    assert_eq!(
+        fountain::parse(&format!("/* Draft notes */\n{script}")),
+        screenplay
+    );
+}
+
+#[test]
+fn keeps_empty_lines_in_dialogue() {
+    assert_round_trip(&elements(vec![
+        Element::new(Kind::Character, "NARRATOR"),
+        Element::new(Kind::Parenthetical, "(softly)"),
+        Element::new(Kind::Dialogue, "Once upon a time.\n\nThe end."),
+        Element::new(Kind::Lyrics, "La la la\nLa la"),
+    ]));
+}
+
+#[test]
+fn exports_a_room_document() {
+    let imported = fountain::parse(SCRIPT);
+    let doc = Doc::new();
+    ydoc::write(&mut doc.transact_mut(), &imported);
+
+    let read = ydoc::read(&doc.transact()).unwrap();
+    assert_eq!(fountain::parse(&fountain::write(&read)), imported);
+}
+
+#[test]
+fn escapes_text_that_reads_as_the_boneyard() {
+    let screenplay = elements(vec![
+        action("price is 10 /* approx */ dollars"),
+        action("a path like src\\/*"),
+        Element::new(Kind::Character, "ALICE"),
+        Element::new(Kind::Dialogue, "Open /* and never close"),
+    ]);
+
+    assert_round_trip(&screenplay);
+}
+
+#[test]
+fn escapes_dialogue_that_reads_as_a_parenthetical() {
+    assert_round_trip(&elements(vec![
+        Element::new(Kind::Character, "ALICE"),
+        Element::new(Kind::Dialogue, "(quietly)"),
+        Element::new(Kind::Character, "BOB"),
+        Element::new(Kind::Parenthetical, "(aside)"),
+        Element::new(Kind::Dialogue, "Well...\n(beat)\n\\(escaped)"),
+    ]));
+}
+
+#[test]
+fn escapes_the_end_of_a_note_inside_it() {
+    assert_round_trip(&elements(vec![
+        Element::new(Kind::Note, "Keep [[this]] as is."),
+        Element::new(Kind::Note, "A literal \\]] too."),
+        Element::new(Kind::Note, "Ends with a backslash\\"),
+        action("He leaves."),
+    ]));
+}
+
+#[test]
+fn keeps_multi_line_synopses_together() {
+    assert_round_trip(&elements(vec![
+        Element::new(Kind::Synopsis, "They meet.\nThey argue."),
+        action("He leaves."),
+        Element::new(Kind::Synopsis, "One line."),
+    ]));
+}
+
+#[test]
+fn forces_a_first_element_that_reads_as_a_title_page() {
+    for element in [
+        Element::new(Kind::Transition, "CUT TO:"),
+        Element::new(Kind::Transition, "FADE IN:"),
+        Element::new(Kind::SceneHeading, "INT HOUSE: DAY"),
+    ] {
+        assert_round_trip(&elements(vec![element, action("He waits.")]));
+    }
+    assert_round_trip(&elements(vec![
+        Element::new(Kind::Character, "CUE:"),
+        Element::new(Kind::Dialogue, "Hello."),
+    ]));
+
+    let script = fountain::write(&elements(vec![Element::new(Kind::Transition, "CUT TO:")]));
+    assert!(
+        script.starts_with(">CUT TO:"),
+        "transition not forced: {script}"
+    );
+    let script = fountain::write(&elements(vec![
+        Element::new(Kind::Character, "CUE:"),
+        Element::new(Kind::Dialogue, "Hello."),
+    ]));
+    assert!(script.starts_with("@CUE:"), "cue not forced: {script}");
+}
+
+#[test]
+fn keeps_empty_lines_in_title_values_and_action() {
+    assert_round_trip(&Screenplay {
+        title_page: TitlePage(vec![TitleField {
+            key: "Notes".into(),
+            value: "a\n\nb".into(),
+        }]),
+        elements: vec![action("one\n\ntwo")],
+    });
+    assert_round_trip(&elements(vec![
+        action("one\n\ntwo"),
+        Element::new(Kind::Note, "one\n\ntwo"),
+    ]));
+}
+
+#[test]
+fn escapes_marks_that_change_an_element() {
+    assert_round_trip(&elements(vec![
+        Element::new(Kind::Section, "#hash").with_attrs(Attributes {
+            level: Some(2),
+            ..Attributes::default()
+        }),
+        Element::new(Kind::Transition, "FADE <"),
+        Element::new(Kind::SceneHeading, "INT. HOUSE #1#"),
+        Element::new(Kind::SceneHeading, "INT. HOUSE #1#").with_attrs(Attributes {
+            scene_number: Some("2".into()),
+            ..Attributes::default()
+        }),
+        Element::new(Kind::SceneHeading, ".HEADING"),
+        Element::new(Kind::Transition, "EXT CUT TO:"),
+        Element::new(Kind::Character, "BOB^"),
+        Element::new(Kind::Dialogue, "Hi."),
+    ]));
+}
+
+#[test]
+fn keeps_empty_dialogue_under_its_cue() {
+    assert_round_trip(&elements(vec![
+        Element::new(Kind::Character, "BOB"),
+        Element::new(Kind::Dialogue, ""),
+        action("He says nothing."),
+    ]));
+}
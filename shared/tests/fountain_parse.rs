@@ -0,0 +1,228 @@
+use shared::screenplay::{
+    Attributes, Element, ScreenplayElementKind as Kind, TitleField, TitlePage, fountain,
+};
+
+fn parse(script: &str) -> Vec<Element> {
+    fountain::parse(script).elements
+}
+
+fn element(kind: Kind, text: &str) -> Element {
+    Element::new(kind, text)
+}
+
+#[test]
+fn reads_unforced_elements() {
+    let script = "\
+EXT. BRICK'S PATIO - DAY #1#
+
+A gorgeous day.
+The sun is shining.
+
+STEEL (V.O.)
+(beer raised)
+To retirement.
+
+CUT TO:
+";
+
+    assert_eq!(
+        parse(script),
+        vec![
+            element(Kind::SceneHeading, "EXT. BRICK'S PATIO - DAY").with_attrs(Attributes {
+                scene_number: Some("1".into()),
+                ..Attributes::default()
+            }),
+            element(Kind::Action, "A gorgeous day.\nThe sun is shining."),
+            element(Kind::Character, "STEEL (V.O.)"),
+            element(Kind::Parenthetical, "(beer raised)"),
+            element(Kind::Dialogue, "To retirement."),
+            element(Kind::Transition, "CUT TO:"),
+        ]
+    );
+}
+
+#[test]
+fn reads_forced_elements() {
+    let script = "\
+.FLASHBACK
+
+!INT. NOT A HEADING
+
+@McCLANE
+Yippee ki-yay!
+
+>SMASH CUT TO BLACK.
+
+> THE END <
+
+~Willy Wonka! Willy Wonka!
+~The amazing chocolatier!
+
+= Steel and Brick meet again.
+
+# ACT I
+";
+
+    assert_eq!(
+        parse(script),
+        vec![
+            element(Kind::SceneHeading, "FLASHBACK"),
+            element(Kind::Action, "INT. NOT A HEADING"),
+            element(Kind::Character, "McCLANE"),
+            element(Kind::Dialogue, "Yippee ki-yay!"),
+            element(Kind::Transition, "SMASH CUT TO BLACK."),
+            element(Kind::Action, "THE END").with_attrs(Attributes {
+                centered: true,
+                ..Attributes::default()
+            }),
+            element(
+                Kind::Lyrics,
+                "Willy Wonka! Willy Wonka!\nThe amazing chocolatier!"
+            ),
+            element(Kind::Synopsis, "Steel and Brick meet again."),
+            element(Kind::Section, "ACT I").with_attrs(Attributes {
+                level: Some(1),
+                ..Attributes::default()
+            }),
+        ]
+    );
+}
+
+#[test]
+fn reads_dual_dialogue() {
+    let script = "\
+BRICK
+Thirty years.
+
+STEEL ^
+Thirty years.
+";
+
+    assert_eq!(
+        parse(script),
+        vec![
+            element(Kind::Character, "BRICK"),
+            element(Kind::Dialogue, "Thirty years."),
+            element(Kind::Character, "STEEL").with_attrs(Attributes {
+                dual: true,
+                ..Attributes::default()
+            }),
+            element(Kind::Dialogue, "Thirty years."),
+        ]
+    );
+}
+
+#[test]
+fn reads_notes() {
+    let script = "\
+[[Check the timeline.]]
+
+[[A note
+over two lines.]]
+
+He leaves. [[inline note]]
+";
+
+    assert_eq!(
+        parse(script),
+        vec![
+            element(Kind::Note, "Check the timeline."),
+            element(Kind::Note, "A note\nover two lines."),
+            element(Kind::Action, "He leaves. [[inline note]]"),
+        ]
+    );
+}
+
+#[test]
+fn drops_the_boneyard() {
+    let script = "\
+They drink /* long and well */ from the beers.
+
+/* A scene
+that was cut. */
+
+He leaves.
+
+/* Never closed
+";
+
+    assert_eq!(
+        parse(script),
+        vec![
+            element(Kind::Action, "They drink  from the beers."),
+            element(Kind::Action, "He leaves."),
+        ]
+    );
+}
+
+#[test]
+fn reads_section_levels_and_page_breaks() {
+    let level = |text: &str, level: u8| {
+        element(Kind::Section, text).with_attrs(Attributes {
+            level: Some(level),
+            ..Attributes::default()
+        })
+    };
+
+    assert_eq!(
+        parse("# Act\n\n## Sequence\n\n### Scene\n\n===\n"),
+        vec![
+            level("Act", 1),
+            level("Sequence", 2),
+            level("Scene", 3),
+            element(Kind::PageBreak, ""),
+        ]
+    );
+}
+
+#[test]
+fn reads_the_title_page() {
+    let script = "\
+Title:
+    _**BRICK & STEEL**_
+    _**FULL RETIRED**_
+Credit: Written by
+Author: Stu Maschwitz
+Draft date:
+
+INT. GARAGE - NIGHT
+";
+    let screenplay = fountain::parse(script);
+
+    assert_eq!(
+        screenplay.title_page,
+        TitlePage(vec![
+            TitleField {
+                key: "Title".into(),
+                value: "_**BRICK & STEEL**_\n_**FULL RETIRED**_".into(),
+            },
+            TitleField {
+                key: "Credit".into(),
+                value: "Written by".into(),
+            },
+            TitleField {
+                key: "Author".into(),
+                value: "Stu Maschwitz".into(),
+            },
+            TitleField {
+                key: "Draft date".into(),
+                value: String::new(),
+            },
+        ])
+    );
+    assert_eq!(
+        screenplay.elements,
+        vec![element(Kind::SceneHeading, "INT. GARAGE - NIGHT")]
+    );
+}
+
+#[test]
+fn reads_a_script_without_a_title_page() {
+    let screenplay = fountain::parse("\u{feff}He waits.\r\nThen leaves.\r\n");
+
+    assert!(screenplay.title_page.is_empty());
+    assert_eq!(
+        screenplay.elements,
+        vec![element(Kind::Action, "He waits.\nThen leaves.")]
+    );
+}
@@ -0,0 +1,112 @@
+use std::collections::HashSet;
+
+use shared::screenplay::{
+    Attributes, Element, SceneId, Screenplay, ScreenplayElementKind as Kind, fountain, ydoc,
+};
+use yrs::{Array, Doc, GetString, Map, Text, Transact, WriteTxn};
+
+#[test]
+fn kind_names_parse_back() {
+    for kind in Kind::ALL {
+        assert_eq!(kind.as_str().parse::<Kind>(), Ok(kind.clone()));
+        assert_eq!(
+            Kind::from_display_name(&kind.to_string()),
+            Some(kind.clone())
+        );
+    }
+    assert!("SceneHeading".parse::<Kind>().is_err());
+    assert!("scene heading".parse::<Kind>().is_err());
+    assert_eq!(Kind::from_display_name("scene_heading"), None);
+}
+
+#[test]
+fn rejects_a_display_name_as_a_stored_kind() {
+    let doc = Doc::new();
+    ydoc::write(
+        &mut doc.transact_mut(),
+        &Screenplay {
+            elements: vec![Element::new(Kind::Action, "He waits.")],
+            ..Screenplay::default()
+        },
+    );
+
+    let mut txn = doc.transact_mut();
+    let blocks = txn.get_or_insert_array(ydoc::BLOCKS);
+    let Some(yrs::Out::YMap(block)) = blocks.get(&txn, 0) else {
+        panic!("no block");
+    };
+    block.insert(&mut txn, "kind", "Action");
+    assert!(ydoc::read_block(&txn, &block).is_err());
+}
+
+#[test]
+fn assigns_scene_ids_to_new_headings() {
+    let screenplay = fountain::parse(
+        "INT. HOUSE - DAY\n\nShe waits.\n\nEXT. STREET - NIGHT\n\nHe runs.\n\nINT. CAR - NIGHT\n",
+    );
+    let doc = Doc::new();
+    ydoc::write(&mut doc.transact_mut(), &screenplay);
+
+    let read = ydoc::read(&doc.transact()).unwrap();
+    let ids: Vec<_> = read.scenes().map(|scene| scene.id().cloned()).collect();
+    assert_eq!(ids.len(), 3);
+    assert!(ids.iter().all(Option::is_some), "{ids:?}");
+    assert_eq!(ids.iter().collect::<HashSet<_>>().len(), 3, "{ids:?}");
+
+    // Other elements get none.
+    assert!(
+        read.elements
+            .iter()
+            .filter(|e| e.kind != Kind::SceneHeading)
+            .all(|e| e.attrs.scene_id.is_none())
+    );
+
+    // Writing a screenplay back keeps the ids it already has.
+    let other = Doc::new();
+    ydoc::write(&mut other.transact_mut(), &read);
+    assert_eq!(ydoc::read(&other.transact()).unwrap(), read);
+}
+
+#[test]
+fn keeps_a_given_scene_id() {
+    let heading = Element::new(Kind::SceneHeading, "INT. HOUSE - DAY").with_attrs(Attributes {
+        scene_id: Some(SceneId("opening".into())),
+        ..Attributes::default()
+    });
+    let screenplay = Screenplay {
+        elements: vec![heading],
+        ..Screenplay::default()
+    };
+    let doc = Doc::new();
+    ydoc::write(&mut doc.transact_mut(), &screenplay);
+
+    assert_eq!(ydoc::read(&doc.transact()).unwrap(), screenplay);
+}
+
+#[test]
+fn migrates_plain_text_roots() {
+    for root in ["lobby", ydoc::LEGACY_TEXT] {
+        let doc = Doc::new();
+        let text = doc.get_or_insert_text(root);
+        text.insert(
+            &mut doc.transact_mut(),
+            0,
+            "INT. HOUSE - DAY\n\nShe waits.\n",
+        );
+
+        assert!(ydoc::migrate_plain_text(&mut doc.transact_mut(), "lobby"));
+        let read = ydoc::read(&doc.transact()).unwrap();
+        assert_eq!(
+            read.elements,
+            [
+                Element::new(Kind::General, "INT. HOUSE - DAY"),
+                Element::new(Kind::General, "She waits."),
+            ],
+            "root {root}"
+        );
+        assert_eq!(text.get_string(&doc.transact()), "");
+
+        // Already migrated.
+        assert!(!ydoc::migrate_plain_text(&mut doc.transact_mut(), "lobby"));
+    }
+}